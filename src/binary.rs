@@ -0,0 +1,455 @@
+use super::{AccessType, BinaryEncoding, Operation, TraceError};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Result as IoResult, Write};
+
+/// The bytes every binary trace starts with.
+pub(crate) const MAGIC: &[u8; 8] = b"MHTRACE\0";
+/// The version of the layout written after the magic number.
+/// Readers reject any other version, so the layout can change without being misread.
+const VERSION: u16 = 1;
+
+/// The bits of a record's tag holding the access type.
+const ACCESS_TYPE_BITS: u8 = 0b011;
+/// The access type of a record that switches to another address space instead of accessing memory.
+const CONTEXT_SWITCH: u8 = 0b011;
+/// The bit of a record's tag set when a size follows the address.
+/// Records without it are one byte accesses.
+const SIZED_BIT: u8 = 0b100;
+
+// The layout of a binary trace (all numbers are little-endian):
+//
+//   header: the magic number, the version (u16), and the encoding (u8: 0 fixed, 1 delta)
+//   record: a tag (u8: the access type in the low two bits, 0 read, 1 write, 2 fetch,
+//           and SIZED_BIT), then the address, then the size (a LEB128 varint) if the tag is sized
+//   context switch: a tag of 3, then the ID of the address space making the records after it (a varint)
+//
+// With the fixed encoding, the address is a u64. With the delta encoding, it is the
+// zigzag-encoded LEB128 varint of its difference from the previous address (or from 0),
+// which is a byte or two for most accesses.
+
+impl BinaryEncoding {
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::Fixed => 0,
+            Self::Delta => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Fixed),
+            1 => Some(Self::Delta),
+            _ => None,
+        }
+    }
+}
+
+/// Write a LEB128 varint: seven bits at a time, lowest first, with the top bit set on every byte but the last.
+fn write_varint<W>(writer: &mut W, mut value: u64) -> IoResult<()>
+where
+    W: Write,
+{
+    let mut bytes = [0; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes[len] = byte;
+            len += 1;
+            break;
+        }
+        bytes[len] = byte | 0x80;
+        len += 1;
+    }
+    writer.write_all(&bytes[..len])
+}
+
+/// Encodes operations as the records of a binary trace.
+pub(crate) struct BinaryEncoder {
+    encoding: BinaryEncoding,
+    /// The address of the last operation written, which the next delta is taken from.
+    previous: u64,
+    /// The address space of the last operation written.
+    asid: u16,
+}
+
+impl BinaryEncoder {
+    /// Write the header of a trace with the given encoding, and get an encoder for its records.
+    pub(crate) fn new<W>(writer: &mut W, encoding: BinaryEncoding) -> IoResult<Self>
+    where
+        W: Write,
+    {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&[encoding.to_byte()])?;
+        Ok(Self {
+            encoding,
+            previous: 0,
+            asid: 0,
+        })
+    }
+
+    pub(crate) fn write<W>(&mut self, writer: &mut W, operation: &Operation) -> IoResult<()>
+    where
+        W: Write,
+    {
+        if operation.asid != self.asid {
+            writer.write_all(&[CONTEXT_SWITCH])?;
+            write_varint(writer, u64::from(operation.asid))?;
+            self.asid = operation.asid;
+        }
+
+        let mut tag = match operation.access_type {
+            AccessType::Read => 0,
+            AccessType::Write => 1,
+            AccessType::Fetch => 2,
+        };
+        if operation.size != 1 {
+            tag |= SIZED_BIT;
+        }
+        writer.write_all(&[tag])?;
+
+        match self.encoding {
+            BinaryEncoding::Fixed => writer.write_all(&operation.address.to_le_bytes())?,
+            BinaryEncoding::Delta => {
+                let delta = operation.address.wrapping_sub(self.previous) as i64;
+                write_varint(writer, ((delta << 1) ^ (delta >> 63)) as u64)?;
+            }
+        }
+        self.previous = operation.address;
+
+        if operation.size != 1 {
+            write_varint(writer, operation.size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the records of a binary trace.
+pub(crate) struct BinaryDecoder<R> {
+    reader: BufReader<R>,
+    /// The encoding from the header, once it has been read.
+    encoding: Option<BinaryEncoding>,
+    /// The address of the last operation read, which the next delta is added to.
+    previous: u64,
+    /// The number of records read so far.
+    records: usize,
+    /// The address space of the records, from the last context switch.
+    asid: u16,
+}
+
+impl<R> BinaryDecoder<R>
+where
+    R: Read,
+{
+    /// Decode the trace in a reader positioned at its magic number.
+    /// The header is checked when the first record is read.
+    pub(crate) fn new(reader: BufReader<R>) -> Self {
+        Self {
+            reader,
+            encoding: None,
+            previous: 0,
+            records: 0,
+            asid: 0,
+        }
+    }
+
+    /// The number of records read so far.
+    pub(crate) fn records(&self) -> usize {
+        self.records
+    }
+
+    /// An error for the record being read.
+    fn error(&self, message: impl Into<String>) -> TraceError {
+        TraceError::Binary {
+            record: self.records + 1,
+            message: message.into(),
+        }
+    }
+
+    /// Fill the buffer from the reader, treating the end of the trace as an error.
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), TraceError> {
+        self.reader.read_exact(buffer).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => self.error("the trace ends in the middle of a record"),
+            _ => TraceError::Io(e.to_string()),
+        })
+    }
+
+    fn read_varint(&mut self) -> Result<u64, TraceError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0];
+            self.read_exact(&mut byte)?;
+            value |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(self.error("a number is longer than 64 bits"))
+    }
+
+    fn read_header(&mut self) -> Result<BinaryEncoding, TraceError> {
+        let mut header = [0; MAGIC.len() + 3];
+        self.reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => TraceError::Binary {
+                record: 0,
+                message: "the header is cut short".to_owned(),
+            },
+            _ => TraceError::Io(e.to_string()),
+        })?;
+        let bad_header = |message: String| TraceError::Binary { record: 0, message };
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(bad_header("missing the magic number of a binary trace".to_owned()));
+        }
+        let version = u16::from_le_bytes([header[MAGIC.len()], header[MAGIC.len() + 1]]);
+        if version != VERSION {
+            return Err(bad_header(format!(
+                "version {version} isn't supported, only version {VERSION}"
+            )));
+        }
+        let encoding = header[MAGIC.len() + 2];
+        BinaryEncoding::from_byte(encoding)
+            .ok_or_else(|| bad_header(format!("unknown address encoding {encoding}")))
+    }
+
+    /// Read the next operation. Return None at the end of the trace.
+    pub(crate) fn next_operation(&mut self) -> Option<Result<Operation, TraceError>> {
+        let encoding = match self.encoding {
+            Some(encoding) => encoding,
+            None => match self.read_header() {
+                Ok(encoding) => *self.encoding.insert(encoding),
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        loop {
+            // The trace may only end between records.
+            match self.reader.fill_buf() {
+                Ok([]) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(TraceError::Io(e.to_string()))),
+            }
+            match self.read_record(encoding) {
+                Ok(Some(operation)) => return Some(Ok(operation)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    /// Read the next record. Return None if it was a context switch rather than an operation.
+    fn read_record(&mut self, encoding: BinaryEncoding) -> Result<Option<Operation>, TraceError> {
+        let mut tag = [0];
+        self.read_exact(&mut tag)?;
+        let tag = tag[0];
+        if tag & !(ACCESS_TYPE_BITS | SIZED_BIT) != 0 {
+            return Err(self.error(format!("unknown flags in the tag {tag:#04x}")));
+        }
+        let access_type = match tag & ACCESS_TYPE_BITS {
+            0 => AccessType::Read,
+            1 => AccessType::Write,
+            2 => AccessType::Fetch,
+            _ if tag == CONTEXT_SWITCH => {
+                let asid = self.read_varint()?;
+                self.asid = u16::try_from(asid)
+                    .map_err(|_| self.error(format!("address space ID {asid} is larger than 16 bits")))?;
+                self.records += 1;
+                return Ok(None);
+            }
+            _ => return Err(self.error(format!("unknown access type in the tag {tag:#04x}"))),
+        };
+
+        let address = match encoding {
+            BinaryEncoding::Fixed => {
+                let mut bytes = [0; 8];
+                self.read_exact(&mut bytes)?;
+                u64::from_le_bytes(bytes)
+            }
+            BinaryEncoding::Delta => {
+                let zigzag = self.read_varint()?;
+                let delta = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+                self.previous.wrapping_add(delta as u64)
+            }
+        };
+        self.previous = address;
+
+        let size = if tag & SIZED_BIT != 0 {
+            self.read_varint()?
+        } else {
+            1
+        };
+        if size == 0 || address.checked_add(size - 1).is_none() {
+            return Err(self.error(format!("bad size {size} at address {address:x}")));
+        }
+
+        self.records += 1;
+        Ok(Some(Operation {
+            access_type,
+            address,
+            size,
+            asid: self.asid,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Trace, TraceFormat, TraceMode};
+
+    fn encode(operations: &[Operation], encoding: BinaryEncoding) -> Vec<u8> {
+        let trace = Trace {
+            operations: operations.to_vec(),
+            ..Trace::new()
+        };
+        trace.write(vec![], TraceFormat::Binary(encoding)).unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<Operation>, TraceError> {
+        Trace::from_reader(bytes, TraceMode::Strict).map(|trace| trace.operations)
+    }
+
+    /// The zigzag varint of a delta, as the delta encoding writes it.
+    fn delta_bytes(delta: i64) -> Vec<u8> {
+        let mut bytes = vec![];
+        write_varint(&mut bytes, ((delta << 1) ^ (delta >> 63)) as u64).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn both_encodings_round_trip() {
+        let operations = [
+            Operation::read(0x1ffc),
+            Operation::write(0x1ff0).with_size(8),
+            Operation::fetch(0x400).with_size(4).with_asid(2),
+            Operation::read(0x400).with_asid(2),
+            Operation::write(0xdead_beef_0000).with_size(64),
+        ];
+        for encoding in [BinaryEncoding::Fixed, BinaryEncoding::Delta] {
+            let bytes = encode(&operations, encoding);
+            assert!(bytes.starts_with(MAGIC));
+            assert_eq!(decode(&bytes).unwrap(), operations);
+        }
+    }
+
+    #[test]
+    fn extreme_deltas_round_trip() {
+        // The deltas are 0, i64::MAX, i64::MIN, and 1 wrapping around to address 0.
+        let operations = [
+            Operation::read(0),
+            Operation::read(i64::MAX as u64),
+            Operation::read(u64::MAX),
+            Operation::read(0),
+        ];
+        assert_eq!(decode(&encode(&operations, BinaryEncoding::Delta)).unwrap(), operations);
+    }
+
+    #[test]
+    fn zigzag_varints_have_the_expected_lengths() {
+        assert_eq!(delta_bytes(0), [0]);
+        assert_eq!(delta_bytes(-1), [1]);
+        assert_eq!(delta_bytes(1), [2]);
+        assert_eq!(delta_bytes(-64), [0x7f]);
+        assert_eq!(delta_bytes(64), [0x80, 0x01]);
+        assert_eq!(delta_bytes(i64::MAX), [0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert_eq!(delta_bytes(i64::MIN), [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+    }
+
+    #[test]
+    fn the_delta_encoding_is_smaller_with_locality() {
+        let operations: Vec<_> = (0..100).map(|i| Operation::read(0x4000_0000 + 8 * i)).collect();
+        let fixed = encode(&operations, BinaryEncoding::Fixed);
+        let delta = encode(&operations, BinaryEncoding::Delta);
+        assert!(delta.len() * 3 < fixed.len(), "{} vs {}", delta.len(), fixed.len());
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let header = |version: u16, encoding: u8| {
+            let mut bytes = MAGIC.to_vec();
+            bytes.extend(version.to_le_bytes());
+            bytes.push(encoding);
+            bytes
+        };
+        assert!(decode(&header(VERSION, 1)).unwrap().is_empty());
+        assert!(matches!(decode(&header(VERSION + 1, 1)), Err(TraceError::Binary { record: 0, .. })));
+        assert!(matches!(decode(&header(VERSION, 2)), Err(TraceError::Binary { record: 0, .. })));
+        assert!(matches!(decode(&MAGIC[..]), Err(TraceError::Binary { record: 0, .. })));
+    }
+
+    #[test]
+    fn bad_records_are_rejected_by_number() {
+        let mut bytes = encode(&[Operation::read(0x10), Operation::read(0x20).with_size(4)], BinaryEncoding::Delta);
+        let whole = bytes.clone();
+
+        // The trace ends in the middle of the size of the second record.
+        bytes.pop();
+        assert!(matches!(decode(&bytes), Err(TraceError::Binary { record: 2, .. })));
+
+        // A tag with unknown flags.
+        let mut bytes = whole.clone();
+        bytes.push(0x80);
+        assert!(matches!(decode(&bytes), Err(TraceError::Binary { record: 3, .. })));
+
+        // A varint longer than 64 bits.
+        let mut bytes = whole.clone();
+        bytes.push(0);
+        bytes.extend([0x80; 10]);
+        bytes.push(0);
+        assert!(matches!(decode(&bytes), Err(TraceError::Binary { record: 3, .. })));
+
+        // A zero size.
+        let mut bytes = whole;
+        bytes.extend([SIZED_BIT, 0, 0]);
+        assert!(matches!(decode(&bytes), Err(TraceError::Binary { record: 3, .. })));
+    }
+
+    #[test]
+    fn context_switches_are_records() {
+        let operations = [Operation::read(0x10).with_asid(u16::MAX), Operation::read(0x10)];
+        assert_eq!(decode(&encode(&operations, BinaryEncoding::Fixed)).unwrap(), operations);
+
+        // An address space ID wider than 16 bits.
+        let mut bytes = encode(&[], BinaryEncoding::Fixed);
+        bytes.push(CONTEXT_SWITCH);
+        write_varint(&mut bytes, 70000).unwrap();
+        assert!(matches!(decode(&bytes), Err(TraceError::Binary { record: 1, .. })));
+    }
+
+    #[test]
+    fn binary_traces_are_recognized_whatever_the_format() {
+        let operations = [Operation::write(0x20).with_size(2)];
+        let bytes = encode(&operations, BinaryEncoding::Delta);
+        let options = crate::TraceOptions {
+            format: TraceFormat::Lackey,
+            ..crate::TraceOptions::default()
+        };
+        assert_eq!(Trace::from_reader(bytes.as_slice(), options).unwrap().operations, operations);
+    }
+
+    /// A reader that hands out at most a few bytes at a time, like a pipe.
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+            let length = self.1.min(buf.len()).min(self.0.len());
+            buf[..length].copy_from_slice(&self.0[..length]);
+            self.0 = &self.0[length..];
+            Ok(length)
+        }
+    }
+
+    #[test]
+    fn the_magic_number_is_recognized_when_read_a_few_bytes_at_a_time() {
+        let operations = [Operation::read(0x10), Operation::write(0x1234).with_size(4)];
+        let bytes = encode(&operations, BinaryEncoding::Delta);
+        for chunk in 1..=MAGIC.len() {
+            let trace = Trace::from_reader(Trickle(&bytes, chunk), TraceMode::Strict).unwrap();
+            assert_eq!(trace.operations, operations, "{chunk} bytes at a time");
+        }
+        // Text traces (even ones shorter than the magic number) are read in full.
+        for text in ["R:10\nW:1234\n", "R:1\n"] {
+            let trace = Trace::from_reader(Trickle(text.as_bytes(), 3), TraceMode::Strict).unwrap();
+            assert_eq!(trace.operations, text.parse::<Trace>().unwrap().operations);
+        }
+    }
+}
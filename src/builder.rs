@@ -0,0 +1,228 @@
+use super::*;
+
+/// Builds a [`SimulatorConfig`] in code, one setting at a time.
+///
+/// The builder starts from the default settings of every component, with virtual
+/// addresses, the TLB, and a data cache backed by an L2 cache all enabled. Calling [`SimulatorConfigBuilder::build`]
+/// validates the result, so a built configuration is always one the simulator can model.
+#[derive(Clone, Debug)]
+pub struct SimulatorConfigBuilder {
+    config: SimulatorConfig,
+}
+
+impl Default for SimulatorConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatorConfigBuilder {
+    /// Create a builder with the default settings.
+    pub fn new() -> Self {
+        Self {
+            config: SimulatorConfig {
+                virtual_addresses_enabled: true,
+                tlb_enabled: true,
+                virtual_address_bits: ADDRESS_BITS,
+                physical_address_bits: ADDRESS_BITS,
+                tlb: TLBConfig::default(),
+                itlb: None,
+                page_table: PageTableConfig::default(),
+                caches: vec![
+                    DataCacheConfig::default(),
+                    L2CacheConfig::new(16, 4, 16, true),
+                ],
+                icache: None,
+            },
+        }
+    }
+
+    /// Start from an existing configuration instead of the defaults.
+    pub fn from_config(config: SimulatorConfig) -> Self {
+        Self { config }
+    }
+
+    /// Are the addresses in the trace virtual (translated through the page table) or physical?
+    pub fn virtual_addresses(mut self, enabled: bool) -> Self {
+        self.config.virtual_addresses_enabled = enabled;
+        self
+    }
+
+    /// Enable or disable the TLB.
+    pub fn tlb_enabled(mut self, enabled: bool) -> Self {
+        self.config.tlb_enabled = enabled;
+        self
+    }
+
+    /// Set the number of bits in virtual and physical addresses.
+    pub fn address_bits(mut self, virtual_bits: u64, physical_bits: u64) -> Self {
+        self.config.virtual_address_bits = virtual_bits;
+        self.config.physical_address_bits = physical_bits;
+        self
+    }
+
+    /// Enable or disable the L2 cache.
+    pub fn l2_cache_enabled(mut self, enabled: bool) -> Self {
+        self.level_mut(1).enabled = enabled;
+        self
+    }
+
+    /// Get the level at `depth`, adding default levels to the hierarchy until it is that deep.
+    fn level_mut(&mut self, depth: usize) -> &mut CacheConfig {
+        while self.config.caches.len() <= depth {
+            self.config.caches.push(CacheConfig::default());
+        }
+        &mut self.config.caches[depth]
+    }
+
+    /// Replace the whole cache hierarchy, starting with the data cache.
+    pub fn cache_levels(mut self, caches: Vec<CacheConfig>) -> Self {
+        self.config.caches = caches;
+        self
+    }
+
+    /// Replace the configuration of the level at `depth` (0 for the data cache, 1 for the L2, ...).
+    pub fn cache_level(mut self, depth: usize, cache: CacheConfig) -> Self {
+        *self.level_mut(depth) = cache;
+        self
+    }
+
+    /// Add a level below the deepest level of the hierarchy.
+    pub fn add_cache_level(mut self, cache: CacheConfig) -> Self {
+        self.config.caches.push(cache);
+        self
+    }
+
+    /// Replace the whole TLB configuration.
+    pub fn tlb(mut self, tlb: TLBConfig) -> Self {
+        self.config.tlb = tlb;
+        self
+    }
+
+    /// Set the number of sets in the TLB, and the number of entries in each set.
+    pub fn tlb_geometry(mut self, number_of_sets: u64, set_size: u64) -> Self {
+        self.config.tlb.number_of_sets = number_of_sets;
+        self.config.tlb.set_size = set_size;
+        self
+    }
+
+    /// Set the eviction policy of the TLB.
+    pub fn tlb_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.config.tlb.eviction_policy = policy;
+        self
+    }
+
+    /// Add an instruction TLB, or replace its configuration. Without one, instruction fetches
+    /// are translated by the data TLB.
+    pub fn instruction_tlb(mut self, itlb: TLBConfig) -> Self {
+        self.config.itlb = Some(itlb);
+        self
+    }
+
+    /// Replace the whole page table configuration.
+    pub fn page_table(mut self, page_table: PageTableConfig) -> Self {
+        self.config.page_table = page_table;
+        self
+    }
+
+    /// Set the number of virtual and physical pages, and the size of each page in bytes.
+    pub fn page_table_geometry(
+        mut self,
+        number_of_virtual_pages: u64,
+        number_of_physical_pages: u64,
+        page_size: u64,
+    ) -> Self {
+        self.config.page_table =
+            PageTableConfig::new(number_of_virtual_pages, number_of_physical_pages, page_size);
+        self
+    }
+
+    /// Replace the whole data cache configuration.
+    pub fn data_cache(mut self, data_cache: DataCacheConfig) -> Self {
+        *self.level_mut(0) = data_cache;
+        self
+    }
+
+    /// Set the number of sets in the data cache, the number of lines in each set, and the line size in bytes.
+    pub fn data_cache_geometry(mut self, number_of_sets: u64, set_size: u64, line_size: u64) -> Self {
+        let cache = self.level_mut(0);
+        cache.number_of_sets = number_of_sets;
+        cache.set_size = set_size;
+        cache.line_size = line_size;
+        self
+    }
+
+    /// Set the write-hit (write-through or write-back) and write-miss (allocate or not) policies of the data cache.
+    pub fn data_cache_write_policy(mut self, write_through: bool, write_allocate: bool) -> Self {
+        let cache = self.level_mut(0);
+        cache.write_through = write_through;
+        cache.write_allocate = Some(write_allocate);
+        self
+    }
+
+    /// Set the eviction policy of the data cache.
+    pub fn data_cache_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.level_mut(0).eviction_policy = policy;
+        self
+    }
+
+    /// Set the insertion policy of the data cache.
+    pub fn data_cache_insertion_policy(mut self, policy: InsertionPolicy) -> Self {
+        self.level_mut(0).insertion_policy = policy;
+        self
+    }
+
+    /// Add an instruction cache beside the data cache, or replace its configuration.
+    /// Without one, instruction fetches go through the data cache.
+    pub fn instruction_cache(mut self, icache: InstructionCacheConfig) -> Self {
+        self.config.icache = Some(icache);
+        self
+    }
+
+    /// Replace the whole L2 cache configuration.
+    pub fn l2_cache(mut self, l2_cache: L2CacheConfig) -> Self {
+        *self.level_mut(1) = l2_cache;
+        self
+    }
+
+    /// Set the number of sets in the L2 cache, the number of lines in each set, and the line size in bytes.
+    pub fn l2_cache_geometry(mut self, number_of_sets: u64, set_size: u64, line_size: u64) -> Self {
+        let cache = self.level_mut(1);
+        cache.number_of_sets = number_of_sets;
+        cache.set_size = set_size;
+        cache.line_size = line_size;
+        self
+    }
+
+    /// Set the write-hit (write-through or write-back) and write-miss (allocate or not) policies of the L2 cache.
+    pub fn l2_cache_write_policy(mut self, write_through: bool, write_allocate: bool) -> Self {
+        let cache = self.level_mut(1);
+        cache.write_through = write_through;
+        cache.write_allocate = Some(write_allocate);
+        self
+    }
+
+    /// Set the eviction policy of the L2 cache.
+    pub fn l2_cache_eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.level_mut(1).eviction_policy = policy;
+        self
+    }
+
+    /// Set the insertion policy of the L2 cache.
+    pub fn l2_cache_insertion_policy(mut self, policy: InsertionPolicy) -> Self {
+        self.level_mut(1).insertion_policy = policy;
+        self
+    }
+
+    /// Override a field by its key, as with `--set` on the command line (see [`SimulatorConfig::set`]).
+    pub fn set(mut self, key: &str, value: &str) -> Result<Self, ConfigError> {
+        self.config.set(key, value)?;
+        Ok(self)
+    }
+
+    /// Validate and return the configuration.
+    pub fn build(self) -> Result<SimulatorConfig, Vec<ValidationError>> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
@@ -1,20 +1,333 @@
 use super::BlockAddress;
+use core::fmt::{Display, Formatter, Result as FmtResult};
+use core::str::FromStr;
 use log::{trace};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The width of the re-reference prediction values (RRPVs) of the RRIP policies, unless it's given.
+pub const DEFAULT_RRPV_BITS: u8 = 2;
+/// The widest RRPVs the RRIP policies keep.
+pub const MAX_RRPV_BITS: u8 = 8;
+/// The probability that BRRIP inserts a block with a long re-reference interval, unless it's given.
+pub const DEFAULT_BRRIP_PROBABILITY: f64 = 1.0 / 32.0;
+/// The number of leader sets DRRIP and DIP give each of their two policies, unless it's given.
+pub const DEFAULT_LEADER_SETS: u32 = 32;
+/// The width of the policy selection counter of DRRIP and DIP, unless it's given.
+pub const DEFAULT_PSEL_BITS: u32 = 10;
+/// The widest policy selection counter DRRIP and DIP keep.
+pub const MAX_PSEL_BITS: u32 = 32;
+/// The probability that BIP loads a block as the most recently used, unless it's given.
+pub const DEFAULT_BIP_EPSILON: f64 = 1.0 / 32.0;
 
 /// This encodes the eviction policy for a generic cache.
 /// Whenever a block is evicted from a set, this is used to
 /// select the block to evict from the given set.
-#[derive(Copy, Clone, Debug)]
+///
+/// Policies are written as their names, followed by any parameters that differ from the
+/// defaults as `:key=value`, such as `DRRIP:bits=3:leaders=16`. Structured configurations use the same text.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
 pub enum EvictionPolicy {
     /// Evict the least recently used block from the set.
+    #[default]
     LRU,
     /// Evict the least recently block loaded into the set (first-in-first-out).
     FIFO,
     /// Evict a random a block from the set.
     Random,
+    /// Approximate LRU with a binary tree of bits over the ways of the set, each pointing
+    /// away from the half used more recently. The set size must be a power of two.
+    TreePLRU,
+    /// Evict a block whose "recently used" bit is clear (bit-PLRU, or MRU-bit).
+    /// A block's bit is set when it is used, and once every bit is set, the others are cleared.
+    NRU,
+    /// Sweep a hand over the ways of the set, clearing the reference bits it passes,
+    /// and evict the first block whose bit is already clear (second chance).
+    Clock,
+    /// Static re-reference interval prediction. Each block has a re-reference prediction value
+    /// (RRPV) of `rrpv_bits` bits, which is one below the largest when the block is loaded,
+    /// and 0 once it hits. The victim is a block with the largest RRPV, and until there is one,
+    /// every RRPV in the set is incremented. The parameter is `bits`.
+    SRRIP { rrpv_bits: u8 },
+    /// Bimodal RRIP: SRRIP, except that blocks are loaded with the largest RRPV, and only with
+    /// the given probability with one below it, so that scans don't flush the set.
+    /// The parameters are `bits` and `probability`, which may be a fraction such as `1/32`.
+    BRRIP { rrpv_bits: u8, probability: f64 },
+    /// Dynamic RRIP, which chooses between SRRIP and BRRIP by set dueling. `leader_sets` sets
+    /// always load blocks as each of them, and their misses move a saturating policy selection
+    /// counter (PSEL) of `psel_bits` bits, which decides how the other sets load blocks.
+    /// The parameters are those of BRRIP, `leaders` and `psel`.
+    DRRIP {
+        rrpv_bits: u8,
+        probability: f64,
+        leader_sets: u32,
+        psel_bits: u32,
+    },
+    /// Belady's MIN: evict the block that will be used again furthest in the future, or never.
+    /// This needs the future accesses of the cache (see [`Cache::set_future_accesses`]),
+    /// and evicts like LRU until it has them. The simulator gives the levels below the first the
+    /// trace's accesses to their lines, not their own requests, so there it is only close to optimal.
+    Optimal,
+}
+
+impl Display for EvictionPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::LRU => write!(f, "LRU"),
+            Self::FIFO => write!(f, "FIFO"),
+            Self::Random => write!(f, "Random"),
+            Self::TreePLRU => write!(f, "Tree-PLRU"),
+            Self::NRU => write!(f, "NRU"),
+            Self::Clock => write!(f, "Clock"),
+            Self::SRRIP { .. } => write!(f, "SRRIP"),
+            Self::BRRIP { .. } => write!(f, "BRRIP"),
+            Self::DRRIP { .. } => write!(f, "DRRIP"),
+            Self::Optimal => write!(f, "Optimal"),
+        }?;
+        // Only the parameters that differ from the defaults are written.
+        if let Self::SRRIP { rrpv_bits } | Self::BRRIP { rrpv_bits, .. } | Self::DRRIP { rrpv_bits, .. } = self {
+            if *rrpv_bits != DEFAULT_RRPV_BITS {
+                write!(f, ":bits={rrpv_bits}")?;
+            }
+        }
+        if let Self::BRRIP { probability, .. } | Self::DRRIP { probability, .. } = self {
+            if *probability != DEFAULT_BRRIP_PROBABILITY {
+                write!(f, ":probability={probability}")?;
+            }
+        }
+        if let Self::DRRIP { leader_sets, psel_bits, .. } = self {
+            if *leader_sets != DEFAULT_LEADER_SETS {
+                write!(f, ":leaders={leader_sets}")?;
+            }
+            if *psel_bits != DEFAULT_PSEL_BITS {
+                write!(f, ":psel={psel_bits}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    /// Parse the name of a policy, ignoring case, and then its parameters.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, parameters) = split_policy(s)?;
+        let mut policy = match name.to_ascii_lowercase().as_str() {
+            "lru" => Self::LRU,
+            "fifo" => Self::FIFO,
+            "random" => Self::Random,
+            "tree-plru" | "treeplru" | "plru" => Self::TreePLRU,
+            "nru" | "bit-plru" | "mru-bit" => Self::NRU,
+            "clock" | "second-chance" => Self::Clock,
+            "optimal" | "opt" | "belady" | "min" => Self::Optimal,
+            "srrip" => Self::SRRIP {
+                rrpv_bits: DEFAULT_RRPV_BITS,
+            },
+            "brrip" => Self::BRRIP {
+                rrpv_bits: DEFAULT_RRPV_BITS,
+                probability: DEFAULT_BRRIP_PROBABILITY,
+            },
+            "drrip" => Self::DRRIP {
+                rrpv_bits: DEFAULT_RRPV_BITS,
+                probability: DEFAULT_BRRIP_PROBABILITY,
+                leader_sets: DEFAULT_LEADER_SETS,
+                psel_bits: DEFAULT_PSEL_BITS,
+            },
+            _ => {
+                return Err(format!(
+                    "unknown eviction policy \"{s}\", expected LRU, FIFO, Random, Tree-PLRU, NRU, Clock, SRRIP, BRRIP, DRRIP, or Optimal"
+                ))
+            }
+        };
+        for (key, value) in parameters {
+            policy.set_parameter(key, value)?;
+        }
+        Ok(policy)
+    }
+}
+
+/// The `key=value` parameters written after the name of a policy.
+type Parameters<'a> = Vec<(&'a str, &'a str)>;
+
+/// Split the text of a policy into its name and its parameters.
+fn split_policy(text: &str) -> Result<(&str, Parameters<'_>), String> {
+    let mut parts = text.split(':');
+    let name = parts.next().unwrap_or_default().trim();
+    let parameters = parts
+        .map(|parameter| match parameter.split_once('=') {
+            Some((key, value)) => Ok((key.trim(), value.trim())),
+            None => Err(format!("expected a parameter of {name} as \"key=value\", got \"{parameter}\"")),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((name, parameters))
+}
+
+/// Parse a probability, written as a number or a fraction such as `1/32`.
+fn parse_probability(value: &str) -> Result<f64, String> {
+    let parsed = match value.split_once('/') {
+        Some((numerator, denominator)) => numerator
+            .trim()
+            .parse::<f64>()
+            .and_then(|numerator| Ok(numerator / denominator.trim().parse::<f64>()?)),
+        None => value.parse(),
+    };
+    match parsed {
+        Ok(parsed) if (0.0..=1.0).contains(&parsed) => Ok(parsed),
+        _ => Err(format!("expected a probability from 0 to 1, got \"{value}\"")),
+    }
+}
+
+/// Parse the number of leader sets that a policy using set dueling gives each of its two policies.
+fn parse_leader_sets(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(sets) if sets > 0 => Ok(sets),
+        _ => Err(format!("expected a positive number of leader sets, got \"{value}\"")),
+    }
+}
+
+/// Parse the width of the policy selection counter of a policy using set dueling.
+fn parse_psel_bits(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(bits) if (1..=MAX_PSEL_BITS).contains(&bits) => Ok(bits),
+        _ => Err(format!("expected PSEL bits from 1 to {MAX_PSEL_BITS}, got \"{value}\"")),
+    }
+}
+
+/// Where an LRU cache places a block it loads in the recency order of its set.
+/// The other eviction policies place blocks themselves, as if with MRU insertion.
+///
+/// Policies are written like eviction policies, such as `BIP:epsilon=1/64`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum InsertionPolicy {
+    /// Load blocks as the most recently used block of the set.
+    #[default]
+    MRU,
+    /// LRU insertion: load blocks as the least recently used block of the set, so a block
+    /// that isn't used again before the next miss in the set is the next one evicted.
+    LIP,
+    /// Bimodal insertion: load blocks like LIP, except with probability `epsilon` like MRU.
+    /// The parameter is `epsilon`, which may be a fraction such as `1/32`.
+    BIP { epsilon: f64 },
+    /// Dynamic insertion, which chooses between MRU insertion and BIP by set dueling, like DRRIP.
+    /// The parameters are `epsilon`, `leaders` and `psel`.
+    DIP {
+        epsilon: f64,
+        leader_sets: u32,
+        psel_bits: u32,
+    },
+}
+
+impl Display for InsertionPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::MRU => write!(f, "MRU"),
+            Self::LIP => write!(f, "LIP"),
+            Self::BIP { .. } => write!(f, "BIP"),
+            Self::DIP { .. } => write!(f, "DIP"),
+        }?;
+        // Only the parameters that differ from the defaults are written.
+        if let Self::BIP { epsilon } | Self::DIP { epsilon, .. } = self {
+            if *epsilon != DEFAULT_BIP_EPSILON {
+                write!(f, ":epsilon={epsilon}")?;
+            }
+        }
+        if let Self::DIP { leader_sets, psel_bits, .. } = self {
+            if *leader_sets != DEFAULT_LEADER_SETS {
+                write!(f, ":leaders={leader_sets}")?;
+            }
+            if *psel_bits != DEFAULT_PSEL_BITS {
+                write!(f, ":psel={psel_bits}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for InsertionPolicy {
+    type Err = String;
+
+    /// Parse the name of a policy, ignoring case, and then its parameters.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, parameters) = split_policy(s)?;
+        let mut policy = match name.to_ascii_lowercase().as_str() {
+            "mru" => Self::MRU,
+            "lip" => Self::LIP,
+            "bip" => Self::BIP {
+                epsilon: DEFAULT_BIP_EPSILON,
+            },
+            "dip" => Self::DIP {
+                epsilon: DEFAULT_BIP_EPSILON,
+                leader_sets: DEFAULT_LEADER_SETS,
+                psel_bits: DEFAULT_PSEL_BITS,
+            },
+            _ => return Err(format!("unknown insertion policy \"{s}\", expected MRU, LIP, BIP, or DIP")),
+        };
+        for (key, value) in parameters {
+            let name = policy.to_string();
+            match (&mut policy, key) {
+                (Self::BIP { epsilon } | Self::DIP { epsilon, .. }, "epsilon") => *epsilon = parse_probability(value)?,
+                (Self::DIP { leader_sets, .. }, "leaders") => *leader_sets = parse_leader_sets(value)?,
+                (Self::DIP { psel_bits, .. }, "psel") => *psel_bits = parse_psel_bits(value)?,
+                _ => return Err(format!("{name} has no parameter \"{key}\"")),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+impl From<InsertionPolicy> for String {
+    fn from(policy: InsertionPolicy) -> Self {
+        policy.to_string()
+    }
+}
+
+impl TryFrom<String> for InsertionPolicy {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl From<EvictionPolicy> for String {
+    fn from(policy: EvictionPolicy) -> Self {
+        policy.to_string()
+    }
+}
+
+impl TryFrom<String> for EvictionPolicy {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
 }
 
 impl EvictionPolicy {
+    /// Set one of the parameters of the policy from its text, such as `bits` to `3`.
+    fn set_parameter(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let name = self.to_string();
+        match (self, key) {
+            (
+                Self::SRRIP { rrpv_bits } | Self::BRRIP { rrpv_bits, .. } | Self::DRRIP { rrpv_bits, .. },
+                "bits",
+            ) => match value.parse() {
+                Ok(bits) if (1..=MAX_RRPV_BITS).contains(&bits) => *rrpv_bits = bits,
+                _ => return Err(format!("expected RRPV bits from 1 to {MAX_RRPV_BITS}, got \"{value}\"")),
+            },
+            (Self::BRRIP { probability, .. } | Self::DRRIP { probability, .. }, "probability") => {
+                *probability = parse_probability(value)?
+            }
+            (Self::DRRIP { leader_sets, .. }, "leaders") => *leader_sets = parse_leader_sets(value)?,
+            (Self::DRRIP { psel_bits, .. }, "psel") => *psel_bits = parse_psel_bits(value)?,
+            _ => return Err(format!("{name} has no parameter \"{key}\"")),
+        }
+        Ok(())
+    }
+
     /// Return the tag of the block to evict.
     fn evict(&self, set: &mut Set) -> Option<Block> {
         // Is the set full?
@@ -86,6 +399,9 @@ impl EvictionPolicy {
                 result
             }
 
+            // Without the future, there's nothing to choose by.
+            Self::Optimal if !set.state.knows_future() => Self::LRU.evict(set),
+
             Self::Random => {
                 // Pick a random block to evict
                 let random_index = rand::random::<usize>() % tags.len();
@@ -99,6 +415,161 @@ impl EvictionPolicy {
                 // Return the block to evict
                 Some(result)
             }
+
+            // These choose a way from the metadata they keep for the set.
+            Self::TreePLRU
+            | Self::NRU
+            | Self::Clock
+            | Self::SRRIP { .. }
+            | Self::BRRIP { .. }
+            | Self::DRRIP { .. }
+            | Self::Optimal => {
+                let way = set.state.victim();
+                let result = set.blocks[way].take();
+                trace!(target: "evict", "{self} policy evicting block {result:?} in way {way}");
+                set.state.invalidate(way);
+                result
+            }
+        }
+    }
+}
+
+/// The metadata a replacement policy keeps for each set, alongside its blocks.
+/// LRU and FIFO use the access times of the blocks instead, and Random keeps nothing.
+#[derive(Clone, Debug)]
+enum ReplacementState {
+    None,
+    /// The nodes of a binary tree over the ways, in heap order (the children of node `k` are
+    /// `2k + 1` and `2k + 2`). Each node is set if the victim is in its right half.
+    Tree(Vec<bool>),
+    /// The "recently used" bit of each way.
+    RecentlyUsed(Vec<bool>),
+    /// The reference bit of each way, and the way the hand points at.
+    Clock { referenced: Vec<bool>, hand: usize },
+    /// The RRPV of each way, the largest RRPV, and the probability that a bimodal set
+    /// loads a block with one below the largest RRPV.
+    Rrip { rrpv: Vec<u8>, max: u8, probability: f64 },
+    /// The time each way will next be used, or `u64::MAX` for never, found by the cache from its
+    /// future accesses just before the set evicts a block. None if the cache doesn't know the future.
+    NextUse(Option<Vec<u64>>),
+}
+
+impl ReplacementState {
+    /// The metadata of a policy for an empty set with this many ways.
+    fn new(policy: EvictionPolicy, ways: usize) -> Self {
+        let rrip = |rrpv_bits: u8, probability: f64| {
+            let max = u8::MAX >> (u8::BITS - u32::from(rrpv_bits));
+            Self::Rrip {
+                rrpv: vec![max; ways],
+                max,
+                probability,
+            }
+        };
+        match policy {
+            EvictionPolicy::LRU | EvictionPolicy::FIFO | EvictionPolicy::Random => Self::None,
+            EvictionPolicy::Optimal => Self::NextUse(None),
+            EvictionPolicy::TreePLRU => Self::Tree(vec![false; ways.next_power_of_two() - 1]),
+            EvictionPolicy::NRU => Self::RecentlyUsed(vec![false; ways]),
+            EvictionPolicy::Clock => Self::Clock {
+                referenced: vec![false; ways],
+                hand: 0,
+            },
+            EvictionPolicy::SRRIP { rrpv_bits } => rrip(rrpv_bits, 0.0),
+            EvictionPolicy::BRRIP { rrpv_bits, probability }
+            | EvictionPolicy::DRRIP { rrpv_bits, probability, .. } => rrip(rrpv_bits, probability),
+        }
+    }
+
+    /// Record the loading of a block into a way, by a set that may be bimodal (see [`Set`]).
+    fn insert(&mut self, way: usize, bimodal: bool) {
+        match self {
+            Self::Rrip { rrpv, max, probability } => {
+                let long = !bimodal || rand::random::<f64>() < *probability;
+                rrpv[way] = if long { max.saturating_sub(1) } else { *max };
+            }
+            _ => self.touch(way),
+        }
+    }
+
+    /// Record a use of the block in a way, when it hits or is loaded into the set.
+    fn touch(&mut self, way: usize) {
+        match self {
+            Self::None | Self::NextUse(_) => {}
+            Self::Tree(nodes) => {
+                // Point every node on the path to the way at the other half.
+                let mut node = way + nodes.len();
+                while node > 0 {
+                    let parent = (node - 1) / 2;
+                    nodes[parent] = node == 2 * parent + 1;
+                    node = parent;
+                }
+            }
+            Self::RecentlyUsed(bits) => {
+                bits[way] = true;
+                if bits.iter().all(|bit| *bit) {
+                    bits.fill(false);
+                    bits[way] = true;
+                }
+            }
+            Self::Clock { referenced, .. } => referenced[way] = true,
+            Self::Rrip { rrpv, .. } => rrpv[way] = 0,
+        }
+    }
+
+    /// Forget the use of a way whose block has been evicted or invalidated.
+    fn invalidate(&mut self, way: usize) {
+        match self {
+            Self::None | Self::Tree(_) | Self::NextUse(_) => {}
+            Self::RecentlyUsed(bits) => bits[way] = false,
+            Self::Clock { referenced, .. } => referenced[way] = false,
+            Self::Rrip { rrpv, max, .. } => rrpv[way] = *max,
+        }
+    }
+
+    /// Does the set know when its blocks will next be used?
+    fn knows_future(&self) -> bool {
+        matches!(self, Self::NextUse(Some(_)))
+    }
+
+    /// Choose the way to evict from a full set.
+    fn victim(&mut self) -> usize {
+        match self {
+            Self::None | Self::NextUse(None) => {
+                unreachable!("the policy doesn't keep metadata to choose a victim with")
+            }
+            Self::Tree(nodes) => {
+                let mut node = 0;
+                while node < nodes.len() {
+                    node = 2 * node + 1 + usize::from(nodes[node]);
+                }
+                node - nodes.len()
+            }
+            // Only a set with a single way can have every bit set.
+            Self::RecentlyUsed(bits) => bits.iter().position(|bit| !bit).unwrap_or(0),
+            Self::Clock { referenced, hand } => {
+                while referenced[*hand] {
+                    referenced[*hand] = false;
+                    *hand = (*hand + 1) % referenced.len();
+                }
+                let way = *hand;
+                *hand = (*hand + 1) % referenced.len();
+                way
+            }
+            Self::Rrip { rrpv, max, .. } => loop {
+                if let Some(way) = rrpv.iter().position(|value| value == max) {
+                    break way;
+                }
+                // Age every block of the set by the same amount, until one has the largest RRPV.
+                let oldest = *rrpv.iter().max().unwrap_or(max);
+                for value in rrpv.iter_mut() {
+                    *value += *max - oldest;
+                }
+            },
+            // The first of the blocks used furthest in the future.
+            Self::NextUse(Some(next_use)) => {
+                let furthest = next_use.iter().max().copied().unwrap_or_default();
+                next_use.iter().position(|time| *time == furthest).unwrap_or(0)
+            }
         }
     }
 }
@@ -193,10 +664,10 @@ impl Block {
             self.get_tag(),
             self.get_index()
         );
-        self.dirty = true;
         self.last_access = current_access_time;
     }
 
+    /// Has the block been written to since it was loaded into the cache?
     pub fn is_dirty(&self) -> bool {
         self.dirty
     }
@@ -212,6 +683,13 @@ pub struct Set {
     block_size: u64,
     /// The eviction policy of the set.
     evict_policy: EvictionPolicy,
+    /// The metadata the eviction policy keeps for the ways of the set.
+    state: ReplacementState,
+    /// Where an LRU set places the blocks it loads.
+    insertion_policy: InsertionPolicy,
+    /// Does the set load blocks bimodally, like BRRIP or BIP? This is decided by set dueling in
+    /// DRRIP and DIP caches, and the other policies either always or never load blocks this way.
+    bimodal: bool,
 }
 
 impl Set {
@@ -222,6 +700,31 @@ impl Set {
             blocks: vec![None; associativity as usize],
             block_size,
             evict_policy,
+            state: ReplacementState::new(evict_policy, associativity as usize),
+            insertion_policy: InsertionPolicy::MRU,
+            bimodal: matches!(evict_policy, EvictionPolicy::BRRIP { .. }),
+        }
+    }
+
+    /// Use an insertion policy, which only matters to LRU sets.
+    pub fn set_insertion_policy(&mut self, insertion_policy: InsertionPolicy) {
+        self.insertion_policy = insertion_policy;
+        if self.evict_policy == EvictionPolicy::LRU {
+            self.bimodal = matches!(insertion_policy, InsertionPolicy::BIP { .. });
+        }
+    }
+
+    /// Should the block being loaded go in the LRU position of the set, rather than the MRU position?
+    fn inserts_at_lru(&self) -> bool {
+        if self.evict_policy != EvictionPolicy::LRU {
+            return false;
+        }
+        match self.insertion_policy {
+            InsertionPolicy::MRU => false,
+            InsertionPolicy::LIP => true,
+            InsertionPolicy::BIP { epsilon } | InsertionPolicy::DIP { epsilon, .. } => {
+                self.bimodal && rand::random::<f64>() >= epsilon
+            }
         }
     }
 
@@ -235,6 +738,11 @@ impl Set {
         self.blocks.len()
     }
 
+    /// Does the set hold no blocks at all (an associativity of zero)?
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
     /// Insert the block into the set.
     /// If the set is full, then evict a block.
     /// This will return the block that was evicted, if any.
@@ -248,17 +756,26 @@ impl Set {
             None
         };
 
+        let mut new_block = Block::new(block.tag, block.index, self.block_size, current_access_time);
+        if self.inserts_at_lru() {
+            // Make the block older than every other block in the set. If the oldest was
+            // last accessed at time 0, the others are moved up first, keeping their order.
+            if let Some(oldest) = self.blocks.iter().flatten().map(|block| block.last_access).min() {
+                if oldest == 0 {
+                    for block in self.blocks.iter_mut().flatten() {
+                        block.last_access += 1;
+                    }
+                }
+                new_block.last_access = oldest.saturating_sub(1);
+            }
+        }
+
         // Find the first empty block slot
-        for block_slot in self.blocks.iter_mut() {
+        for (way, block_slot) in self.blocks.iter_mut().enumerate() {
             if block_slot.is_none() {
                 // Allocate the block
-                *block_slot = Some(Block::new(
-                    block.tag,
-                    block.index,
-                    self.block_size,
-                    current_access_time,
-                ));
-                
+                *block_slot = Some(new_block);
+                self.state.insert(way, self.bimodal);
                 break;
             }
         }
@@ -283,12 +800,13 @@ impl Set {
     fn evict_tag(&mut self, tag: u64) -> Option<Block> {
         // trace!("Evicting block with tag={tag:x}");
         // Find the block with the matching tag and index
-        for block_slot in self.blocks.iter_mut() {
+        for (way, block_slot) in self.blocks.iter_mut().enumerate() {
             let result = *block_slot;
             if let Some(present_block) = result {
                 if present_block.tag == tag {
                     // trace!("Evicting block {block_slot:?}");
                     *block_slot = None;
+                    self.state.invalidate(way);
                     assert!(!self.get_tags().contains(&tag));
                     return result;
                 }
@@ -307,17 +825,11 @@ impl Set {
     pub fn get_tags(&self) -> Vec<u64> {
         self.blocks
             .iter()
-            .filter_map(|block| {
-                if let Some(block) = block {
-                    Some(block.tag)
-                } else {
-                    None
-                }
-            })
+            .filter_map(|block| block.map(|block| block.tag))
             .collect()
     }
 
-    /// Return the block with the given tag.
+    // Return the block with the given tag.
     // fn get_block_with_tag_mut(&mut self, tag: u64) -> Option<&mut Block> {
     //     // Find the block with the matching tag and index
     //     for block in self.blocks.iter_mut() {
@@ -333,42 +845,33 @@ impl Set {
     /// Return the block with the given tag.
     fn get_block_with_tag(&mut self, tag: u64) -> Option<&Block> {
         // Find the block with the matching tag and index
-        for block in self.blocks.iter() {
-            if let Some(block) = block {
-                if block.tag == tag {
-                    return Some(block);
-                }
-            }
-        }
-        None
+        self.blocks.iter().flatten().find(|block| block.tag == tag)
     }
 
-    /// Get the block at the given address.
+    /// Get the block at the given address, recording the use of its way with the eviction policy.
     /// Return None if the block is not in the set.
     /// Return Some(block) if the block is in the set.
     fn get_block_with_addr_mut(&mut self, block_address: BlockAddress) -> Option<&mut Block> {
+        let way = self.get_way(block_address)?;
+        self.state.touch(way);
+        self.blocks[way].as_mut()
+    }
+
+    /// Get the way holding the block at the given address, if it is in the set.
+    fn get_way(&self, block_address: BlockAddress) -> Option<usize> {
         let tag = block_address.tag;
-        for block in self.blocks.iter_mut() {
-            if let Some(block) = block {
-                if block.tag == tag && block.index == block_address.index {
-                    return Some(block);
-                }
-            }
-        }
-        None
+        self.blocks.iter().position(|block| {
+            block.is_some_and(|block| block.tag == tag && block.index == block_address.index)
+        })
     }
 
     /// Get the block associated with the block address.
     fn get_block_with_addr(&self, block_address: BlockAddress) -> Option<&Block> {
         let tag = block_address.tag;
-        for block in self.blocks.iter() {
-            if let Some(block) = block {
-                if block.tag == tag && block.index == block_address.index {
-                    return Some(block);
-                }
-            }
-        }
-        None
+        self.blocks
+            .iter()
+            .flatten()
+            .find(|block| block.tag == tag && block.index == block_address.index)
     }
 
     /// Does this set contain the block at the given address?
@@ -417,9 +920,13 @@ impl Set {
         // If the operation failed, allocate the block and then try again
         assert!(!self.is_hit(block_address));
         let result = self.allocate_block(block_address, current_access_time);
-        // It *MUST* work after the block has been allocated.
-        // Otherwise it was not allocated properly
-        assert!(self.is_hit(block_address));
+        // It *MUST* be in the set after the block has been allocated.
+        // Otherwise it was not allocated properly. The write only makes it dirty, since loading
+        // it already placed it in the set's recency order.
+        let way = self.get_way(block_address).expect("the block was just allocated");
+        if let Some(block) = self.blocks[way].as_mut() {
+            block.dirty = true;
+        }
         result
     }
 
@@ -474,6 +981,156 @@ impl Set {
     }
 }
 
+/// The number of PSEL values a DRRIP cache keeps in its history. Once it is full, every other
+/// value is dropped and the rest are sampled half as often, so the history spans the whole simulation.
+const PSEL_HISTORY_LENGTH: usize = 64;
+
+/// The set dueling of a DRRIP or DIP cache, between a policy and its bimodal counterpart
+/// (SRRIP and BRRIP, or MRU insertion and BIP): which sets lead for each, and the policy selection
+/// counter (PSEL) their misses move. Misses in the leaders of the first policy increment it,
+/// misses in those of the bimodal one decrement it, and the follower sets load blocks bimodally
+/// while it is above its midpoint.
+#[derive(Clone, Debug)]
+struct SetDueling {
+    /// The names of the two policies.
+    policies: [&'static str; 2],
+    /// The role of each set: None for a follower, or whether a leader loads blocks bimodally.
+    leaders: Vec<Option<bool>>,
+    psel: u64,
+    psel_max: u64,
+    /// The number of accesses to the cache.
+    accesses: u64,
+    /// The value of PSEL after every `interval` accesses.
+    history: Vec<u64>,
+    interval: u64,
+}
+
+impl SetDueling {
+    /// Spread `leader_sets` leaders of each policy over the sets, as far apart as possible.
+    /// Every set leads if there are no more than twice as many sets as leaders.
+    fn new(policies: [&'static str; 2], sets: usize, leader_sets: u32, psel_bits: u32) -> Self {
+        let region = (sets / leader_sets as usize).max(2);
+        let leaders = (0..sets)
+            .map(|index| match index % region {
+                0 => Some(false),
+                offset if offset == region / 2 => Some(true),
+                _ => None,
+            })
+            .collect();
+        let psel_max = u64::MAX >> (u64::BITS - psel_bits);
+        Self {
+            policies,
+            leaders,
+            psel: psel_max / 2,
+            psel_max,
+            accesses: 0,
+            history: vec![],
+            interval: 1,
+        }
+    }
+
+    /// Does the set load blocks bimodally?
+    fn is_bimodal(&self, index: usize) -> bool {
+        self.leaders[index].unwrap_or(self.psel > self.psel_max / 2)
+    }
+
+    /// Count an access to a set, moving PSEL if it missed in a leader.
+    fn record(&mut self, index: usize, hit: bool) {
+        match self.leaders[index] {
+            Some(false) if !hit => self.psel = (self.psel + 1).min(self.psel_max),
+            Some(true) if !hit => self.psel = self.psel.saturating_sub(1),
+            _ => {}
+        }
+        self.accesses += 1;
+        if self.accesses.is_multiple_of(self.interval) {
+            self.history.push(self.psel);
+            if self.history.len() == PSEL_HISTORY_LENGTH {
+                self.history = self.history.iter().skip(1).step_by(2).copied().collect();
+                self.interval *= 2;
+            }
+        }
+    }
+
+    fn stats(&self) -> DuelingStats {
+        let leaders = |bimodal: bool| {
+            self.leaders
+                .iter()
+                .enumerate()
+                .filter(|(_, role)| **role == Some(bimodal))
+                .map(|(index, _)| index)
+                .collect()
+        };
+        DuelingStats {
+            policies: self.policies,
+            leaders: [leaders(false), leaders(true)],
+            psel: self.psel,
+            psel_max: self.psel_max,
+            history: self.history.clone(),
+            history_interval: self.interval,
+        }
+    }
+}
+
+/// The set dueling of a DRRIP or DIP cache, for its statistics.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DuelingStats {
+    /// The names of the two policies, such as SRRIP and BRRIP.
+    pub policies: [&'static str; 2],
+    /// The indices of the leader sets of each policy.
+    pub leaders: [Vec<usize>; 2],
+    /// The final value of the policy selection counter. The follower sets load blocks with
+    /// the second policy while it is above half of `psel_max`.
+    pub psel: u64,
+    pub psel_max: u64,
+    /// The value of the counter after every `history_interval` accesses to the cache.
+    pub history: Vec<u64>,
+    pub history_interval: u64,
+}
+
+impl DuelingStats {
+    /// The policy the follower sets load blocks with at the end of the simulation.
+    pub fn follower_policy(&self) -> &'static str {
+        self.policies[usize::from(self.psel > self.psel_max / 2)]
+    }
+}
+
+/// The accesses a cache will make, for Belady's optimal replacement: the times at which each
+/// block will be accessed, and the times at which the whole cache will be flushed.
+#[derive(Clone, Debug, Default)]
+pub struct FutureAccesses {
+    /// The times of the accesses to each block, by its tag and index, in order.
+    accesses: HashMap<(u64, u64), Vec<u64>>,
+    /// The times of the flushes, in order.
+    flushes: Vec<u64>,
+}
+
+impl FutureAccesses {
+    /// Add an access to the block at the given address. Accesses must be added in order.
+    pub fn add_access(&mut self, address: BlockAddress, time: u64) {
+        self.accesses.entry((address.tag, address.index)).or_default().push(time);
+    }
+
+    /// Add a flush of the cache, at the start of the access at `time`.
+    /// Flushes must be added in order.
+    pub fn add_flush(&mut self, time: u64) {
+        self.flushes.push(time);
+    }
+
+    /// The first time after `time` that the block will be accessed, or `u64::MAX` if it won't
+    /// be before the cache is next flushed.
+    fn next_use(&self, block: &Block, time: u64) -> u64 {
+        let Some(times) = self.accesses.get(&(block.tag, block.index)) else {
+            return u64::MAX;
+        };
+        let next = times.get(times.partition_point(|t| *t <= time)).copied().unwrap_or(u64::MAX);
+        let flush = self.flushes.get(self.flushes.partition_point(|t| *t <= time)).copied();
+        match flush {
+            Some(flush) if flush <= next => u64::MAX,
+            _ => next,
+        }
+    }
+}
+
 /// A cache.
 /// This contains the sets in the cache.
 /// The cache is indexed by the index of the block.
@@ -491,6 +1148,10 @@ pub struct Cache {
     /// This is used to determine which block to evict when a block is
     /// inserted into a set that is full.
     evict_policy: EvictionPolicy,
+    /// The set dueling of a DRRIP or DIP cache.
+    dueling: Option<SetDueling>,
+    /// The accesses the cache will make, for optimal replacement.
+    future: Option<FutureAccesses>,
 }
 
 impl Cache {
@@ -505,16 +1166,23 @@ impl Cache {
             sets: vec![Set::new(block_size, associativity, evict_policy); sets],
             associativity,
             evict_policy,
+            dueling: match evict_policy {
+                EvictionPolicy::DRRIP {
+                    leader_sets,
+                    psel_bits,
+                    ..
+                } => Some(SetDueling::new(["SRRIP", "BRRIP"], sets, leader_sets, psel_bits)),
+                _ => None,
+            },
+            future: None,
         }
     }
 
     pub fn get_blocks(&self) -> Vec<&Block> {
         let mut result = Vec::new();
         for set in self.sets.iter() {
-            for block in set.blocks.iter() {
-                if let Some(block) = block {
-                    result.push(block);
-                }
+            for block in set.blocks.iter().flatten() {
+                result.push(block);
             }
         }
         result
@@ -566,6 +1234,11 @@ impl Cache {
         self.sets.len()
     }
 
+    /// Does the cache have no sets?
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+
     /// Return the size of the cache in bytes (the combined size of the sets in the cache).
     /// This is the size of the cache in bytes.
     pub fn size_in_bytes(&self) -> u64 {
@@ -603,7 +1276,7 @@ impl Cache {
         address: BlockAddress,
         current_access_time: u64,
     ) -> Option<Block> {
-        let set = &mut self.sets[address.index as usize];
+        let set = self.get_set_mut(address, current_access_time);
         set.write_and_allocate(address, current_access_time)
     }
 
@@ -615,7 +1288,7 @@ impl Cache {
         address: BlockAddress,
         current_access_time: u64,
     ) -> Option<Block> {
-        let set = &mut self.sets[address.index as usize];
+        let set = self.get_set_mut(address, current_access_time);
         set.read_and_allocate(address, current_access_time)
     }
 
@@ -626,7 +1299,7 @@ impl Cache {
         address: BlockAddress,
         current_access_time: u64,
     ) -> bool {
-        let set = &mut self.sets[address.index as usize];
+        let set = self.get_set_mut(address, current_access_time);
         set.is_write_and_allocate_hit(address, current_access_time)
     }
 
@@ -637,7 +1310,7 @@ impl Cache {
         address: BlockAddress,
         current_access_time: u64,
     ) -> bool {
-        let set = &mut self.sets[address.index as usize];
+        let set = self.get_set_mut(address, current_access_time);
         set.is_read_and_allocate_hit(address, current_access_time)
     }
 
@@ -645,7 +1318,7 @@ impl Cache {
     /// If the block is not in the cache, then do nothing.
     /// Returns whether or not the write was a hit.
     pub fn try_write(&mut self, address: BlockAddress, current_access_time: u64) -> bool {
-        let set = &mut self.sets[address.index as usize];
+        let set = self.get_set_mut(address, current_access_time);
         set.try_write(address, current_access_time)
     }
 
@@ -653,10 +1326,66 @@ impl Cache {
     /// If the block is not in the cache, then do nothing.
     /// Returns whether or not the read was a hit.
     pub fn try_read(&mut self, address: BlockAddress, current_access_time: u64) -> bool {
-        let set = &mut self.sets[address.index as usize];
+        let set = self.get_set_mut(address, current_access_time);
         set.try_read(address, current_access_time)
     }
 
+    /// Get the set of the block at the given address, to access it at `current_access_time`.
+    /// In a DRRIP or DIP cache, this counts the access for set dueling, and sets how the set loads blocks.
+    /// In a cache that knows its future accesses, a full set that misses finds when its blocks will next be used.
+    fn get_set_mut(&mut self, address: BlockAddress, current_access_time: u64) -> &mut Set {
+        let index = address.index as usize;
+        let set = &mut self.sets[index];
+        if let Some(dueling) = &mut self.dueling {
+            dueling.record(index, set.is_hit(address));
+            set.bimodal = dueling.is_bimodal(index);
+        }
+        if let Some(future) = &self.future {
+            if set.is_full() && !set.is_hit(address) {
+                let next_use = set
+                    .blocks
+                    .iter()
+                    .flatten()
+                    .map(|block| future.next_use(block, current_access_time))
+                    .collect();
+                set.state = ReplacementState::NextUse(Some(next_use));
+            }
+        }
+        set
+    }
+
+    /// Give an Optimal cache the accesses it will make, to choose its victims with.
+    /// Other policies ignore them.
+    pub fn set_future_accesses(&mut self, future: FutureAccesses) {
+        if self.evict_policy == EvictionPolicy::Optimal {
+            self.future = Some(future);
+        }
+    }
+
+    /// Is this an Optimal cache that hasn't been given its future accesses?
+    pub fn needs_future(&self) -> bool {
+        self.evict_policy == EvictionPolicy::Optimal && self.future.is_none()
+    }
+
+    /// Use an insertion policy for every set. It only matters to an LRU cache, and a DIP
+    /// policy replaces the set dueling of any other policy.
+    pub fn with_insertion_policy(mut self, insertion_policy: InsertionPolicy) -> Self {
+        for set in self.sets.iter_mut() {
+            set.set_insertion_policy(insertion_policy);
+        }
+        if let (EvictionPolicy::LRU, InsertionPolicy::DIP { leader_sets, psel_bits, .. }) =
+            (self.evict_policy, insertion_policy)
+        {
+            self.dueling = Some(SetDueling::new(["MRU", "BIP"], self.sets.len(), leader_sets, psel_bits));
+        }
+        self
+    }
+
+    /// Get the leader sets and policy selection counter of a DRRIP or DIP cache.
+    pub fn get_dueling_stats(&self) -> Option<DuelingStats> {
+        self.dueling.as_ref().map(SetDueling::stats)
+    }
+
     /// Return the number of blocks in the cache.
     /// This is the number of sets in the cache multiplied by the associativity.
     /// This is the number of blocks that can be stored in the cache.
@@ -668,4 +1397,353 @@ impl Cache {
     pub fn invalidate(&mut self, address: BlockAddress) -> Option<Block> {
         self.sets[address.index as usize].evict_addr(address)
     }
+
+    /// Evict every block in the cache, and return them.
+    pub fn flush(&mut self) -> Vec<Block> {
+        let mut result = vec![];
+        for set in self.sets.iter_mut() {
+            result.extend(set.blocks.iter_mut().filter_map(Option::take));
+            set.state = ReplacementState::new(set.evict_policy, set.blocks.len());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: u64 = 0xa;
+    const B: u64 = 0xb;
+    const C: u64 = 0xc;
+    const D: u64 = 0xd;
+    const E: u64 = 0xe;
+    const F: u64 = 0xf;
+    const G: u64 = 0x10;
+
+    /// The address of the block with a tag in a set, in a cache of up to 8 sets.
+    fn address(tag: u64, index: u64) -> BlockAddress {
+        BlockAddress::new(tag << 3 | index, 32, 3, 0)
+    }
+
+    /// Read the blocks with the given tags from the first set, one per unit of time,
+    /// and return the tags of the blocks evicted.
+    fn evictions(cache: &mut Cache, tags: &[u64]) -> Vec<u64> {
+        tags.iter()
+            .zip(1..)
+            .filter_map(|(tag, time)| cache.read_and_allocate(address(*tag, 0), time))
+            .map(|block| block.get_tag())
+            .collect()
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_block() {
+        let mut cache = Cache::new(1, 16, 4, EvictionPolicy::LRU);
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, A, E, F]), [B, C]);
+    }
+
+    #[test]
+    fn tree_plru_follows_the_tree_away_from_recent_uses() {
+        // After A is used, the root points at the right half (C and D), and C was used before D.
+        let mut cache = Cache::new(1, 16, 4, EvictionPolicy::TreePLRU);
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, A, E]), [C]);
+        // E's use points the root back at the left half, where A's use points away from A.
+        assert_eq!(evictions(&mut cache, &[F]), [B]);
+    }
+
+    #[test]
+    fn nru_evicts_the_first_block_not_recently_used() {
+        // Loading D sets the last bit, so the others are cleared; B's hit sets its bit again.
+        let mut cache = Cache::new(1, 16, 4, EvictionPolicy::NRU);
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, B, E, F]), [A, C]);
+        // Loading F set the last clear bit, so every bit but F's was cleared.
+        assert_eq!(evictions(&mut cache, &[G]), [E]);
+    }
+
+    #[test]
+    fn clock_gives_referenced_blocks_a_second_chance() {
+        // E's miss sweeps the hand around the set, clearing every bit, and stops at A.
+        // C's hit then saves it from the hand, which evicts B and then skips C for D.
+        let mut cache = Cache::new(1, 16, 4, EvictionPolicy::Clock);
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, E, C, F, G]), [A, B, D]);
+    }
+
+    #[test]
+    fn sets_choose_their_victims_independently() {
+        let mut cache = Cache::new(2, 16, 2, EvictionPolicy::TreePLRU);
+        let mut read = |tag: u64, index: u64, time: u64| {
+            cache.read_and_allocate(address(tag, index), time).map(|block| block.get_tag())
+        };
+        assert_eq!(read(A, 0, 1), None);
+        assert_eq!(read(B, 0, 2), None);
+        assert_eq!(read(C, 1, 3), None);
+        assert_eq!(read(A, 0, 4), None);
+        assert_eq!(read(D, 1, 5), None);
+        assert_eq!(read(E, 0, 6), Some(B));
+        assert_eq!(read(F, 1, 7), Some(C));
+    }
+
+    #[test]
+    fn srrip_ages_the_set_until_a_block_is_distant() {
+        // Blocks are loaded with an RRPV of 2, and B's hit makes its RRPV 0, so it outlives the others.
+        let mut cache = Cache::new(1, 16, 4, EvictionPolicy::SRRIP { rrpv_bits: 2 });
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, B, E, F, G]), [A, C, D]);
+        // Once every other block has been aged to 3 and evicted, B is aged too.
+        assert_eq!(evictions(&mut cache, &[B, A]), [E]);
+    }
+
+    #[test]
+    fn brrip_loads_blocks_as_distant() {
+        // Without long insertions, a block that isn't used again is the next one evicted.
+        let policy = EvictionPolicy::BRRIP {
+            rrpv_bits: 2,
+            probability: 0.0,
+        };
+        let mut cache = Cache::new(1, 16, 4, policy);
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, A, E, F, G]), [B, E, F]);
+
+        // With only long insertions, BRRIP is SRRIP.
+        let policy = EvictionPolicy::BRRIP {
+            rrpv_bits: 2,
+            probability: 1.0,
+        };
+        let mut cache = Cache::new(1, 16, 4, policy);
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, B, E, F, G]), [A, C, D]);
+    }
+
+    #[test]
+    fn wider_rrpvs_age_blocks_longer() {
+        let mut cache = Cache::new(1, 16, 2, EvictionPolicy::SRRIP { rrpv_bits: 3 });
+        assert_eq!(evictions(&mut cache, &[A, B, A, C, D]), [B, C]);
+    }
+
+    #[test]
+    fn set_dueling_spreads_the_leaders() {
+        let dueling = SetDueling::new(["SRRIP", "BRRIP"], 8, 2, 4);
+        let stats = dueling.stats();
+        assert_eq!(stats.leaders, [vec![0, 4], vec![2, 6]]);
+        assert_eq!((stats.psel, stats.psel_max), (7, 15));
+        assert_eq!(stats.follower_policy(), "SRRIP");
+
+        // With as many leaders of each policy as half the sets, every set leads.
+        let stats = SetDueling::new(["SRRIP", "BRRIP"], 4, 8, 4).stats();
+        assert_eq!(stats.leaders, [vec![0, 2], vec![1, 3]]);
+    }
+
+    #[test]
+    fn leader_misses_move_psel_within_its_bounds() {
+        let mut dueling = SetDueling::new(["SRRIP", "BRRIP"], 8, 2, 4);
+        // Hits, and misses in followers, leave PSEL where it is.
+        dueling.record(0, true);
+        dueling.record(1, false);
+        assert_eq!(dueling.psel, 7);
+        assert!(!dueling.is_bimodal(1));
+
+        // Misses in the SRRIP leaders push the followers to BRRIP, and the leaders keep their policies.
+        dueling.record(0, false);
+        assert_eq!(dueling.psel, 8);
+        assert!(dueling.is_bimodal(1));
+        assert!(!dueling.is_bimodal(0));
+        assert!(dueling.is_bimodal(2));
+        for _ in 0..20 {
+            dueling.record(4, false);
+        }
+        assert_eq!(dueling.psel, 15);
+
+        // Misses in the BRRIP leaders bring them back.
+        for _ in 0..20 {
+            dueling.record(6, false);
+        }
+        assert_eq!(dueling.psel, 0);
+        assert!(!dueling.is_bimodal(1));
+        assert_eq!(dueling.stats().follower_policy(), "SRRIP");
+    }
+
+    #[test]
+    fn psel_history_is_sampled_less_often_as_it_fills() {
+        let mut dueling = SetDueling::new(["SRRIP", "BRRIP"], 8, 2, 4);
+        for _ in 0..PSEL_HISTORY_LENGTH {
+            dueling.record(0, false);
+        }
+        let stats = dueling.stats();
+        assert_eq!(stats.history_interval, 2);
+        assert_eq!(stats.history.len(), PSEL_HISTORY_LENGTH / 2);
+        // The values after every second access are kept.
+        assert_eq!(stats.history[..4], [9, 11, 13, 15]);
+    }
+
+    #[test]
+    fn drrip_followers_use_the_policy_winning_the_duel() {
+        let policy = EvictionPolicy::DRRIP {
+            rrpv_bits: 2,
+            probability: 0.0,
+            leader_sets: 2,
+            psel_bits: 4,
+        };
+        let mut cache = Cache::new(8, 16, 2, policy);
+        let mut time = 0;
+        let mut read = |tag: u64, index: u64| {
+            time += 1;
+            cache.read_and_allocate(address(tag, index), time).map(|block| block.get_tag())
+        };
+        // Set 1 follows SRRIP at first, so A's hit protects it from the scan.
+        read(A, 1);
+        read(B, 1);
+        read(A, 1);
+        assert_eq!(read(C, 1), Some(B));
+        assert_eq!(read(D, 1), Some(C));
+
+        // A miss in an SRRIP leader turns the followers to BRRIP, so E is loaded as distant,
+        // and is evicted before D, which was loaded earlier.
+        read(A, 0);
+        assert_eq!(read(E, 1), Some(A));
+        assert_eq!(read(F, 1), Some(E));
+        let stats = cache.get_dueling_stats().unwrap();
+        assert_eq!(stats.psel, 8);
+        assert_eq!(stats.follower_policy(), "BRRIP");
+    }
+
+    #[test]
+    fn mru_insertion_keeps_the_newest_blocks() {
+        let mut cache = Cache::new(1, 16, 4, EvictionPolicy::LRU).with_insertion_policy(InsertionPolicy::MRU);
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, E, F]), [A, B]);
+    }
+
+    #[test]
+    fn lip_loads_blocks_in_the_lru_position() {
+        // Blocks that aren't used again replace each other, and the first blocks stay.
+        let mut cache = Cache::new(1, 16, 4, EvictionPolicy::LRU).with_insertion_policy(InsertionPolicy::LIP);
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, E, F]), [D, E]);
+
+        // A hit moves a block to the MRU position as usual.
+        let mut cache = Cache::new(1, 16, 4, EvictionPolicy::LRU).with_insertion_policy(InsertionPolicy::LIP);
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, D, E, F]), [C, E]);
+    }
+
+    #[test]
+    fn bip_loads_blocks_in_the_mru_position_with_probability_epsilon() {
+        let bip = |epsilon: f64| {
+            Cache::new(1, 16, 4, EvictionPolicy::LRU).with_insertion_policy(InsertionPolicy::BIP { epsilon })
+        };
+        assert_eq!(evictions(&mut bip(0.0), &[A, B, C, D, E, F]), [D, E]);
+        assert_eq!(evictions(&mut bip(1.0), &[A, B, C, D, E, F]), [A, B]);
+    }
+
+    #[test]
+    fn insertion_policies_only_change_lru_sets() {
+        let mut cache = Cache::new(1, 16, 4, EvictionPolicy::FIFO).with_insertion_policy(InsertionPolicy::LIP);
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, E, F]), [A, B]);
+        assert!(cache.get_dueling_stats().is_none());
+
+        let dip = InsertionPolicy::DIP {
+            epsilon: 0.0,
+            leader_sets: 2,
+            psel_bits: 4,
+        };
+        let cache = Cache::new(8, 16, 4, EvictionPolicy::SRRIP { rrpv_bits: 2 }).with_insertion_policy(dip);
+        assert!(cache.get_dueling_stats().is_none());
+    }
+
+    #[test]
+    fn dip_followers_use_the_insertion_winning_the_duel() {
+        let dip = InsertionPolicy::DIP {
+            epsilon: 0.0,
+            leader_sets: 2,
+            psel_bits: 4,
+        };
+        let mut cache = Cache::new(8, 16, 2, EvictionPolicy::LRU).with_insertion_policy(dip);
+        let mut time = 0;
+        let mut read = |tag: u64, index: u64| {
+            time += 1;
+            cache.read_and_allocate(address(tag, index), time).map(|block| block.get_tag())
+        };
+        // Set 1 follows MRU insertion at first.
+        read(A, 1);
+        read(B, 1);
+        assert_eq!(read(C, 1), Some(A));
+
+        // A miss in an MRU leader turns the followers to BIP, so D is loaded in the LRU position,
+        // and is evicted before C.
+        read(A, 0);
+        assert_eq!(read(D, 1), Some(B));
+        assert_eq!(read(E, 1), Some(D));
+
+        // The BIP leaders load blocks in the LRU position whatever PSEL says, and their misses
+        // turn the followers back to MRU insertion.
+        read(A, 2);
+        read(B, 2);
+        assert_eq!(read(C, 2), Some(B));
+
+        let stats = cache.get_dueling_stats().unwrap();
+        assert_eq!(stats.policies, ["MRU", "BIP"]);
+        assert_eq!(stats.follower_policy(), "MRU");
+    }
+
+    /// The accesses to the blocks with the given tags in the first set, one per unit of time.
+    fn future(tags: &[u64]) -> FutureAccesses {
+        let mut future = FutureAccesses::default();
+        for (tag, time) in tags.iter().zip(1..) {
+            future.add_access(address(*tag, 0), time);
+        }
+        future
+    }
+
+    #[test]
+    fn the_next_use_of_a_block_ends_at_a_flush() {
+        let mut future = future(&[A, B, A, A]);
+        future.add_flush(4);
+        let a = Block::new(A, 0, 16, 1);
+        assert_eq!(future.next_use(&a, 1), 3);
+        // A is used at 4, but the cache is flushed just before, so it is lost anyway.
+        assert_eq!(future.next_use(&a, 3), u64::MAX);
+        assert_eq!(future.next_use(&a, 4), u64::MAX);
+        assert_eq!(future.next_use(&Block::new(B, 0, 16, 2), 2), u64::MAX);
+        assert_eq!(future.next_use(&Block::new(C, 0, 16, 2), 0), u64::MAX);
+    }
+
+    #[test]
+    fn optimal_evicts_the_block_used_furthest_in_the_future() {
+        // LRU misses every time on a loop over one more block than the set holds, while
+        // Belady's MIN keeps one of the loop's blocks and hits every other access after the first round.
+        let tags = [A, B, C, A, B, C, A, B, C];
+        let hits = |cache: &mut Cache| {
+            tags.iter()
+                .zip(1..)
+                .filter(|(tag, time)| cache.is_read_and_allocate_hit(address(**tag, 0), *time))
+                .count()
+        };
+        assert_eq!(hits(&mut Cache::new(1, 16, 2, EvictionPolicy::LRU)), 0);
+
+        let mut cache = Cache::new(1, 16, 2, EvictionPolicy::Optimal);
+        assert!(cache.needs_future());
+        cache.set_future_accesses(future(&tags));
+        assert!(!cache.needs_future());
+        assert_eq!(hits(&mut cache), 3);
+
+        let mut cache = Cache::new(1, 16, 2, EvictionPolicy::Optimal);
+        cache.set_future_accesses(future(&tags));
+        assert_eq!(evictions(&mut cache, &tags), [B, A, C, B]);
+    }
+
+    #[test]
+    fn optimal_evicts_blocks_never_used_again_first() {
+        let tags = [A, B, C, D, A, C, D];
+        let mut cache = Cache::new(1, 16, 3, EvictionPolicy::Optimal);
+        cache.set_future_accesses(future(&tags));
+        assert_eq!(evictions(&mut cache, &tags), [B]);
+    }
+
+    #[test]
+    fn optimal_evicts_like_lru_without_its_future() {
+        let mut cache = Cache::new(1, 16, 4, EvictionPolicy::Optimal);
+        assert_eq!(evictions(&mut cache, &[A, B, C, D, A, E, F]), [B, C]);
+    }
+
+    #[test]
+    fn only_optimal_caches_keep_their_future() {
+        let mut cache = Cache::new(1, 16, 2, EvictionPolicy::LRU);
+        assert!(!cache.needs_future());
+        cache.set_future_accesses(future(&[A, B, C, A]));
+        assert!(cache.future.is_none());
+    }
 }
@@ -1,73 +1,824 @@
 use std::{
     fmt::{Display, Formatter, Result as FmtResult},
-    io::{BufReader, Read},
+    io::Read,
+    str::FromStr,
 };
 
-use super::{get_bool, get_decimal, get_header};
-use crate::EvictionPolicy;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug)]
+use super::{get_bool, get_decimal, get_header, get_optional, parse_bool, LineReader};
+use crate::{EvictionPolicy, InsertionPolicy, SimulatorConfigBuilder};
+
+/// The layouts a configuration file can be written in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    /// The strict, order-dependent layout of `trace.config`.
+    Legacy,
+    /// A TOML document with one table per component.
+    Toml,
+    /// A JSON document with one object per component.
+    Json,
+}
+
+impl ConfigFormat {
+    /// Choose the format from a file's extension: `.toml` and `.json` files are
+    /// structured, and anything else uses the legacy layout.
+    pub fn from_path(path: &str) -> Self {
+        match std::path::Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+        {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => Self::Toml,
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Legacy,
+        }
+    }
+
+    /// Parse the name of a format, as given on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "legacy" | "config" => Some(Self::Legacy),
+            "toml" => Some(Self::Toml),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// An error encountered while reading a configuration file.
+/// Every variant carries the line number (starting at 1) where the problem was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A section header was expected, but a different line was found.
+    MissingSection {
+        line: usize,
+        expected: String,
+        found: String,
+    },
+    /// A key-value pair with a given key was expected, but a different line was found.
+    UnknownKey {
+        line: usize,
+        expected: String,
+        found: String,
+    },
+    /// The value for a key is not a valid number.
+    BadNumber {
+        line: usize,
+        key: String,
+        value: String,
+    },
+    /// The value for a key is not a valid boolean ("y" or "n").
+    BadBool {
+        line: usize,
+        key: String,
+        value: String,
+    },
+    /// The value for a key is not the name of a known policy.
+    BadPolicy {
+        line: usize,
+        key: String,
+        value: String,
+    },
+    /// The file ended before an expected line was read.
+    UnexpectedEof { line: usize, expected: String },
+    /// A structured (TOML or JSON) configuration is malformed, or has a field of the wrong type.
+    Syntax { line: usize, message: String },
+    /// A command-line override (`key=value`) names an unknown key or has a bad value.
+    BadOverride { assignment: String, message: String },
+    /// The configuration has a value that the requested format cannot hold, such as a number
+    /// above `i64::MAX` in TOML.
+    Unrepresentable { format: String, message: String },
+    /// The file could not be opened or read.
+    Io(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::MissingSection {
+                line,
+                expected,
+                found,
+            } => write!(f, "line {line}: expected section \"{expected}\", got \"{found}\""),
+            Self::UnknownKey {
+                line,
+                expected,
+                found,
+            } => write!(f, "line {line}: expected \"{expected}: {{value}}\", got \"{found}\""),
+            Self::BadNumber { line, key, value } => {
+                write!(f, "line {line}: expected a number for \"{key}\", got \"{value}\"")
+            }
+            Self::BadBool { line, key, value } => {
+                write!(f, "line {line}: expected \"y\" or \"n\" for \"{key}\", got \"{value}\"")
+            }
+            Self::BadPolicy { line, key, value } => {
+                write!(f, "line {line}: unknown policy \"{value}\" for \"{key}\"")
+            }
+            Self::UnexpectedEof { line, expected } => {
+                write!(f, "line {line}: unexpected end of file, expected \"{expected}\"")
+            }
+            Self::Syntax { line, message } => write!(f, "line {line}: {message}"),
+            Self::BadOverride {
+                assignment,
+                message,
+            } => write!(f, "bad override \"{assignment}\": {message}"),
+            Self::Unrepresentable { format, message } => {
+                write!(f, "cannot write the configuration as {format}: {message}")
+            }
+            Self::Io(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The maximum number of sets in the data TLB supported by the reference simulator.
+pub const MAX_TLB_SETS: u64 = 256;
+/// The maximum associativity of any cache supported by the reference simulator.
+pub const MAX_SET_SIZE: u64 = 8;
+/// The maximum number of virtual pages supported by the reference simulator.
+pub const MAX_VIRTUAL_PAGES: u64 = 8192;
+/// The maximum number of physical pages supported by the reference simulator.
+pub const MAX_PHYSICAL_PAGES: u64 = 1024;
+/// The maximum number of sets in the data cache supported by the reference simulator.
+pub const MAX_DC_SETS: u64 = 8192;
+/// The maximum number of sets in the L2 cache supported by the reference simulator.
+/// Deeper levels share the same limit.
+pub const MAX_L2_SETS: u64 = 8192;
+/// The minimum number of bytes in a cache line.
+pub const MIN_LINE_SIZE: u64 = 8;
+/// The number of bits in a virtual or physical address, unless the configuration says otherwise.
+pub const ADDRESS_BITS: u64 = 32;
+/// The largest number of bits in a virtual or physical address.
+pub const MAX_ADDRESS_BITS: u64 = 64;
+
+/// A configuration value that is well-formed, but that the simulator cannot model.
+/// These are found by [`SimulatorConfig::validate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// A size or count must be a power of two, since it is used to slice address bits.
+    NotPowerOfTwo { field: String, value: u64 },
+    /// A value is larger than the simulator supports.
+    TooLarge {
+        field: String,
+        value: u64,
+        max: u64,
+    },
+    /// A value is smaller than the simulator supports.
+    TooSmall {
+        field: String,
+        value: u64,
+        min: u64,
+    },
+    /// A page must hold a whole number of cache lines.
+    PageNotMultipleOfLine {
+        field: String,
+        page_size: u64,
+        line_size: u64,
+    },
+    /// A cache line must be at least as large as the line of the enabled level above it.
+    LineSmallerThanUpperLevel {
+        level: String,
+        line_size: u64,
+        upper_level: String,
+        upper_line_size: u64,
+    },
+    /// The hierarchy must start with an enabled data cache.
+    NoDataCache,
+    /// An address needs more bits than are available.
+    AddressTooWide {
+        field: String,
+        bits: u64,
+        /// The number of bits in the addresses.
+        max: u64,
+    },
+    /// The trace accesses an address past the end of the address space (see [`SimulatorConfig::get_address_limit`]).
+    TraceOutOfRange { address: u64, limit: u64 },
+    /// Only LRU replacement places blocks by their insertion policy.
+    InsertionNeedsLru {
+        level: String,
+        insertion: String,
+        eviction: String,
+    },
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::NotPowerOfTwo { field, value } => {
+                write!(f, "{field} ({value}) is not a power of two")
+            }
+            Self::TooLarge { field, value, max } => {
+                write!(f, "{field} ({value}) exceeds the maximum of {max}")
+            }
+            Self::TooSmall { field, value, min } => {
+                write!(f, "{field} ({value}) is below the minimum of {min}")
+            }
+            Self::PageNotMultipleOfLine {
+                field,
+                page_size,
+                line_size,
+            } => write!(
+                f,
+                "page size ({page_size}) is not a multiple of the {field} ({line_size})"
+            ),
+            Self::LineSmallerThanUpperLevel {
+                level,
+                line_size,
+                upper_level,
+                upper_line_size,
+            } => write!(
+                f,
+                "{level} line size ({line_size}) must be >= to the {upper_level} line size ({upper_line_size})"
+            ),
+            Self::NoDataCache => write!(f, "the data cache must be configured and enabled"),
+            Self::AddressTooWide { field, bits, max } => write!(
+                f,
+                "{field} require {bits} bits, but addresses are only {max} bits"
+            ),
+            Self::TraceOutOfRange { address, limit } => write!(
+                f,
+                "the trace accesses {address:x}, but the address space ends at {limit:x}"
+            ),
+            Self::InsertionNeedsLru {
+                level,
+                insertion,
+                eviction,
+            } => write!(
+                f,
+                "{level} insertion policy {insertion} needs LRU replacement, not {eviction}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// The configuration of the whole memory hierarchy.
+///
+/// In the structured (TOML or JSON) formats, every field is optional:
+/// missing features are enabled, and missing components use their `Default` settings.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SimulatorConfig {
     /// Are virtual addresses enabled?
+    #[serde(default = "enabled")]
     pub virtual_addresses_enabled: bool,
     /// Is the TLB enabled?
+    #[serde(default = "enabled")]
     pub tlb_enabled: bool,
-    /// Is the L2 cache enabled?
-    pub l2_cache_enabled: bool,
+    /// The number of bits in a virtual address.
+    #[serde(default = "default_address_bits", skip_serializing_if = "is_default_address_bits")]
+    pub virtual_address_bits: u64,
+    /// The number of bits in a physical address.
+    #[serde(default = "default_address_bits", skip_serializing_if = "is_default_address_bits")]
+    pub physical_address_bits: u64,
 
     /// The configuration settings for the TLB.
+    #[serde(default)]
     pub tlb: TLBConfig,
+    /// The configuration settings for the instruction TLB. Without one, instruction fetches
+    /// are translated by the data TLB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub itlb: Option<TLBConfig>,
     /// The configuration settings for the page table.
+    #[serde(default)]
     pub page_table: PageTableConfig,
-    /// The configuration settings for the data cache.
-    pub data_cache: DataCacheConfig,
-    /// The configuration settings for the L2 cache.
-    pub l2_cache: L2CacheConfig,
+    /// The configuration settings for each level of the cache hierarchy, starting with
+    /// the data cache and ending with the level closest to main memory.
+    #[serde(default = "default_caches")]
+    pub caches: Vec<CacheConfig>,
+    /// The configuration settings for the instruction cache, which sits beside the data cache
+    /// and is backed by the same second level. Without one (or if it is disabled), instruction
+    /// fetches go through the data cache.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icache: Option<InstructionCacheConfig>,
+}
+
+/// The default for the feature flags in structured configuration files.
+fn enabled() -> bool {
+    true
+}
+
+fn default_address_bits() -> u64 {
+    ADDRESS_BITS
+}
+
+/// Used to leave the address widths out of structured configurations unless they are changed.
+fn is_default_address_bits(bits: &u64) -> bool {
+    *bits == ADDRESS_BITS
+}
+
+/// Read the optional "Replacement policy" line that may end a section of the legacy layout.
+/// If it is missing, the section uses LRU replacement.
+fn get_eviction_policy<R>(buffer: &mut LineReader<R>) -> Result<EvictionPolicy, ConfigError>
+where
+    R: Read,
+{
+    const KEY: &str = "Replacement policy";
+    match get_optional(buffer, KEY)? {
+        Some((line, value)) => value.parse().map_err(|_| ConfigError::BadPolicy {
+            line,
+            key: KEY.to_owned(),
+            value,
+        }),
+        None => Ok(EvictionPolicy::default()),
+    }
+}
+
+/// Read the optional "Insertion policy" line that may follow the replacement policy of a cache
+/// in the legacy layout. If it is missing, the cache uses MRU insertion.
+fn get_insertion_policy<R>(buffer: &mut LineReader<R>) -> Result<InsertionPolicy, ConfigError>
+where
+    R: Read,
+{
+    const KEY: &str = "Insertion policy";
+    match get_optional(buffer, KEY)? {
+        Some((line, value)) => value.parse().map_err(|_| ConfigError::BadPolicy {
+            line,
+            key: KEY.to_owned(),
+            value,
+        }),
+        None => Ok(InsertionPolicy::default()),
+    }
+}
+
+/// Read the optional "Write allocate" line that may follow the write policy of a cache in the
+/// legacy layout. If it is missing, the write-miss policy follows the write-hit policy.
+fn get_write_allocate<R>(buffer: &mut LineReader<R>) -> Result<Option<bool>, ConfigError>
+where
+    R: Read,
+{
+    const KEY: &str = "Write allocate";
+    match get_optional(buffer, KEY)? {
+        Some((line, value)) => match parse_bool(&value) {
+            Some(flag) => Ok(Some(flag)),
+            None => Err(ConfigError::BadBool {
+                line,
+                key: KEY.to_owned(),
+                value,
+            }),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Read one of the optional lines that may end the legacy layout with the number of bits in
+/// virtual or physical addresses. If it is missing, addresses have [`ADDRESS_BITS`] bits.
+fn get_address_bits<R>(buffer: &mut LineReader<R>, key: &str) -> Result<u64, ConfigError>
+where
+    R: Read,
+{
+    match get_optional(buffer, key)? {
+        Some((line, value)) => value.parse().map_err(|_| ConfigError::BadNumber {
+            line,
+            key: key.to_owned(),
+            value,
+        }),
+        None => Ok(ADDRESS_BITS),
+    }
+}
+
+/// Read the optional "ASID tagged" line that may end the section of a TLB in the legacy layout.
+/// If it is missing, the TLB is flushed on every context switch.
+fn get_asid_tagged<R>(buffer: &mut LineReader<R>) -> Result<bool, ConfigError>
+where
+    R: Read,
+{
+    const KEY: &str = "ASID tagged";
+    match get_optional(buffer, KEY)? {
+        Some((line, value)) => parse_bool(&value).ok_or(ConfigError::BadBool {
+            line,
+            key: KEY.to_owned(),
+            value,
+        }),
+        None => Ok(false),
+    }
+}
+
+/// Write the "Write allocate" line of a cache in the legacy layout. It is only needed when the
+/// write-miss policy isn't the one paired with the write-hit policy by "Write through/no write allocate".
+fn legacy_write_allocate(write_through: bool, is_write_allocate: bool) -> String {
+    if is_write_allocate != write_through {
+        String::new()
+    } else {
+        format!("Write allocate: {}\n", if is_write_allocate { "y" } else { "n" })
+    }
+}
+
+/// Write the "Replacement policy" line of a section in the legacy layout.
+/// LRU is the default, so it is left out to stay readable by the reference simulator.
+fn legacy_eviction_policy(policy: EvictionPolicy) -> String {
+    if policy == EvictionPolicy::LRU {
+        String::new()
+    } else {
+        format!("Replacement policy: {policy}\n")
+    }
+}
+
+/// Write the "Insertion policy" line of a cache in the legacy layout, unless it is the default MRU insertion.
+fn legacy_insertion_policy(policy: InsertionPolicy) -> String {
+    if policy == InsertionPolicy::MRU {
+        String::new()
+    } else {
+        format!("Insertion policy: {policy}\n")
+    }
+}
+
+/// Used to leave the insertion policy out of structured configurations unless it is changed.
+fn is_mru_insertion(policy: &InsertionPolicy) -> bool {
+    *policy == InsertionPolicy::MRU
+}
+
+/// Describe the eviction policy of a component in the configuration summary.
+/// LRU is the default, so it is left out to match the reference simulator's summary.
+fn write_eviction_policy(f: &mut Formatter<'_>, policy: EvictionPolicy) -> FmtResult {
+    if policy == EvictionPolicy::LRU {
+        Ok(())
+    } else {
+        writeln!(f, "The replacement policy is {policy}.")
+    }
+}
+
+/// The header of the data TLB's section in the legacy layout.
+const DATA_TLB_HEADER: &str = "Data TLB configuration";
+/// The header of the optional instruction TLB section, which follows the data TLB's.
+const INSTRUCTION_TLB_HEADER: &str = "Instruction TLB configuration";
+/// The header of the optional instruction cache section, which follows the data cache's.
+const INSTRUCTION_CACHE_HEADER: &str = "Instruction Cache configuration";
+/// The key of the line disabling the instruction cache in the legacy layout.
+const INSTRUCTION_CACHE_ENABLE_KEY: &str = "Instruction cache";
+/// The prefix of the override keys for the instruction cache.
+const INSTRUCTION_CACHE_PREFIX: &str = "ic";
+/// The keys of the lines setting the widths of addresses in the legacy layout.
+const VIRTUAL_ADDRESS_BITS_KEY: &str = "Virtual address bits";
+const PHYSICAL_ADDRESS_BITS_KEY: &str = "Physical address bits";
+
+/// Check whether the next line starts the section with the given header,
+/// leaving it to be read again either way.
+fn next_section_is<R>(buffer: &mut LineReader<R>, header: &str) -> Result<bool, ConfigError>
+where
+    R: Read,
+{
+    match buffer.next_line()? {
+        Some(line) => {
+            let found = line == header;
+            buffer.unread_line(line);
+            Ok(found)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Convert a byte offset into a document into a line number (starting at 1).
+fn line_of_offset(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
 }
 
 impl Default for SimulatorConfig {
     fn default() -> Self {
         // Read the configuration from the file "trace.config".
-        Self::from_file("trace.config")
+        Self::from_file("trace.config").unwrap_or_else(|e| panic!("trace.config: {e}"))
     }
 }
 
 impl SimulatorConfig {
     /// Read the configuration from a file, buffer, or other reader.
-    fn from_buffer<R>(buffer: &mut BufReader<R>) -> Self
+    fn from_buffer<R>(buffer: &mut LineReader<R>) -> Result<Self, ConfigError>
     where
         R: Read,
     {
-        // Read the TLB configuration from the file.
-        let tlb = TLBConfig::from_buffer(buffer);
+        // Read the TLB configuration from the file, and the optional instruction TLB after it.
+        let tlb = TLBConfig::from_buffer(buffer, DATA_TLB_HEADER)?;
+        let itlb = match next_section_is(buffer, INSTRUCTION_TLB_HEADER)? {
+            true => Some(TLBConfig::from_buffer(buffer, INSTRUCTION_TLB_HEADER)?),
+            false => None,
+        };
         // Read the page table configuration from the file.
-        let page_table = PageTableConfig::from_buffer(buffer);
-        // Read the data cache configuration from the file.
-        let data_cache = DataCacheConfig::from_buffer(buffer);
-        // Read the L2 cache configuration from the file.
-        let l2_cache = L2CacheConfig::from_buffer(buffer);
+        let page_table = PageTableConfig::from_buffer(buffer)?;
+        // Read the data cache configuration, the optional instruction cache after it,
+        // and then the L2 cache configuration from the file.
+        let data_cache = CacheConfig::from_buffer(buffer, &CacheConfig::section_header(0))?;
+        let mut icache = match next_section_is(buffer, INSTRUCTION_CACHE_HEADER)? {
+            true => Some(CacheConfig::from_buffer(buffer, INSTRUCTION_CACHE_HEADER)?),
+            false => None,
+        };
+        let mut caches = vec![
+            data_cache,
+            CacheConfig::from_buffer(buffer, &CacheConfig::section_header(1))?,
+        ];
+        // Read the configurations of any deeper levels.
+        while next_section_is(buffer, &CacheConfig::section_header(caches.len()))? {
+            caches.push(CacheConfig::from_buffer(buffer, &CacheConfig::section_header(caches.len()))?);
+        }
 
-        // Read the last three lines of the file, which enable certain features of the simulator.
-        let virtual_addresses_enabled = get_bool(buffer, Some("Virtual addresses")).unwrap().1;
-        let tlb_enabled = get_bool(buffer, Some("TLB")).unwrap().1;
-        let l2_cache_enabled = get_bool(buffer, Some("L2 cache")).unwrap().1;
+        // Read the last lines of the file, which enable certain features of the simulator.
+        let virtual_addresses_enabled = get_bool(buffer, Some("Virtual addresses"))?.1;
+        let tlb_enabled = get_bool(buffer, Some("TLB"))?.1;
+        caches[1].enabled = get_bool(buffer, Some("L2 cache"))?.1;
+        // The deeper levels are enabled unless they say otherwise.
+        for (depth, cache) in caches.iter_mut().enumerate().skip(2) {
+            let key = CacheConfig::enable_key(depth);
+            if let Some((line, value)) = get_optional(buffer, &key)? {
+                cache.enabled = parse_bool(&value).ok_or(ConfigError::BadBool {
+                    line,
+                    key,
+                    value,
+                })?;
+            }
+        }
+        // So is the instruction cache.
+        if let Some(icache) = &mut icache {
+            if let Some((line, value)) = get_optional(buffer, INSTRUCTION_CACHE_ENABLE_KEY)? {
+                icache.enabled = parse_bool(&value).ok_or(ConfigError::BadBool {
+                    line,
+                    key: INSTRUCTION_CACHE_ENABLE_KEY.to_owned(),
+                    value,
+                })?;
+            }
+        }
+        // The widths of addresses come last.
+        let virtual_address_bits = get_address_bits(buffer, VIRTUAL_ADDRESS_BITS_KEY)?;
+        let physical_address_bits = get_address_bits(buffer, PHYSICAL_ADDRESS_BITS_KEY)?;
 
-        Self {
+        Ok(Self {
             virtual_addresses_enabled,
             tlb_enabled,
-            l2_cache_enabled,
+            virtual_address_bits,
+            physical_address_bits,
             tlb,
+            itlb,
             page_table,
-            data_cache,
-            l2_cache,
-        }
+            caches,
+            icache,
+        })
+    }
+
+    /// Create a builder for a configuration, starting from the default settings
+    /// of every component with all features enabled.
+    pub fn builder() -> SimulatorConfigBuilder {
+        SimulatorConfigBuilder::new()
+    }
+
+    /// Read the configuration in the legacy `trace.config` layout from any reader.
+    pub fn from_reader<R>(reader: R) -> Result<Self, ConfigError>
+    where
+        R: Read,
+    {
+        Self::from_buffer(&mut LineReader::new(reader))
     }
 
     /// Read the configuration from a file.
-    fn from_file(path: &str) -> Self {
-        let file = std::fs::File::open(path).unwrap();
-        let mut buffer = BufReader::new(file);
-        Self::from_buffer(&mut buffer)
+    /// The format of the file is chosen by its extension (see [`ConfigFormat::from_path`]).
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Io(format!("could not open \"{path}\": {e}")))?;
+        Self::from_str_with_format(&text, ConfigFormat::from_path(path))
+    }
+
+    /// Read the configuration from text in the given format.
+    pub fn from_str_with_format(text: &str, format: ConfigFormat) -> Result<Self, ConfigError> {
+        match format {
+            ConfigFormat::Legacy => Self::from_reader(text.as_bytes()),
+            ConfigFormat::Toml => toml::from_str(text).map_err(|e| ConfigError::Syntax {
+                line: e.span().map_or(0, |span| line_of_offset(text, span.start)),
+                message: e.message().to_owned(),
+            }),
+            ConfigFormat::Json => serde_json::from_str(text).map_err(|e| ConfigError::Syntax {
+                line: e.line(),
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    /// Write the configuration as text in the given format.
+    /// Any format can be read back with [`SimulatorConfig::from_str_with_format`].
+    pub fn to_string_with_format(&self, format: ConfigFormat) -> Result<String, ConfigError> {
+        let unrepresentable = |format: &str, message: String| ConfigError::Unrepresentable {
+            format: format.to_owned(),
+            message,
+        };
+        match format {
+            ConfigFormat::Legacy => Ok(self.to_legacy_string()),
+            ConfigFormat::Toml => toml::to_string(self).map_err(|e| unrepresentable("TOML", e.to_string())),
+            ConfigFormat::Json => {
+                serde_json::to_string_pretty(self).map_err(|e| unrepresentable("JSON", e.to_string()))
+            }
+        }
+    }
+
+    /// Write the configuration in the layout of `trace.config`.
+    /// The layout always has a data cache and an L2 cache section, so a missing L2 is written as a disabled
+    /// copy of the data cache. Reading the text back gives a two-level hierarchy that simulates the same way.
+    fn to_legacy_string(&self) -> String {
+        let yes_no = |flag: bool| if flag { "y" } else { "n" };
+        let mut caches = self.caches.clone();
+        if caches.is_empty() {
+            caches.push(DataCacheConfig::default());
+        }
+        if caches.len() == 1 {
+            caches.push(CacheConfig {
+                enabled: false,
+                ..caches[0].clone()
+            });
+        }
+
+        let mut text = self.tlb.to_legacy_string(DATA_TLB_HEADER);
+        if let Some(itlb) = &self.itlb {
+            text += &itlb.to_legacy_string(INSTRUCTION_TLB_HEADER);
+        }
+        text += &format!(
+            "Page Table configuration\n\
+             Number of virtual pages: {}\n\
+             Number of physical pages: {}\n\
+             Page size: {}\n\n",
+            self.page_table.number_of_virtual_pages,
+            self.page_table.number_of_physical_pages,
+            self.page_table.page_size,
+        );
+        for (depth, cache) in caches.iter().enumerate() {
+            text += &cache.to_legacy_string(&CacheConfig::section_header(depth));
+            if let (0, Some(icache)) = (depth, &self.icache) {
+                text += &icache.to_legacy_string(INSTRUCTION_CACHE_HEADER);
+            }
+        }
+        text += &format!(
+            "Virtual addresses: {}\nTLB: {}\n",
+            yes_no(self.virtual_addresses_enabled),
+            yes_no(self.tlb_enabled),
+        );
+        for (depth, cache) in caches.iter().enumerate().skip(1) {
+            text += &format!("{}: {}\n", CacheConfig::enable_key(depth), yes_no(cache.enabled));
+        }
+        if self.icache.as_ref().is_some_and(|icache| !icache.enabled) {
+            text += &format!("{INSTRUCTION_CACHE_ENABLE_KEY}: n\n");
+        }
+        for (key, bits) in [
+            (VIRTUAL_ADDRESS_BITS_KEY, self.virtual_address_bits),
+            (PHYSICAL_ADDRESS_BITS_KEY, self.physical_address_bits),
+        ] {
+            if bits != ADDRESS_BITS {
+                text += &format!("{key}: {bits}\n");
+            }
+        }
+        text
+    }
+
+    /// The keys accepted by [`SimulatorConfig::set`] that aren't part of a cache level.
+    pub const KEYS: &'static [&'static str] = &[
+        "tlb.sets",
+        "tlb.set_size",
+        "tlb.policy",
+        "tlb.asid_tagged",
+        "pt.virtual_pages",
+        "pt.physical_pages",
+        "pt.page_size",
+        "virtual_addresses",
+        "tlb.enabled",
+        "itlb.sets",
+        "itlb.set_size",
+        "itlb.policy",
+        "itlb.asid_tagged",
+        "itlb.enabled",
+        "virtual_address_bits",
+        "physical_address_bits",
+    ];
+
+    /// The fields of a cache level accepted by [`SimulatorConfig::set`], after the prefix
+    /// naming the level ("dc" for the data cache, then "l2", "l3", ...).
+    pub const LEVEL_KEYS: &'static [&'static str] = &[
+        "sets",
+        "set_size",
+        "line_size",
+        "write_through",
+        "write_allocate",
+        "policy",
+        "insertion",
+        "enabled",
+    ];
+
+    /// Every key accepted by [`SimulatorConfig::set`] for this configuration,
+    /// including the fields of each of its cache levels and of the instruction cache.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = Self::KEYS.iter().map(|key| key.to_string()).collect();
+        let prefixes = (0..self.caches.len())
+            .map(CacheConfig::key_prefix)
+            .chain([INSTRUCTION_CACHE_PREFIX.to_owned()]);
+        for prefix in prefixes {
+            keys.extend(Self::LEVEL_KEYS.iter().map(|field| format!("{prefix}.{field}")));
+        }
+        keys
+    }
+
+    /// Find the depth of the cache level named by a key prefix, such as "dc" or "l3".
+    fn level_of_prefix(prefix: &str) -> Option<usize> {
+        if prefix == "dc" {
+            return Some(0);
+        }
+        match prefix.strip_prefix('l')?.parse::<usize>().ok()? {
+            n if n >= 2 => Some(n - 1),
+            _ => None,
+        }
+    }
+
+    /// Override a single field of the configuration, named by one of [`SimulatorConfig::keys`]
+    /// (such as `dc.sets`), with the value parsed from text.
+    /// Setting a field of the level just past the deepest one adds that level, so `l3.sets=64`
+    /// adds an L3 cache to a hierarchy with an L2. In the same way, setting a field of the
+    /// instruction cache (`ic.*`) or instruction TLB (`itlb.*`) adds it with default settings.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let bad_override = |message: String| ConfigError::BadOverride {
+            assignment: format!("{key}={value}"),
+            message,
+        };
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| bad_override(format!("expected a number, got \"{value}\"")))
+        };
+        let policy = || value.parse::<EvictionPolicy>().map_err(bad_override);
+        let insertion = || value.parse::<InsertionPolicy>().map_err(bad_override);
+        let boolean = || match value {
+            "y" | "Y" | "true" => Ok(true),
+            "n" | "N" | "false" => Ok(false),
+            _ => Err(bad_override(format!(
+                "expected \"y\" or \"n\", got \"{value}\""
+            ))),
+        };
+        let unknown_key = || {
+            bad_override(format!(
+                "unknown key \"{key}\", expected one of: {}",
+                self.keys().join(", ")
+            ))
+        };
+
+        let level = key.split_once('.').and_then(|(prefix, field)| {
+            if prefix == INSTRUCTION_CACHE_PREFIX {
+                return Some((None, field));
+            }
+            Some((Some(Self::level_of_prefix(prefix)?), field))
+        });
+        if let Some((depth, field)) = level {
+            if !Self::LEVEL_KEYS.contains(&field) || depth.is_some_and(|depth| depth > self.caches.len()) {
+                return Err(unknown_key());
+            }
+            let cache = match depth {
+                None => self.icache.get_or_insert_with(InstructionCacheConfig::default),
+                Some(depth) => {
+                    if depth == self.caches.len() {
+                        self.caches.push(CacheConfig::default());
+                    }
+                    &mut self.caches[depth]
+                }
+            };
+            match field {
+                "sets" => cache.number_of_sets = number()?,
+                "set_size" => cache.set_size = number()?,
+                "line_size" => cache.line_size = number()?,
+                "write_through" => cache.write_through = boolean()?,
+                "write_allocate" => cache.write_allocate = Some(boolean()?),
+                "policy" => cache.eviction_policy = policy()?,
+                "insertion" => cache.insertion_policy = insertion()?,
+                _ => cache.enabled = boolean()?,
+            }
+            return Ok(());
+        }
+
+        match key {
+            "tlb.sets" => self.tlb.number_of_sets = number()?,
+            "tlb.set_size" => self.tlb.set_size = number()?,
+            "tlb.policy" => self.tlb.eviction_policy = policy()?,
+            "tlb.asid_tagged" => self.tlb.asid_tagged = boolean()?,
+            "pt.virtual_pages" => self.page_table.number_of_virtual_pages = number()?,
+            "pt.physical_pages" => self.page_table.number_of_physical_pages = number()?,
+            "pt.page_size" => self.page_table.page_size = number()?,
+            "virtual_addresses" => self.virtual_addresses_enabled = boolean()?,
+            "tlb.enabled" => self.tlb_enabled = boolean()?,
+            "virtual_address_bits" => self.virtual_address_bits = number()?,
+            "physical_address_bits" => self.physical_address_bits = number()?,
+            "itlb.sets" => self.itlb.get_or_insert_with(TLBConfig::default).number_of_sets = number()?,
+            "itlb.set_size" => self.itlb.get_or_insert_with(TLBConfig::default).set_size = number()?,
+            "itlb.policy" => self.itlb.get_or_insert_with(TLBConfig::default).eviction_policy = policy()?,
+            "itlb.asid_tagged" => self.itlb.get_or_insert_with(TLBConfig::default).asid_tagged = boolean()?,
+            "itlb.enabled" => match boolean()? {
+                true => {
+                    self.itlb.get_or_insert_with(TLBConfig::default);
+                }
+                false => self.itlb = None,
+            },
+            _ => return Err(unknown_key()),
+        }
+        Ok(())
+    }
+
+    /// Apply an override written as `key=value`, such as `dc.sets=4`.
+    pub fn apply_override(&mut self, assignment: &str) -> Result<(), ConfigError> {
+        match assignment.split_once('=') {
+            Some((key, value)) => self.set(key.trim(), value.trim()),
+            None => Err(ConfigError::BadOverride {
+                assignment: assignment.to_owned(),
+                message: "expected \"key=value\"".to_owned(),
+            }),
+        }
     }
 
     /// Get the size of a page in bytes.
@@ -93,19 +844,50 @@ impl SimulatorConfig {
     }
 
     pub fn get_data_cache_index_bits(&self) -> u64 {
-        self.data_cache.get_index_bits()
+        self.data_cache().get_index_bits()
     }
 
     pub fn get_data_cache_offset_bits(&self) -> u64 {
-        self.data_cache.get_offset_bits()
+        self.data_cache().get_offset_bits()
     }
 
-    pub fn get_l2_cache_index_bits(&self) -> u64 {
-        self.l2_cache.get_index_bits()
+    /// Get the configuration of the data cache, the first level of the hierarchy.
+    pub fn data_cache(&self) -> &DataCacheConfig {
+        &self.caches[0]
     }
 
-    pub fn get_l2_cache_offset_bits(&self) -> u64 {
-        self.l2_cache.get_offset_bits()
+    /// Get the configuration of the L2 cache, if the hierarchy has a second level.
+    pub fn l2_cache(&self) -> Option<&L2CacheConfig> {
+        self.caches.get(1)
+    }
+
+    /// Get the configuration of the instruction cache, if there is one and it is enabled.
+    pub fn instruction_cache(&self) -> Option<&InstructionCacheConfig> {
+        self.icache.as_ref().filter(|icache| icache.enabled)
+    }
+
+    /// Get the configuration of the instruction TLB, if there is one and the TLBs are enabled.
+    pub fn instruction_tlb(&self) -> Option<&TLBConfig> {
+        self.itlb.as_ref().filter(|_| self.tlb_enabled)
+    }
+
+    /// Get the configurations of the enabled cache levels, along with their depth in the hierarchy.
+    pub fn enabled_caches(&self) -> impl Iterator<Item = (usize, &CacheConfig)> {
+        self.caches.iter().enumerate().filter(|(_, cache)| cache.enabled)
+    }
+
+    /// Does an enabled TLB or cache use Belady's optimal replacement, which needs the whole trace up front?
+    pub fn uses_optimal(&self) -> bool {
+        let tlbs = [Some(&self.tlb).filter(|_| self.tlb_enabled), self.instruction_tlb()]
+            .into_iter()
+            .flatten()
+            .map(|tlb| tlb.eviction_policy);
+        let caches = self
+            .enabled_caches()
+            .map(|(_, cache)| cache)
+            .chain(self.instruction_cache())
+            .map(|cache| cache.eviction_policy);
+        tlbs.chain(caches).any(|policy| policy == EvictionPolicy::Optimal)
     }
 
     pub fn is_tlb_enabled(&self) -> bool {
@@ -113,21 +895,262 @@ impl SimulatorConfig {
     }
 
     pub fn is_l2_cache_enabled(&self) -> bool {
-        self.l2_cache_enabled
+        self.l2_cache().is_some_and(|cache| cache.enabled)
     }
 
     pub fn is_virtual_addresses_enabled(&self) -> bool {
         self.virtual_addresses_enabled
     }
+
+    /// Get the number of bits in a virtual address.
+    pub fn get_virtual_address_bits(&self) -> u64 {
+        self.virtual_address_bits
+    }
+
+    /// Get the number of bits in a physical address.
+    pub fn get_physical_address_bits(&self) -> u64 {
+        self.physical_address_bits
+    }
+
+    /// Get the number of bits in the addresses of a trace, which are virtual if virtual addresses are enabled.
+    pub fn get_trace_address_bits(&self) -> u64 {
+        if self.virtual_addresses_enabled {
+            self.virtual_address_bits
+        } else {
+            self.physical_address_bits
+        }
+    }
+
+    /// Get the first address past the ones a trace can access, or None if it can access any 64-bit address.
+    /// With virtual addresses, this is the end of the space mapped by the page table.
+    pub fn get_address_limit(&self) -> Option<u64> {
+        if self.virtual_addresses_enabled {
+            self.page_table
+                .number_of_virtual_pages
+                .checked_mul(self.page_table.page_size)
+        } else {
+            1u64.checked_shl(self.physical_address_bits as u32)
+        }
+    }
+
+    /// Check that the configuration describes a hierarchy the simulator can model.
+    /// Every violation is returned, not just the first one found.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = vec![];
+
+        let mut power_of_two = |field: String, value: u64| {
+            if !value.is_power_of_two() {
+                errors.push(ValidationError::NotPowerOfTwo { field, value });
+            }
+        };
+        power_of_two("number of dtlb sets".into(), self.tlb.number_of_sets);
+        if let Some(itlb) = &self.itlb {
+            power_of_two("number of itlb sets".into(), itlb.number_of_sets);
+        }
+        power_of_two("number of virtual pages".into(), self.page_table.number_of_virtual_pages);
+        power_of_two("number of physical pages".into(), self.page_table.number_of_physical_pages);
+        power_of_two("page size".into(), self.page_table.page_size);
+        for (depth, cache) in self.caches.iter().enumerate() {
+            let name = CacheConfig::stats_name(depth);
+            power_of_two(format!("number of {name} sets"), cache.number_of_sets);
+            power_of_two(format!("number of bytes in {name} line"), cache.line_size);
+        }
+        if let Some(icache) = &self.icache {
+            power_of_two("number of ic sets".into(), icache.number_of_sets);
+            power_of_two("number of bytes in ic line".into(), icache.line_size);
+        }
+
+        let mut at_most = |field: String, value: u64, max: u64| {
+            if value > max {
+                errors.push(ValidationError::TooLarge { field, value, max });
+            }
+        };
+        at_most("number of dtlb sets".into(), self.tlb.number_of_sets, MAX_TLB_SETS);
+        at_most("dtlb set size".into(), self.tlb.set_size, MAX_SET_SIZE);
+        if let Some(itlb) = &self.itlb {
+            at_most("number of itlb sets".into(), itlb.number_of_sets, MAX_TLB_SETS);
+            at_most("itlb set size".into(), itlb.set_size, MAX_SET_SIZE);
+        }
+        at_most("virtual address bits".into(), self.virtual_address_bits, MAX_ADDRESS_BITS);
+        at_most("physical address bits".into(), self.physical_address_bits, MAX_ADDRESS_BITS);
+        at_most(
+            "number of virtual pages".into(),
+            self.page_table.number_of_virtual_pages,
+            MAX_VIRTUAL_PAGES,
+        );
+        at_most(
+            "number of physical pages".into(),
+            self.page_table.number_of_physical_pages,
+            MAX_PHYSICAL_PAGES,
+        );
+        for (depth, cache) in self.caches.iter().enumerate() {
+            let name = CacheConfig::stats_name(depth);
+            let max_sets = if depth == 0 { MAX_DC_SETS } else { MAX_L2_SETS };
+            at_most(format!("number of {name} sets"), cache.number_of_sets, max_sets);
+            at_most(format!("{name} set size"), cache.set_size, MAX_SET_SIZE);
+        }
+        if let Some(icache) = &self.icache {
+            at_most("number of ic sets".into(), icache.number_of_sets, MAX_DC_SETS);
+            at_most("ic set size".into(), icache.set_size, MAX_SET_SIZE);
+        }
+
+        let mut at_least = |field: String, value: u64, min: u64| {
+            if value < min {
+                errors.push(ValidationError::TooSmall { field, value, min });
+            }
+        };
+        at_least("dtlb set size".into(), self.tlb.set_size, 1);
+        if let Some(itlb) = &self.itlb {
+            at_least("itlb set size".into(), itlb.set_size, 1);
+        }
+        // The instruction cache is checked like the levels, under the name "ic".
+        let named_caches: Vec<(String, &CacheConfig)> = self
+            .caches
+            .iter()
+            .enumerate()
+            .map(|(depth, cache)| (CacheConfig::stats_name(depth), cache))
+            .chain(self.icache.iter().map(|icache| ("ic".to_owned(), icache)))
+            .collect();
+        for (name, cache) in &named_caches {
+            at_least(format!("{name} set size"), cache.set_size, 1);
+            at_least(format!("number of bytes in {name} line"), cache.line_size, MIN_LINE_SIZE);
+        }
+
+        // A tree pseudo-LRU policy splits the ways of a set in halves.
+        let tlbs = std::iter::once(("dtlb", &self.tlb)).chain(self.itlb.iter().map(|itlb| ("itlb", itlb)));
+        let policies = tlbs
+            .map(|(name, tlb)| (name.to_owned(), tlb.set_size, tlb.eviction_policy))
+            .chain(named_caches.iter().map(|(name, cache)| (name.clone(), cache.set_size, cache.eviction_policy)));
+        for (name, set_size, policy) in policies {
+            if policy == EvictionPolicy::TreePLRU && set_size > 0 && !set_size.is_power_of_two() {
+                errors.push(ValidationError::NotPowerOfTwo {
+                    field: format!("{name} set size for {policy}"),
+                    value: set_size,
+                });
+            }
+        }
+
+        for (name, cache) in &named_caches {
+            if cache.insertion_policy != InsertionPolicy::MRU && cache.eviction_policy != EvictionPolicy::LRU {
+                errors.push(ValidationError::InsertionNeedsLru {
+                    level: name.clone(),
+                    insertion: cache.insertion_policy.to_string(),
+                    eviction: cache.eviction_policy.to_string(),
+                });
+            }
+        }
+
+        let page_size = self.page_table.page_size;
+        for (name, cache) in &named_caches {
+            let line_size = cache.line_size;
+            if line_size == 0 || !page_size.is_multiple_of(line_size) {
+                errors.push(ValidationError::PageNotMultipleOfLine {
+                    field: format!("{name} line size"),
+                    page_size,
+                    line_size,
+                });
+            }
+        }
+
+        if self.caches.first().is_none_or(|cache| !cache.enabled) {
+            errors.push(ValidationError::NoDataCache);
+        }
+
+        // Each level fetches whole lines for the level above it, so its lines can't be smaller.
+        let enabled: Vec<_> = self.enabled_caches().collect();
+        for pair in enabled.windows(2) {
+            let ((upper_depth, upper), (lower_depth, lower)) = (pair[0], pair[1]);
+            if lower.line_size < upper.line_size {
+                errors.push(ValidationError::LineSmallerThanUpperLevel {
+                    level: CacheConfig::stats_name(lower_depth),
+                    line_size: lower.line_size,
+                    upper_level: CacheConfig::stats_name(upper_depth),
+                    upper_line_size: upper.line_size,
+                });
+            }
+        }
+        // The instruction cache is backed by the same level as the data cache.
+        if let (Some(icache), Some(&(lower_depth, lower))) = (self.instruction_cache(), enabled.get(1)) {
+            if lower.line_size < icache.line_size {
+                errors.push(ValidationError::LineSmallerThanUpperLevel {
+                    level: CacheConfig::stats_name(lower_depth),
+                    line_size: lower.line_size,
+                    upper_level: "ic".to_owned(),
+                    upper_line_size: icache.line_size,
+                });
+            }
+        }
+
+        // The page table, and the index and offset of every cache and TLB, must fit in the addresses they split.
+        let offset_bits = self.page_table.get_offset_bits();
+        let mut fields = vec![
+            (
+                "virtual addresses".to_owned(),
+                self.page_table.number_of_virtual_pages.trailing_zeros() as u64 + offset_bits,
+                self.virtual_address_bits,
+            ),
+            (
+                "physical addresses".to_owned(),
+                self.page_table.number_of_physical_pages.trailing_zeros() as u64 + offset_bits,
+                self.physical_address_bits,
+            ),
+        ];
+        for (name, tlb) in std::iter::once(("dtlb", &self.tlb)).chain(self.itlb.iter().map(|itlb| ("itlb", itlb))) {
+            fields.push((
+                format!("{name} sets and pages"),
+                tlb.get_index_bits() + offset_bits,
+                self.virtual_address_bits,
+            ));
+        }
+        for (name, cache) in &named_caches {
+            fields.push((
+                format!("{name} sets and lines"),
+                cache.get_index_bits() + cache.get_offset_bits(),
+                self.physical_address_bits,
+            ));
+        }
+        for (field, bits, max) in fields {
+            if bits > max {
+                errors.push(ValidationError::AddressTooWide { field, bits, max });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl FromStr for SimulatorConfig {
+    type Err = ConfigError;
+
+    /// Read the configuration in the legacy `trace.config` layout.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_reader(s.as_bytes())
+    }
 }
 
 impl Display for SimulatorConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(
-            f,
-            "{}\n{}\n{}\n{}",
-            self.tlb, self.page_table, self.data_cache, self.l2_cache
-        )?;
+        self.tlb.write_summary(f, "Data")?;
+        if let Some(itlb) = &self.itlb {
+            writeln!(f)?;
+            itlb.write_summary(f, "Instruction")?;
+        }
+        write!(f, "\n{}\n", self.page_table)?;
+        for (depth, cache) in self.caches.iter().enumerate() {
+            let name = if depth == 0 {
+                "D".to_owned()
+            } else {
+                CacheConfig::short_name(depth)
+            };
+            cache.write_summary(f, &name)?;
+            if let (0, Some(icache)) = (depth, &self.icache) {
+                icache.write_summary(f, "I")?;
+            }
+        }
 
         writeln!(
             f,
@@ -139,22 +1162,48 @@ impl Display for SimulatorConfig {
             }
         )?;
 
+        if (self.virtual_address_bits, self.physical_address_bits) != (ADDRESS_BITS, ADDRESS_BITS) {
+            writeln!(
+                f,
+                "Virtual addresses are {} bits, and physical addresses are {} bits.",
+                self.virtual_address_bits, self.physical_address_bits
+            )?;
+        }
+
         if !self.tlb_enabled {
             writeln!(f, "TLB is disabled in this configuration.")?;
         }
 
-        if !self.l2_cache_enabled {
-            writeln!(f, "L2 cache is disabled in this configuration.")?;
+        for (depth, cache) in self.caches.iter().enumerate().skip(1) {
+            if !cache.enabled {
+                writeln!(f, "{} is disabled in this configuration.", CacheConfig::enable_key(depth))?;
+            }
+        }
+        if self.icache.as_ref().is_some_and(|icache| !icache.enabled) {
+            writeln!(f, "{INSTRUCTION_CACHE_ENABLE_KEY} is disabled in this configuration.")?;
         }
 
         Ok(())
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct TLBConfig {
     pub number_of_sets: u64,
     pub set_size: u64,
+    /// The policy used to choose which entry to evict from a full set.
+    pub eviction_policy: EvictionPolicy,
+    /// Are the entries tagged with the address space they belong to? If not, the TLB is
+    /// flushed on every context switch.
+    #[serde(skip_serializing_if = "is_disabled")]
+    pub asid_tagged: bool,
+}
+
+impl Default for TLBConfig {
+    fn default() -> Self {
+        Self::new(2, 1)
+    }
 }
 
 impl TLBConfig {
@@ -162,12 +1211,14 @@ impl TLBConfig {
         Self {
             number_of_sets,
             set_size,
+            eviction_policy: EvictionPolicy::LRU,
+            asid_tagged: false,
         }
     }
 
     /// Get the eviction policy for the TLB cache.
     pub fn get_eviction_policy(&self) -> EvictionPolicy {
-        EvictionPolicy::LRU
+        self.eviction_policy
     }
 
     /// Returns the number of bits used for the TLB index.
@@ -192,26 +1243,61 @@ impl TLBConfig {
         self.number_of_sets
     }
 
-    /// Read the configuration from a file, buffer, or other reader.
-    fn from_buffer<R>(buffer: &mut BufReader<R>) -> Self
+    /// Read the configuration of the TLB whose section starts with `header`
+    /// from a file, buffer, or other reader.
+    fn from_buffer<R>(buffer: &mut LineReader<R>, header: &str) -> Result<Self, ConfigError>
     where
         R: Read,
     {
-        get_header(buffer, "Data TLB configuration");
-        let number_of_sets = get_decimal(buffer, Some("Number of sets")).unwrap().1;
-        let set_size = get_decimal(buffer, Some("Set size")).unwrap().1;
-        Self::new(number_of_sets, set_size)
+        get_header(buffer, header)?;
+        let number_of_sets = get_decimal(buffer, Some("Number of sets"))?.1;
+        let set_size = get_decimal(buffer, Some("Set size"))?.1;
+        let eviction_policy = get_eviction_policy(buffer)?;
+        let asid_tagged = get_asid_tagged(buffer)?;
+        Ok(Self {
+            eviction_policy,
+            asid_tagged,
+            ..Self::new(number_of_sets, set_size)
+        })
+    }
+}
+
+impl TLBConfig {
+    /// Write the section of the TLB, starting with `header`, in the layout of `trace.config`.
+    fn to_legacy_string(&self, header: &str) -> String {
+        format!(
+            "{header}\n\
+             Number of sets: {}\n\
+             Set size: {}\n\
+             {}{}\n",
+            self.number_of_sets,
+            self.set_size,
+            legacy_eviction_policy(self.eviction_policy),
+            if self.asid_tagged { "ASID tagged: y\n" } else { "" },
+        )
+    }
+
+    /// Describe the TLB in the configuration summary, as the "Data" or "Instruction" TLB.
+    fn write_summary(&self, f: &mut Formatter<'_>, kind: &str) -> FmtResult {
+        writeln!(f, "{kind} TLB contains {} sets.\nEach set contains {} entries.\nNumber of bits used for the index is {}.", self.number_of_sets, self.set_size, self.get_index_bits())?;
+        write_eviction_policy(f, self.eviction_policy)?;
+        // Flushing on a context switch is the default, so it is left out to match the reference simulator's summary.
+        if self.asid_tagged {
+            writeln!(f, "The {} TLB entries are tagged with address space IDs.", kind.to_lowercase())?;
+        }
+        Ok(())
     }
 }
 
 impl Display for TLBConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        writeln!(f, "Data TLB contains {} sets.\nEach set contains {} entries.\nNumber of bits used for the index is {}.", self.number_of_sets, self.set_size, self.get_index_bits())
+        self.write_summary(f, "Data")
     }
 }
 
 /// Configuration for the page table.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PageTableConfig {
     /// Number of virtual pages in the page table.
     pub number_of_virtual_pages: u64,
@@ -221,6 +1307,12 @@ pub struct PageTableConfig {
     pub page_size: u64,
 }
 
+impl Default for PageTableConfig {
+    fn default() -> Self {
+        Self::new(8192, 1024, 524288)
+    }
+}
+
 impl PageTableConfig {
     pub fn new(
         number_of_virtual_pages: u64,
@@ -258,19 +1350,19 @@ impl PageTableConfig {
     }
 
     /// Read the configuration from a file, buffer, or other reader.
-    fn from_buffer<R>(buffer: &mut BufReader<R>) -> Self
+    fn from_buffer<R>(buffer: &mut LineReader<R>) -> Result<Self, ConfigError>
     where
         R: Read,
     {
-        get_header(buffer, "Page Table configuration");
-        let number_of_virtual_pages = get_decimal(buffer, Some("Number of virtual pages"))
-            .unwrap()
-            .1;
-        let number_of_physical_pages = get_decimal(buffer, Some("Number of physical pages"))
-            .unwrap()
-            .1;
-        let page_size = get_decimal(buffer, Some("Page size")).unwrap().1;
-        Self::new(number_of_virtual_pages, number_of_physical_pages, page_size)
+        get_header(buffer, "Page Table configuration")?;
+        let number_of_virtual_pages = get_decimal(buffer, Some("Number of virtual pages"))?.1;
+        let number_of_physical_pages = get_decimal(buffer, Some("Number of physical pages"))?.1;
+        let page_size = get_decimal(buffer, Some("Page size"))?.1;
+        Ok(Self::new(
+            number_of_virtual_pages,
+            number_of_physical_pages,
+            page_size,
+        ))
     }
 }
 
@@ -280,29 +1372,118 @@ impl Display for PageTableConfig {
     }
 }
 
-/// Configuration for the data cache.
-#[derive(Clone, Debug)]
-pub struct DataCacheConfig {
+/// Configuration for one level of the cache hierarchy.
+/// The first level is the data cache, and each level after it is backed by the one that follows.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Is this level enabled? A disabled level is skipped, and the levels around it talk directly.
+    /// The data cache can't be disabled.
+    #[serde(skip_serializing_if = "is_enabled")]
+    pub enabled: bool,
     /// Number of sets in the cache.
     pub number_of_sets: u64,
     /// Number of entries in each set.
     pub set_size: u64,
     /// Number of bytes in each cache line.
     pub line_size: u64,
-    /// Is the cache write-through?
+    /// Is the cache write-through (rather than write-back) on a write hit?
     pub write_through: bool,
+    /// Does the cache allocate a line on a write miss? If this isn't set, it is paired with
+    /// the write-hit policy: write-through caches are no-write-allocate, and write-back caches are write-allocate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_allocate: Option<bool>,
+    /// The policy used to choose which line to evict from a full set.
+    /// Optimal replacement below the first level is only close to optimal (see [`crate::Simulator::prepare_optimal`]).
+    pub eviction_policy: EvictionPolicy,
+    /// Where a set places the lines it loads in its recency order, with LRU replacement.
+    #[serde(skip_serializing_if = "is_mru_insertion")]
+    pub insertion_policy: InsertionPolicy,
+}
+
+/// The configuration of the data cache, the first level of the hierarchy.
+pub type DataCacheConfig = CacheConfig;
+/// The configuration of the L2 cache, the second level of the hierarchy.
+pub type L2CacheConfig = CacheConfig;
+/// The configuration of the instruction cache, beside the data cache at the first level.
+pub type InstructionCacheConfig = CacheConfig;
+
+/// Used to leave the `enabled` flag out of structured configurations unless a level is disabled.
+fn is_enabled(enabled: &bool) -> bool {
+    *enabled
+}
+
+fn is_disabled(enabled: &bool) -> bool {
+    !*enabled
+}
+
+/// The cache levels used when a structured configuration doesn't list any:
+/// a data cache and an L2 cache, as in the legacy layout.
+fn default_caches() -> Vec<CacheConfig> {
+    vec![
+        CacheConfig::new(2, 2, 16, true),
+        CacheConfig::new(16, 4, 16, true),
+    ]
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self::new(2, 2, 16, true)
+    }
 }
 
-impl DataCacheConfig {
+impl CacheConfig {
     pub fn new(number_of_sets: u64, set_size: u64, line_size: u64, write_through: bool) -> Self {
         Self {
+            enabled: true,
             number_of_sets,
             set_size,
             line_size,
             write_through,
+            write_allocate: None,
+            eviction_policy: EvictionPolicy::LRU,
+            insertion_policy: InsertionPolicy::MRU,
+        }
+    }
+
+    /// The short name of the level at `depth` (starting at 0), used in the output columns:
+    /// "DC" for the data cache, and "L2", "L3", ... for the levels below it.
+    pub fn short_name(depth: usize) -> String {
+        if depth == 0 {
+            "DC".to_owned()
+        } else {
+            format!("L{}", depth + 1)
+        }
+    }
+
+    /// The name of the level at `depth` in the statistics, such as "dc" or "L3".
+    pub fn stats_name(depth: usize) -> String {
+        if depth == 0 {
+            "dc".to_owned()
+        } else {
+            Self::short_name(depth)
+        }
+    }
+
+    /// The prefix of the override keys for the level at `depth`, such as "dc" or "l3".
+    pub fn key_prefix(depth: usize) -> String {
+        Self::short_name(depth).to_lowercase()
+    }
+
+    /// The header of the level's section in the legacy layout.
+    fn section_header(depth: usize) -> String {
+        if depth == 0 {
+            "Data Cache configuration".to_owned()
+        } else {
+            format!("L{} Cache configuration", depth + 1)
         }
     }
 
+    /// The key of the line enabling the level in the legacy layout, such as "L2 cache".
+    fn enable_key(depth: usize) -> String {
+        format!("L{} cache", depth + 1)
+    }
+
     /// Returns the number of bits used for the cache set index.
     /// The cache set index is the number of bits used to address a cache set.
     pub fn get_index_bits(&self) -> u64 {
@@ -317,33 +1498,31 @@ impl DataCacheConfig {
 
     /// Is the cache write-through?
     /// Write through means that the data is written to both the cache and the main memory.
-    /// A write-through cache uses a no-write-allocate policy.
     pub fn is_write_through(&self) -> bool {
         self.write_through
     }
 
     /// Is the cache no-write-allocate?
     /// No-write-allocate means that the cache line is not loaded into the cache when a write miss occurs.
-    /// A write-through cache uses a no-write-allocate policy.
+    /// Unless configured otherwise, a write-through cache uses a no-write-allocate policy.
     pub fn is_no_write_allocate(&self) -> bool {
-        self.write_through
+        !self.is_write_allocate()
     }
 
     /// Is the cache write-back?
     /// Write back means that the data is written to the cache and the main memory is updated when the cache line is evicted.
-    /// A write-back cache uses a write-allocate policy.
     pub fn is_write_back(&self) -> bool {
         !self.write_through
     }
 
     /// Is the cache write-allocate?
     /// Write allocate means that the cache line is loaded into the cache when a write miss occurs.
-    /// A write-back cache uses a write-allocate policy.
+    /// Unless configured otherwise, a write-back cache uses a write-allocate policy.
     pub fn is_write_allocate(&self) -> bool {
-        !self.write_through
+        self.write_allocate.unwrap_or(!self.write_through)
     }
 
-    /// Get the associativity of the data cache from the configuration.
+    /// Get the associativity of the cache from the configuration.
     pub fn get_associativity(&self) -> u64 {
         // The associativity is the number of blocks in each set
         self.set_size
@@ -354,148 +1533,289 @@ impl DataCacheConfig {
         self.line_size
     }
 
-    /// Get the eviction policy for the data cache.
+    /// Get the eviction policy for the cache.
     pub fn get_eviction_policy(&self) -> EvictionPolicy {
-        EvictionPolicy::LRU
+        self.eviction_policy
+    }
+
+    /// Get the insertion policy for the cache.
+    pub fn get_insertion_policy(&self) -> InsertionPolicy {
+        self.insertion_policy
     }
 
-    /// Get the number of sets in the data cache.
+    /// Get the number of sets in the cache.
     pub fn get_number_of_sets(&self) -> u64 {
         self.number_of_sets
     }
 
-    /// Read the configuration from a file, buffer, or other reader.
-    fn from_buffer<R>(buffer: &mut BufReader<R>) -> Self
+    /// Read the configuration of the cache whose section starts with `header`
+    /// from a file, buffer, or other reader.
+    fn from_buffer<R>(buffer: &mut LineReader<R>, header: &str) -> Result<Self, ConfigError>
     where
         R: Read,
     {
-        get_header(buffer, "Data Cache configuration");
-        let number_of_sets = get_decimal(buffer, Some("Number of sets")).unwrap().1;
-        let set_size = get_decimal(buffer, Some("Set size")).unwrap().1;
-        let line_size = get_decimal(buffer, Some("Line size")).unwrap().1;
-        let write_through = get_bool(buffer, Some("Write through/no write allocate"))
-            .unwrap()
-            .1;
-        Self::new(number_of_sets, set_size, line_size, write_through)
+        get_header(buffer, header)?;
+        let number_of_sets = get_decimal(buffer, Some("Number of sets"))?.1;
+        let set_size = get_decimal(buffer, Some("Set size"))?.1;
+        let line_size = get_decimal(buffer, Some("Line size"))?.1;
+        let write_through = get_bool(buffer, Some("Write through/no write allocate"))?.1;
+        let write_allocate = get_write_allocate(buffer)?;
+        let eviction_policy = get_eviction_policy(buffer)?;
+        let insertion_policy = get_insertion_policy(buffer)?;
+        Ok(Self {
+            write_allocate,
+            eviction_policy,
+            insertion_policy,
+            ..Self::new(number_of_sets, set_size, line_size, write_through)
+        })
     }
-}
 
-impl Display for DataCacheConfig {
-    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        let is_write_through = self.is_write_through();
-        let is_write_allocate = self.is_write_allocate();
-
-        let allocate_policy = if is_write_allocate { "" } else { "no " };
-        let write_policy = if is_write_through { "through" } else { "back" };
+    /// Write the section of the cache, starting with `header`, in the layout of `trace.config`.
+    fn to_legacy_string(&self, header: &str) -> String {
+        format!(
+            "{header}\n\
+             Number of sets: {}\n\
+             Set size: {}\n\
+             Line size: {}\n\
+             Write through/no write allocate: {}\n\
+             {}{}{}\n",
+            self.number_of_sets,
+            self.set_size,
+            self.line_size,
+            if self.write_through { "y" } else { "n" },
+            legacy_write_allocate(self.write_through, self.is_write_allocate()),
+            legacy_eviction_policy(self.eviction_policy),
+            legacy_insertion_policy(self.insertion_policy),
+        )
+    }
 
-        writeln!(f, "D-cache contains {} sets.\nEach set contains {} entries.\nEach line is {} bytes.\nThe cache uses a {}write-allocate and write-{} policy.\nNumber of bits used for the index is {}.\nNumber of bits used for the offset is {}.", self.number_of_sets, self.set_size, self.line_size, allocate_policy, write_policy, self.get_index_bits(), self.get_offset_bits())
+    /// Describe the cache in the configuration summary, as the "D", "I", "L2", ... cache.
+    fn write_summary(&self, f: &mut Formatter<'_>, name: &str) -> FmtResult {
+        let allocate_policy = if self.is_write_allocate() { "" } else { "no " };
+        let write_policy = if self.write_through { "through" } else { "back" };
 
-        // writeln!(f, "D-cache contains {} sets.\nEach set contains {} entries.\nEach line is {} bytes.\nThe cache uses a {}write-allocate and write-{} policy.\nNumber of bits used for the index is {}.\nNumber of bits used for the offset is {}.", self.number_of_sets, self.set_size, self.line_size, if self.write_through { "no " } else { "" }, if self.write_through { "through" } else {"back"}, self.get_index_bits(), self.get_offset_bits())
+        writeln!(f, "{}-cache contains {} sets.\nEach set contains {} entries.\nEach line is {} bytes.\nThe cache uses a {}write-allocate and write-{} policy.\nNumber of bits used for the index is {}.\nNumber of bits used for the offset is {}.", name, self.number_of_sets, self.set_size, self.line_size, allocate_policy, write_policy, self.get_index_bits(), self.get_offset_bits())?;
+        write_eviction_policy(f, self.eviction_policy)?;
+        if self.eviction_policy == EvictionPolicy::Optimal && !matches!(name, "D" | "I") {
+            writeln!(f, "It plans with the trace's accesses to its lines, not the requests of the level above, so it is close to optimal, but not exact.")?;
+        }
+        if self.insertion_policy != InsertionPolicy::MRU {
+            writeln!(f, "The insertion policy is {}.", self.insertion_policy)?;
+        }
+        writeln!(f)
     }
 }
 
-/// Configuration for the L2 cache.
-#[derive(Clone, Debug)]
-pub struct L2CacheConfig {
-    /// Number of sets in the L2 cache.
-    pub number_of_sets: u64,
-    /// Number of entries in each set.
-    pub set_size: u64,
-    /// Number of bytes in each cache line.
-    pub line_size: u64,
-    /// Is the cache write-through?
-    pub write_through: bool,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl L2CacheConfig {
-    pub fn new(number_of_sets: u64, set_size: u64, line_size: u64, write_through: bool) -> Self {
-        Self {
-            number_of_sets,
-            set_size,
-            line_size,
-            write_through,
-        }
+    const TRACE_CONFIG: &str = include_str!("../trace.config");
+
+    /// Read a legacy configuration, with one of the lines of `trace.config` replaced.
+    fn with_line(number: usize, line: &str) -> Result<SimulatorConfig, ConfigError> {
+        let mut lines: Vec<&str> = TRACE_CONFIG.lines().collect();
+        lines[number - 1] = line;
+        lines.join("\n").parse()
     }
 
-    /// Returns the number of bits used for the cache set index.
-    /// The cache set index is the number of bits used to address a cache set.
-    pub fn get_index_bits(&self) -> u64 {
-        self.number_of_sets.trailing_zeros() as u64
+    #[test]
+    fn a_truncated_file_reports_the_last_line_read() {
+        let truncated: String = TRACE_CONFIG.lines().take(11).map(|line| format!("{line}\n")).collect();
+        assert_eq!(
+            truncated.parse::<SimulatorConfig>().unwrap_err(),
+            ConfigError::UnexpectedEof {
+                line: 11,
+                expected: "Set size".to_owned(),
+            }
+        );
     }
 
-    /// Returns the number of bits used for the cache line offset.
-    /// The cache line offset is the number of bits used to address a byte within a cache line.
-    pub fn get_offset_bits(&self) -> u64 {
-        self.line_size.trailing_zeros() as u64
+    #[test]
+    fn a_garbled_line_is_reported_by_its_number() {
+        assert_eq!(
+            with_line(12, "Set size: two").unwrap_err(),
+            ConfigError::BadNumber {
+                line: 12,
+                key: "Set size".to_owned(),
+                value: "two".to_owned(),
+            }
+        );
+        assert_eq!(
+            with_line(14, "Write through: y").unwrap_err(),
+            ConfigError::UnknownKey {
+                line: 14,
+                expected: "Write through/no write allocate".to_owned(),
+                found: "Write through: y".to_owned(),
+            }
+        );
+        assert_eq!(
+            with_line(16, "L3 Cache configuration").unwrap_err(),
+            ConfigError::MissingSection {
+                line: 16,
+                expected: "L2 Cache configuration".to_owned(),
+                found: "L3 Cache configuration".to_owned(),
+            }
+        );
+        assert_eq!(
+            with_line(23, "TLB: maybe").unwrap_err(),
+            ConfigError::BadBool {
+                line: 23,
+                key: "TLB".to_owned(),
+                value: "maybe".to_owned(),
+            }
+        );
     }
 
-    /// Is the cache write-through?
-    /// Write through means that the data is written to both the cache and the main memory.
-    /// A write-through cache uses a no-write-allocate policy.
-    pub fn is_write_through(&self) -> bool {
-        self.write_through
+    #[test]
+    fn validation_reports_every_violation() {
+        let mut config: SimulatorConfig = TRACE_CONFIG.parse().unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        config.tlb.set_size = 9;
+        config.caches[0].number_of_sets = 3;
+        config.caches[1].line_size = 8;
+        config.page_table.page_size = 8;
+        assert_eq!(
+            config.validate().unwrap_err(),
+            [
+                ValidationError::NotPowerOfTwo {
+                    field: "number of dc sets".to_owned(),
+                    value: 3,
+                },
+                ValidationError::TooLarge {
+                    field: "dtlb set size".to_owned(),
+                    value: 9,
+                    max: MAX_SET_SIZE,
+                },
+                ValidationError::PageNotMultipleOfLine {
+                    field: "dc line size".to_owned(),
+                    page_size: 8,
+                    line_size: 16,
+                },
+                ValidationError::LineSmallerThanUpperLevel {
+                    level: "L2".to_owned(),
+                    line_size: 8,
+                    upper_level: "dc".to_owned(),
+                    upper_line_size: 16,
+                },
+            ]
+        );
     }
 
-    /// Is the cache no-write-allocate?
-    /// No-write-allocate means that the cache line is not loaded into the cache when a write miss occurs.
-    /// A write-through cache uses a no-write-allocate policy.
-    pub fn is_no_write_allocate(&self) -> bool {
-        self.write_through
+    #[test]
+    fn overrides_set_fields_by_key() {
+        let mut config: SimulatorConfig = TRACE_CONFIG.parse().unwrap();
+        config.apply_override("dc.sets=4").unwrap();
+        config.apply_override(" l2.write_through = n ").unwrap();
+        config.apply_override("tlb.policy=fifo").unwrap();
+        config.apply_override("virtual_addresses=false").unwrap();
+        assert_eq!(config.caches[0].number_of_sets, 4);
+        assert!(!config.caches[1].write_through);
+        assert_eq!(config.tlb.eviction_policy, EvictionPolicy::FIFO);
+        assert!(!config.virtual_addresses_enabled);
     }
 
-    /// Is the cache write-back?
-    /// Write back means that the data is written to the cache and the main memory is updated when the cache line is evicted.
-    /// A write-back cache uses a write-allocate policy.
-    pub fn is_write_back(&self) -> bool {
-        !self.write_through
+    #[test]
+    fn overriding_the_level_past_the_deepest_adds_it() {
+        let mut config: SimulatorConfig = TRACE_CONFIG.parse().unwrap();
+        config.apply_override("l3.sets=64").unwrap();
+        assert_eq!(config.caches.len(), 3);
+        assert_eq!(config.caches[2].number_of_sets, 64);
+
+        // The hierarchy ends at the L3, so an L4 could be added, but not an L5.
+        let error = config.apply_override("l5.sets=64").unwrap_err();
+        assert!(error.to_string().starts_with("bad override \"l5.sets=64\": unknown key"), "{error}");
+        assert_eq!(config.caches.len(), 3);
     }
 
-    /// Is the cache write-allocate?
-    /// Write allocate means that the cache line is loaded into the cache when a write miss occurs.
-    /// A write-back cache uses a write-allocate policy.
-    pub fn is_write_allocate(&self) -> bool {
-        !self.write_through
+    #[test]
+    fn bad_overrides_are_rejected() {
+        let mut config: SimulatorConfig = TRACE_CONFIG.parse().unwrap();
+        let message = |assignment: &str| match config.clone().apply_override(assignment) {
+            Err(ConfigError::BadOverride { message, .. }) => message,
+            result => panic!("{assignment}: {result:?}"),
+        };
+        assert!(message("dc.colour=red").starts_with("unknown key \"dc.colour\", expected one of: tlb.sets"));
+        assert!(message("l1.sets=4").starts_with("unknown key"));
+        assert!(message("cache_size=4").starts_with("unknown key"));
+        assert_eq!(message("dc.sets"), "expected \"key=value\"");
+        assert_eq!(message("dc.sets=four"), "expected a number, got \"four\"");
+        assert_eq!(message("tlb.enabled=maybe"), "expected \"y\" or \"n\", got \"maybe\"");
+        assert!(config.set("dc.policy", "newest").is_err());
     }
 
-    /// Get the associativity of the data cache from the configuration.
-    pub fn get_associativity(&self) -> u64 {
-        // The associativity is the number of blocks in each set
-        self.set_size
+    /// A configuration that uses every optional part of the structured formats.
+    fn elaborate_config() -> SimulatorConfig {
+        let mut config: SimulatorConfig = TRACE_CONFIG.parse().unwrap();
+        for assignment in [
+            "itlb.sets=4",
+            "ic.sets=8",
+            "dc.write_through=n",
+            "dc.write_allocate=n",
+            "dc.policy=srrip",
+            "l2.insertion=bip",
+            "l3.sets=64",
+            "l3.enabled=n",
+            "virtual_address_bits=40",
+            "physical_address_bits=36",
+        ] {
+            config.apply_override(assignment).unwrap();
+        }
+        config
     }
 
-    /// Get the number of bytes in each block (the line size for the cache).
-    pub fn get_block_size(&self) -> u64 {
-        self.line_size
+    #[test]
+    fn structured_formats_read_back_what_they_write() {
+        let config = elaborate_config();
+        for format in [ConfigFormat::Toml, ConfigFormat::Json, ConfigFormat::Legacy] {
+            let text = config.to_string_with_format(format).unwrap();
+            let read = SimulatorConfig::from_str_with_format(&text, format).unwrap();
+            assert_eq!(format!("{read:?}"), format!("{config:?}"), "{format:?}:\n{text}");
+        }
     }
 
-    /// Get the eviction policy for the data cache.
-    pub fn get_eviction_policy(&self) -> EvictionPolicy {
-        EvictionPolicy::LRU
+    #[test]
+    fn numbers_above_i64_max_are_written_without_panicking() {
+        let mut config: SimulatorConfig = TRACE_CONFIG.parse().unwrap();
+        config.page_table.page_size = 1 << 63;
+        for format in [ConfigFormat::Toml, ConfigFormat::Json] {
+            let text = config.to_string_with_format(format).unwrap();
+            let read = SimulatorConfig::from_str_with_format(&text, format).unwrap();
+            assert_eq!(read.page_table.page_size, 1 << 63, "{format:?}");
+        }
     }
 
-    /// Get the number of sets in the data cache.
-    pub fn get_number_of_sets(&self) -> u64 {
-        self.number_of_sets
+    #[test]
+    fn structured_syntax_errors_have_line_numbers() {
+        let toml = "[tlb]\nnumber_of_sets = 2\nset_size = \"one\"\n";
+        let error = SimulatorConfig::from_str_with_format(toml, ConfigFormat::Toml).unwrap_err();
+        assert!(matches!(error, ConfigError::Syntax { line: 3, .. }), "{error}");
+        let json = "{\n  \"tlb\": {\n    \"set_size\": true\n  }\n}";
+        let error = SimulatorConfig::from_str_with_format(json, ConfigFormat::Json).unwrap_err();
+        assert!(matches!(error, ConfigError::Syntax { line: 3, .. }), "{error}");
     }
 
-    /// Read the configuration from a file, buffer, or other reader.
-    fn from_buffer<R>(buffer: &mut BufReader<R>) -> Self
-    where
-        R: Read,
-    {
-        get_header(buffer, "L2 Cache configuration");
-        let number_of_sets = get_decimal(buffer, Some("Number of sets")).unwrap().1;
-        let set_size = get_decimal(buffer, Some("Set size")).unwrap().1;
-        let line_size = get_decimal(buffer, Some("Line size")).unwrap().1;
-        let write_through = get_bool(buffer, Some("Write through/no write allocate"))
-            .unwrap()
-            .1;
-        Self::new(number_of_sets, set_size, line_size, write_through)
+    #[test]
+    fn the_builder_defaults_match_trace_config() {
+        let built = SimulatorConfig::builder().build().unwrap();
+        let read: SimulatorConfig = TRACE_CONFIG.parse().unwrap();
+        assert_eq!(format!("{built:?}"), format!("{read:?}"));
     }
-}
 
-impl Display for L2CacheConfig {
-    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        writeln!(f, "L2-cache contains {} sets.\nEach set contains {} entries.\nEach line is {} bytes.\nThe cache uses a {}write-allocate and write-{} policy.\nNumber of bits used for the index is {}.\nNumber of bits used for the offset is {}.\n", self.number_of_sets, self.set_size, self.line_size, if self.write_through { "no " } else { "" }, if self.write_through { "through" } else {"back"}, self.get_index_bits(), self.get_offset_bits())
+    #[test]
+    fn a_single_level_is_written_to_the_legacy_layout_with_a_disabled_l2() {
+        let config = SimulatorConfig::builder()
+            .cache_levels(vec![CacheConfig::new(4, 2, 32, false)])
+            .build()
+            .unwrap();
+        let text = config.to_string_with_format(ConfigFormat::Legacy).unwrap();
+        assert!(text.contains("L2 Cache configuration\nNumber of sets: 4\n"), "{text}");
+        assert!(text.contains("L2 cache: n\n"), "{text}");
+
+        let read: SimulatorConfig = text.parse().unwrap();
+        assert_eq!(read.caches.len(), 2);
+        assert!(!read.caches[1].enabled);
+        let enabled: Vec<_> = read.enabled_caches().map(|(depth, cache)| (depth, cache.number_of_sets)).collect();
+        assert_eq!(enabled, [(0, 4)]);
     }
 }
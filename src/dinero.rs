@@ -0,0 +1,106 @@
+use super::{AccessType, Operation, Record, TraceError};
+use std::io::{Result as IoResult, Write};
+
+/// The layout of a din line, for error messages.
+const EXPECTED_LINE: &str = "\"label address\"";
+/// The labels of din, for error messages.
+const EXPECTED_LABELS: &str = "a label from 0 to 4";
+
+/// Parse one line of a Dinero "din" trace, found at the given line number.
+///
+/// Lines look like `0 1ffc`: a label, then the address in hexadecimal. Anything after
+/// the address is ignored, as Dinero does. The labels are 0 for a read, 1 for a write and 2
+/// for an instruction fetch. Label 3 is a miscellaneous access, which is simulated as a read.
+/// Label 4 asks Dinero to flush its caches, which the simulator doesn't model, so the line has no operations.
+pub(crate) fn parse_line(line: usize, text: &str) -> Result<Option<Record>, TraceError> {
+    let text_owned = || text.to_owned();
+
+    let mut fields = text.split_whitespace();
+    let (Some(label), Some(address)) = (fields.next(), fields.next()) else {
+        return Err(TraceError::Malformed {
+            line,
+            text: text_owned(),
+            expected: EXPECTED_LINE.to_owned(),
+        });
+    };
+    let address = address.trim_start_matches("0x");
+    let address = u64::from_str_radix(address, 16).map_err(|_| TraceError::BadAddress {
+        line,
+        text: text_owned(),
+    })?;
+
+    let operation = match label {
+        "0" | "3" => Operation::read(address),
+        "1" => Operation::write(address),
+        "2" => Operation::fetch(address),
+        "4" => return Ok(None),
+        _ => {
+            return Err(TraceError::BadAccessType {
+                line,
+                text: text_owned(),
+                expected: EXPECTED_LABELS.to_owned(),
+            })
+        }
+    };
+    Ok(Some((operation, None)))
+}
+
+/// Write an operation as a din line. The format has no sizes, so only the first byte is written.
+pub(crate) fn write_operation<W>(writer: &mut W, operation: &Operation) -> IoResult<()>
+where
+    W: Write,
+{
+    let label = match operation.access_type {
+        AccessType::Read => 0,
+        AccessType::Write => 1,
+        AccessType::Fetch => 2,
+    };
+    writeln!(writer, "{label} {:x}", operation.address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Trace, TraceFormat, TraceOptions};
+
+    #[test]
+    fn labels_are_parsed() {
+        let parse = |text: &str| parse_line(1, text).unwrap().map(|(operation, _)| operation);
+        assert_eq!(parse("0 1ffc"), Some(Operation::read(0x1ffc)));
+        assert_eq!(parse("1 0x20"), Some(Operation::write(0x20)));
+        assert_eq!(parse("2 400"), Some(Operation::fetch(0x400)));
+        assert_eq!(parse("3 10"), Some(Operation::read(0x10)));
+    }
+
+    #[test]
+    fn a_flush_has_no_operations() {
+        assert_eq!(parse_line(1, "4 0").unwrap(), None);
+    }
+
+    #[test]
+    fn anything_after_the_address_is_ignored() {
+        assert_eq!(parse_line(1, "1 20 some comment").unwrap(), Some((Operation::write(0x20), None)));
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        assert!(matches!(parse_line(3, "0"), Err(TraceError::Malformed { line: 3, .. })));
+        assert!(matches!(parse_line(3, "4"), Err(TraceError::Malformed { line: 3, .. })));
+        assert!(matches!(parse_line(3, "5 1ffc"), Err(TraceError::BadAccessType { line: 3, .. })));
+        assert!(matches!(parse_line(3, "r 1ffc"), Err(TraceError::BadAccessType { line: 3, .. })));
+        assert!(matches!(parse_line(3, "0 xyz"), Err(TraceError::BadAddress { line: 3, .. })));
+    }
+
+    #[test]
+    fn a_trace_is_written_and_read_back() {
+        let trace: Trace = "R:1ffc\nW:20\nI:400".parse().unwrap();
+        let written = trace.write(vec![], TraceFormat::Dinero).unwrap();
+        assert_eq!(String::from_utf8(written.clone()).unwrap(), "0 1ffc\n1 20\n2 400\n");
+        let options = TraceOptions {
+            format: TraceFormat::Dinero,
+            ..TraceOptions::default()
+        };
+        let read = Trace::from_reader(written.as_slice(), options).unwrap();
+        assert_eq!(read.operations, trace.operations);
+    }
+}
@@ -0,0 +1,160 @@
+use super::{AccessType, Operation, Record, TraceError};
+use std::io::{Result as IoResult, Write};
+
+/// The layout of a Lackey line, for error messages.
+const EXPECTED_LINE: &str = "\"type address,size\"";
+/// The access types of Lackey, for error messages.
+const EXPECTED_TYPES: &str = "\"I\", \"L\", \"S\" or \"M\"";
+
+/// Parse one line of the output of Valgrind's `--tool=lackey --trace-mem=yes`,
+/// found at the given line number.
+///
+/// Lines look like `I  0400d7d4,8` for an instruction fetch, or ` L 0421ad54,4`,
+/// ` S 0421ad54,4` and ` M 0421ad54,4` for a load, a store and a modify (a load followed
+/// by a store to the same bytes). The address is in hexadecimal, and the size in decimal.
+/// Valgrind's own messages (starting with `==` or `--`) have no operations.
+pub(crate) fn parse_line(line: usize, text: &str) -> Result<Option<Record>, TraceError> {
+    if text.starts_with("==") || text.starts_with("--") {
+        return Ok(None);
+    }
+    let text_owned = || text.to_owned();
+
+    let mut fields = text.split_whitespace();
+    let (Some(access_type), Some(access), None) = (fields.next(), fields.next(), fields.next())
+    else {
+        return Err(TraceError::Malformed {
+            line,
+            text: text_owned(),
+            expected: EXPECTED_LINE.to_owned(),
+        });
+    };
+    let Some((address, size)) = access.split_once(',') else {
+        return Err(TraceError::Malformed {
+            line,
+            text: text_owned(),
+            expected: EXPECTED_LINE.to_owned(),
+        });
+    };
+    let address = u64::from_str_radix(address, 16).map_err(|_| TraceError::BadAddress {
+        line,
+        text: text_owned(),
+    })?;
+    let size = match size.parse::<u64>() {
+        Ok(size) if size > 0 && address.checked_add(size - 1).is_some() => size,
+        _ => {
+            return Err(TraceError::BadSize {
+                line,
+                text: text_owned(),
+            })
+        }
+    };
+
+    let (operation, then) = match access_type {
+        "I" => (Operation::fetch(address), None),
+        "L" => (Operation::read(address), None),
+        "S" => (Operation::write(address), None),
+        "M" => (Operation::read(address), Some(Operation::write(address))),
+        _ => {
+            return Err(TraceError::BadAccessType {
+                line,
+                text: text_owned(),
+                expected: EXPECTED_TYPES.to_owned(),
+            })
+        }
+    };
+    Ok(Some((
+        operation.with_size(size),
+        then.map(|operation| operation.with_size(size)),
+    )))
+}
+
+/// Write an operation as a Lackey line.
+pub(crate) fn write_operation<W>(writer: &mut W, operation: &Operation) -> IoResult<()>
+where
+    W: Write,
+{
+    let access_type = match operation.access_type {
+        AccessType::Fetch => "I ",
+        AccessType::Read => " L",
+        AccessType::Write => " S",
+    };
+    writeln!(writer, "{access_type} {:08x},{}", operation.address, operation.size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Trace, TraceFormat, TraceOptions};
+
+    #[test]
+    fn accesses_are_parsed() {
+        assert_eq!(
+            parse_line(1, "I  0400d7d4,8").unwrap(),
+            Some((Operation::fetch(0x0400d7d4).with_size(8), None))
+        );
+        assert_eq!(
+            parse_line(1, " L 0421ad54,4").unwrap(),
+            Some((Operation::read(0x0421ad54).with_size(4), None))
+        );
+        assert_eq!(
+            parse_line(1, " S 0421ad54,2").unwrap(),
+            Some((Operation::write(0x0421ad54).with_size(2), None))
+        );
+    }
+
+    #[test]
+    fn a_modify_is_a_load_then_a_store() {
+        assert_eq!(
+            parse_line(1, " M 0421ad54,4").unwrap(),
+            Some((
+                Operation::read(0x0421ad54).with_size(4),
+                Some(Operation::write(0x0421ad54).with_size(4))
+            ))
+        );
+    }
+
+    #[test]
+    fn valgrind_messages_have_no_operations() {
+        assert_eq!(parse_line(1, "==12345== Lackey, an example Valgrind tool").unwrap(), None);
+        assert_eq!(parse_line(1, "--12345-- run: /bin/true").unwrap(), None);
+    }
+
+    #[test]
+    fn malformed_lines_are_errors() {
+        assert!(matches!(parse_line(7, "L"), Err(TraceError::Malformed { line: 7, .. })));
+        assert!(matches!(parse_line(7, " L 0421ad54"), Err(TraceError::Malformed { line: 7, .. })));
+        assert!(matches!(parse_line(7, " L 1,4 extra"), Err(TraceError::Malformed { line: 7, .. })));
+        assert!(matches!(parse_line(7, " X 0421ad54,4"), Err(TraceError::BadAccessType { line: 7, .. })));
+        assert!(matches!(parse_line(7, " L zz,4"), Err(TraceError::BadAddress { line: 7, .. })));
+        assert!(matches!(parse_line(7, " L 10,0"), Err(TraceError::BadSize { line: 7, .. })));
+        assert!(matches!(parse_line(7, " L ffffffffffffffff,2"), Err(TraceError::BadSize { line: 7, .. })));
+    }
+
+    #[test]
+    fn fetches_can_be_dropped() {
+        let options = TraceOptions {
+            format: TraceFormat::Lackey,
+            drop_fetches: true,
+            ..TraceOptions::default()
+        };
+        let trace = Trace::from_reader("I  1000,4\n M 2000,8\nI  1004,4\n".as_bytes(), options).unwrap();
+        assert_eq!(
+            trace.operations,
+            vec![Operation::read(0x2000).with_size(8), Operation::write(0x2000).with_size(8)]
+        );
+    }
+
+    #[test]
+    fn written_lines_are_read_back() {
+        for operation in [
+            Operation::fetch(0x400).with_size(4),
+            Operation::read(0x1ffc).with_size(8),
+            Operation::write(0x20),
+        ] {
+            let mut line = vec![];
+            write_operation(&mut line, &operation).unwrap();
+            let line = String::from_utf8(line).unwrap();
+            assert_eq!(parse_line(1, line.trim_end()).unwrap(), Some((operation, None)));
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use super::*;
+use log::{debug, info, trace};
+
+/// A request made of a cache level, either by the processor (for the first level)
+/// or by the level above it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LevelRequest {
+    /// Read a line, allocating it on a miss.
+    Read,
+    /// Write to a line. On a miss, the line is allocated if the level is write-allocate,
+    /// or if `allocate` is set because the level above is fetching the line.
+    Write { allocate: bool },
+    /// Write back a whole dirty line evicted by the level above. On a miss, the line is allocated
+    /// if the level is write-allocate, but never fetched, since all of it is written.
+    Writeback,
+}
+
+impl LevelRequest {
+    /// The request the processor makes of the first level for an operation.
+    pub fn from_operation(operation: &Operation) -> Self {
+        if operation.is_write() {
+            Self::Write { allocate: false }
+        } else {
+            Self::Read
+        }
+    }
+
+    pub fn is_read(&self) -> bool {
+        matches!(self, Self::Read)
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(self, Self::Write { .. } | Self::Writeback)
+    }
+}
+
+/// What happened when a cache level handled a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LevelOutcome {
+    /// Was the request a hit?
+    pub hit: bool,
+    /// The request passed on to the next level down (or to main memory), if any.
+    pub next: Option<LevelRequest>,
+    /// The physical address of the dirty line the level evicted to make room, which must be
+    /// written back to the next level down (or to main memory).
+    pub writeback: Option<u64>,
+}
+
+/// One level of the cache hierarchy, such as the data cache, an L2 or an L3.
+/// The instruction cache is also a level, beside the data cache at depth 0.
+pub struct CacheLevel {
+    cache: Cache,
+    /// The position of the level in the hierarchy, starting at 0 for the data cache.
+    depth: usize,
+    /// The name of the level in the output, such as "DC", "IC" or "L2".
+    name: String,
+    block_size: u64,
+    /// The number of bits in the physical addresses the level splits.
+    address_bits: u64,
+    index_bits: u64,
+    offset_bits: u64,
+    is_write_through: bool,
+    is_write_allocate: bool,
+    total_read_misses: u64,
+    total_write_misses: u64,
+    total_reads: u64,
+    total_writes: u64,
+}
+
+impl CacheLevel {
+    /// Create the cache level described by the configuration for the level at `depth`
+    /// (starting at 0 for the data cache), for physical addresses of `address_bits` bits.
+    pub fn new_from_config(depth: usize, config: &CacheConfig, address_bits: u64) -> Self {
+        Self::new_named(depth, CacheConfig::short_name(depth), config, address_bits)
+    }
+
+    /// Create the instruction cache described by the configuration,
+    /// for physical addresses of `address_bits` bits.
+    pub fn new_instruction_cache(config: &InstructionCacheConfig, address_bits: u64) -> Self {
+        Self::new_named(0, "IC".to_owned(), config, address_bits)
+    }
+
+    fn new_named(depth: usize, name: String, config: &CacheConfig, address_bits: u64) -> Self {
+        let number_of_sets = config.get_number_of_sets();
+        let associativity = config.get_associativity();
+        let block_size = config.get_block_size();
+        let evict_policy = config.get_eviction_policy();
+        info!("Creating new {name} cache with {number_of_sets} sets, block-size={block_size}, associativity={associativity}, and policy={evict_policy:?}");
+        Self {
+            cache: Cache::new(number_of_sets as usize, block_size, associativity, evict_policy)
+                .with_insertion_policy(config.get_insertion_policy()),
+            depth,
+            name,
+            block_size,
+            address_bits,
+            index_bits: config.get_index_bits(),
+            offset_bits: config.get_offset_bits(),
+            is_write_through: config.is_write_through(),
+            is_write_allocate: config.is_write_allocate(),
+            total_read_misses: 0,
+            total_write_misses: 0,
+            total_reads: 0,
+            total_writes: 0,
+        }
+    }
+
+    /// The position of the level in the hierarchy, starting at 0 for the data cache.
+    pub fn get_depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The name of the level in the output, such as "DC", "IC" or "L2".
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the leader sets and policy selection counter of the level, if it uses DRRIP or DIP.
+    pub fn get_dueling_stats(&self) -> Option<DuelingStats> {
+        self.cache.get_dueling_stats()
+    }
+
+    /// The replacement policy of the level.
+    pub fn get_eviction_policy(&self) -> EvictionPolicy {
+        self.cache.get_eviction_policy()
+    }
+
+    /// Give the level the accesses it will make, if it uses optimal replacement.
+    pub fn set_future_accesses(&mut self, future: FutureAccesses) {
+        self.cache.set_future_accesses(future);
+    }
+
+    /// Does the level use optimal replacement without having been given its future accesses?
+    pub fn needs_future(&self) -> bool {
+        self.cache.needs_future()
+    }
+
+    /// The number of bytes in a line of this level.
+    pub fn get_block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Split a physical address into the tag, index and offset used by this level.
+    pub fn get_address(&self, physical_address: u64) -> BlockAddress {
+        BlockAddress::new(physical_address, self.address_bits, self.index_bits, self.offset_bits)
+    }
+
+    /// Get the physical address of the first byte of a block in the cache.
+    fn get_block_address(&self, block: &Block) -> u64 {
+        (block.get_tag() << (self.index_bits + self.offset_bits)) | (block.get_index() << self.offset_bits)
+    }
+
+    /// Get the physical address of an evicted block, if it is dirty and must be written back.
+    /// A write-through level has already passed every write down, so it never writes back.
+    fn get_writeback(&self, evicted: Option<Block>) -> Option<u64> {
+        evicted
+            .filter(|block| block.is_dirty() && !self.is_write_through)
+            .map(|block| self.get_block_address(&block))
+    }
+
+    /// Write to a block in the cache, using the cache's write-miss policy.
+    /// This will return whether or not the write was a hit.
+    pub fn write(&mut self, address: BlockAddress, current_access_time: u64) -> bool {
+        self.write_with_allocate(address, current_access_time, self.is_write_allocate)
+    }
+
+    /// Write to a block in the cache. On a miss, the block is allocated if `allocate` is set,
+    /// whatever the cache's own write-miss policy is.
+    /// This will return whether or not the write was a hit.
+    pub fn write_with_allocate(
+        &mut self,
+        address: BlockAddress,
+        current_access_time: u64,
+        allocate: bool,
+    ) -> bool {
+        self.write_line(address, current_access_time, allocate).0
+    }
+
+    /// Write to a block in the cache, allocating it on a miss if `allocate` is set.
+    /// This returns whether or not the write was a hit, along with the block evicted to make room for it.
+    fn write_line(
+        &mut self,
+        address: BlockAddress,
+        current_access_time: u64,
+        allocate: bool,
+    ) -> (bool, Option<Block>) {
+        self.total_writes += 1;
+        let result = self.cache.is_hit(address);
+        let evicted = if allocate {
+            self.cache.write_and_allocate(address, current_access_time)
+        } else {
+            self.cache.try_write(address, current_access_time);
+            None
+        };
+
+        // If the result was not a hit, increment the miss count
+        if !result {
+            self.total_write_misses += 1;
+        }
+
+        (result, evicted)
+    }
+
+    /// Read a block in the cache.
+    /// This will return whether or not the read was a hit.
+    pub fn read(&mut self, address: BlockAddress, current_access_time: u64) -> bool {
+        self.read_line(address, current_access_time).0
+    }
+
+    /// Read a block in the cache, allocating it on a miss.
+    /// This returns whether or not the read was a hit, along with the block evicted to make room for it.
+    fn read_line(&mut self, address: BlockAddress, current_access_time: u64) -> (bool, Option<Block>) {
+        self.total_reads += 1;
+        let result = self.cache.is_hit(address);
+        let evicted = self.cache.read_and_allocate(address, current_access_time);
+        if !result {
+            self.total_read_misses += 1
+        }
+
+        (result, evicted)
+    }
+
+    /// Perform a request at this level.
+    /// This returns whether or not the request was a hit, the request this level passes on
+    /// to the next level down (or to main memory), and the dirty line it evicted, if any.
+    pub fn access(
+        &mut self,
+        request: LevelRequest,
+        address: BlockAddress,
+        current_access_time: u64,
+    ) -> LevelOutcome {
+        let ((hit, evicted), allocated) = match request {
+            LevelRequest::Read => (self.read_line(address, current_access_time), true),
+            LevelRequest::Write { allocate } => {
+                let allocate = allocate || self.is_write_allocate;
+                (self.write_line(address, current_access_time, allocate), allocate)
+            }
+            LevelRequest::Writeback => (
+                self.write_line(address, current_access_time, self.is_write_allocate),
+                self.is_write_allocate,
+            ),
+        };
+
+        // A miss that allocates the line fetches it from the next level, unless the whole line is written.
+        let fetches_line = !hit && allocated && request != LevelRequest::Writeback;
+        // A write is passed down if the level is write-through, or if it missed and didn't
+        // allocate the line. Otherwise, a write-back level absorbs the write.
+        let passes_write = request.is_write() && (self.is_write_through || (!hit && !allocated));
+
+        let next = if passes_write && request == LevelRequest::Writeback {
+            Some(LevelRequest::Writeback)
+        } else if passes_write {
+            // A line fetched into this level is always allocated in the next one,
+            // whatever its write-miss policy.
+            Some(LevelRequest::Write {
+                allocate: fetches_line,
+            })
+        } else if fetches_line {
+            Some(LevelRequest::Read)
+        } else {
+            None
+        };
+        LevelOutcome {
+            hit,
+            next,
+            writeback: self.get_writeback(evicted),
+        }
+    }
+
+    /// Invalidate a physical page from the cache. This gets all the blocks loaded from
+    /// the page, and then invalidates them in the cache.
+    /// This returns the physical addresses of the dirty lines among them, which must be written back.
+    pub fn invalidate_page(&mut self, physical_address: u64, page_size: u64) -> Vec<u64> {
+        let block_size = self.block_size;
+        let number_of_blocks = (page_size / block_size) as usize;
+        let physical_address = physical_address & !(page_size - 1);
+        debug!(
+            "{} Invalidating {number_of_blocks} blocks = {} bytes at {physical_address:x}",
+            self.name,
+            number_of_blocks as u64 * block_size
+        );
+        assert!(number_of_blocks as u64 * block_size == page_size);
+        let mut result = vec![];
+        for block in 0..number_of_blocks {
+            let block_address = self.get_address(physical_address + block as u64 * block_size);
+            if let Some(block) = self.cache.invalidate(block_address) {
+                trace!("Invalidated {} block {block:?}", self.name);
+                result.extend(self.get_writeback(Some(block)));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use LevelRequest::{Read, Write};
+
+    /// An L2 of one set of two 16-byte lines, with the given write policies.
+    fn level(write_through: bool, write_allocate: bool) -> CacheLevel {
+        let config = CacheConfig {
+            write_allocate: Some(write_allocate),
+            ..CacheConfig::new(1, 2, 16, write_through)
+        };
+        CacheLevel::new_from_config(1, &config, 32)
+    }
+
+    /// Make a request of a level for the line at a physical address, and return whether it hit
+    /// and the request passed down.
+    fn request(level: &mut CacheLevel, request: LevelRequest, address: u64, time: u64) -> (bool, Option<LevelRequest>) {
+        let outcome = level.access(request, level.get_address(address), time);
+        (outcome.hit, outcome.next)
+    }
+
+    const WRITE: LevelRequest = Write { allocate: false };
+
+    #[test]
+    fn write_through_without_allocation_passes_every_write_down() {
+        let mut level = level(true, false);
+        assert_eq!(request(&mut level, WRITE, 0x0, 1), (false, Some(WRITE)));
+        assert_eq!(request(&mut level, Read, 0x0, 2), (false, Some(Read)));
+        assert_eq!(request(&mut level, WRITE, 0x0, 3), (true, Some(WRITE)));
+    }
+
+    #[test]
+    fn write_through_with_allocation_fetches_on_a_write_miss() {
+        let mut level = level(true, true);
+        assert_eq!(request(&mut level, WRITE, 0x0, 1), (false, Some(Write { allocate: true })));
+        assert_eq!(request(&mut level, Read, 0x0, 2), (true, None));
+        assert_eq!(request(&mut level, WRITE, 0x0, 3), (true, Some(WRITE)));
+    }
+
+    #[test]
+    fn write_back_with_allocation_absorbs_writes() {
+        let mut level = level(false, true);
+        assert_eq!(request(&mut level, WRITE, 0x0, 1), (false, Some(Read)));
+        assert_eq!(request(&mut level, Read, 0x0, 2), (true, None));
+        assert_eq!(request(&mut level, WRITE, 0x0, 3), (true, None));
+    }
+
+    #[test]
+    fn write_back_without_allocation_passes_write_misses_down() {
+        let mut level = level(false, false);
+        assert_eq!(request(&mut level, WRITE, 0x0, 1), (false, Some(WRITE)));
+        assert_eq!(request(&mut level, Read, 0x0, 2), (false, Some(Read)));
+        assert_eq!(request(&mut level, WRITE, 0x0, 3), (true, None));
+    }
+
+    #[test]
+    fn a_write_fetched_by_the_level_above_is_allocated_whatever_the_policy() {
+        let allocating = Write { allocate: true };
+        let mut write_back = level(false, false);
+        assert_eq!(request(&mut write_back, allocating, 0x0, 1), (false, Some(Read)));
+        assert_eq!(request(&mut write_back, Read, 0x0, 2), (true, None));
+
+        let mut write_through = level(true, false);
+        assert_eq!(request(&mut write_through, allocating, 0x0, 1), (false, Some(allocating)));
+        assert_eq!(request(&mut write_through, Read, 0x0, 2), (true, None));
+    }
+
+    #[test]
+    fn a_writeback_is_never_fetched() {
+        let mut write_back = level(false, true);
+        assert_eq!(request(&mut write_back, LevelRequest::Writeback, 0x0, 1), (false, None));
+        assert_eq!(request(&mut write_back, Read, 0x0, 2), (true, None));
+
+        let mut write_through = level(true, true);
+        let writeback = Some(LevelRequest::Writeback);
+        assert_eq!(request(&mut write_through, LevelRequest::Writeback, 0x0, 1), (false, writeback));
+        assert_eq!(request(&mut write_through, Read, 0x0, 2), (true, None));
+
+        let mut no_allocate = level(false, false);
+        assert_eq!(request(&mut no_allocate, LevelRequest::Writeback, 0x0, 1), (false, writeback));
+        assert_eq!(request(&mut no_allocate, Read, 0x0, 2), (false, Some(Read)));
+    }
+
+    #[test]
+    fn only_dirty_lines_of_a_write_back_level_are_written_back() {
+        let mut write_back = level(false, true);
+        let mut access = |request: LevelRequest, address: u64, time: u64| {
+            write_back.access(request, write_back.get_address(address), time).writeback
+        };
+        assert_eq!(access(WRITE, 0x0, 1), None);
+        assert_eq!(access(Read, 0x10, 2), None);
+        assert_eq!(access(Read, 0x20, 3), Some(0x0));
+        assert_eq!(access(Read, 0x30, 4), None);
+
+        let mut write_through = level(true, true);
+        for (time, address) in (1..).zip([0x0, 0x10, 0x20]) {
+            let outcome = write_through.access(WRITE, write_through.get_address(address), time);
+            assert_eq!(outcome.writeback, None);
+        }
+    }
+
+    #[test]
+    fn invalidating_a_page_returns_its_dirty_lines() {
+        let config = CacheConfig::new(4, 2, 16, false);
+        let mut level = CacheLevel::new_from_config(0, &config, 32);
+        for (time, (request, address)) in (1..).zip([(WRITE, 0x110), (Read, 0x120), (WRITE, 0x200)]) {
+            level.access(request, level.get_address(address), time);
+        }
+        assert_eq!(level.invalidate_page(0x100, 0x100), [0x110]);
+        assert_eq!(request(&mut level, Read, 0x120, 4), (false, Some(Read)));
+        assert_eq!(request(&mut level, Read, 0x200, 5), (true, None));
+    }
+
+    #[test]
+    fn a_level_splits_addresses_by_its_own_geometry() {
+        let config = CacheConfig::new(4, 2, 32, true);
+        let level = CacheLevel::new_from_config(2, &config, 32);
+        assert_eq!(level.get_name(), "L3");
+        let address = level.get_address(0x1234_5678);
+        assert_eq!((address.tag, address.index, address.offset), (0x1234_5678 >> 7, 3, 0x18));
+    }
+}
@@ -1,140 +1,211 @@
+mod binary;
+pub mod builder;
 pub mod cache;
 pub mod config;
-pub mod dc;
-pub mod l2;
+mod dinero;
+mod lackey;
+pub mod level;
 pub mod output;
 pub mod pagetable;
 pub mod simulator;
+pub mod sweep;
 pub mod tlb;
 pub mod trace;
+pub mod tracegen;
+pub mod transform;
 
+pub use builder::*;
 pub use cache::*;
 pub use config::*;
-pub use dc::*;
-pub use l2::*;
+pub use level::*;
 pub use output::*;
 pub use pagetable::*;
 pub use simulator::*;
+pub use sweep::*;
 pub use tlb::*;
 pub use trace::*;
+pub use tracegen::*;
+pub use transform::*;
 
 use std::io::{BufRead, BufReader, Read};
 
-/// Read a line from the buffer, and panic if it is not equal to the given text.
-pub(crate) fn get_header<R>(buffer: &mut BufReader<R>, text: &str)
+/// A buffered reader that keeps track of the line number of the last line read,
+/// so that parse errors can point at the offending line.
+pub(crate) struct LineReader<R> {
+    buffer: BufReader<R>,
+    line_number: usize,
+    /// A line that was read, but handed back to be read again.
+    unread: Option<String>,
+}
+
+impl<R> LineReader<R>
 where
     R: Read,
 {
-    let mut line = String::new();
-    while line.trim() == "" {
-        buffer.read_line(&mut line).unwrap();
+    pub(crate) fn new(reader: R) -> Self {
+        Self::from_buffer(BufReader::new(reader))
+    }
+
+    /// Read lines from a reader that is already buffered.
+    pub(crate) fn from_buffer(buffer: BufReader<R>) -> Self {
+        Self {
+            buffer,
+            line_number: 0,
+            unread: None,
+        }
+    }
+
+    /// The line number (starting at 1) of the last line read.
+    pub(crate) fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    /// Read the next non-blank line from the buffer, with surrounding whitespace trimmed.
+    /// Return None if the end of the buffer is reached first.
+    pub(crate) fn next_line(&mut self) -> Result<Option<String>, ConfigError> {
+        if let Some(line) = self.unread.take() {
+            return Ok(Some(line));
+        }
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let bytes_read = self
+                .buffer
+                .read_line(&mut line)
+                .map_err(|e| ConfigError::Io(e.to_string()))?;
+            if bytes_read == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+            if !line.trim().is_empty() {
+                return Ok(Some(line.trim().to_owned()));
+            }
+        }
     }
 
-    if line.trim() != text {
-        panic!("Expected \"{}\", got \"{}\"", text, line.trim());
+    /// Hand a line back to the reader, so that the next call to `next_line` returns it again.
+    pub(crate) fn unread_line(&mut self, line: String) {
+        self.unread = Some(line);
     }
 }
 
-/// Read a line from the buffer. Treat the line as a key value pair. If the key doesn't match the given text, panic.
-/// Return the key and the value parsed as a decimal number.
-pub(crate) fn get_decimal<R>(buffer: &mut BufReader<R>, text: Option<&str>) -> Option<(String, u64)>
+/// Read a line from the buffer, and fail if it is not equal to the given text.
+pub(crate) fn get_header<R>(buffer: &mut LineReader<R>, text: &str) -> Result<(), ConfigError>
 where
     R: Read,
 {
-    let mut line = String::new();
-    while line.trim() == "" {
-        if buffer.read_line(&mut line).unwrap() == 0 {
-            return None;
-        }
-    }
-    let mut split = line.split(':');
-    let first = split.next().unwrap().trim();
-    if let Some(text) = text {
-        if split.clone().count() != 1 {
-            panic!("Expected \"{}: {{number}}\", got \"{}\"", text, line.trim());
-        }
-        // Confirm that there is only one item in the split to consume (the decimal value)
-        if first != text {
-            panic!(
-                "Expected \"{:?}: {{number}}\", got \"{}\"",
-                text,
-                line.trim()
-            );
-        }
+    let line = buffer
+        .next_line()?
+        .ok_or_else(|| ConfigError::UnexpectedEof {
+            line: buffer.line_number(),
+            expected: text.to_owned(),
+        })?;
+
+    if line != text {
+        return Err(ConfigError::MissingSection {
+            line: buffer.line_number(),
+            expected: text.to_owned(),
+            found: line,
+        });
     }
-    Some((
-        first.to_owned(),
-        split.next().unwrap().trim().parse::<u64>().unwrap(),
-    ))
+    Ok(())
 }
 
-/// Read a line from the buffer. Treat the line as a key value pair. If the key doesn't match the given text, panic.
-/// Return the key and the value parsed as a hexadecimal number.
-pub(crate) fn get_hexadecimal<R>(
-    buffer: &mut BufReader<R>,
+/// Read a line from the buffer. Treat the line as a key value pair. If the key doesn't match the given text, fail.
+/// Return the line number, the key, and the (unparsed) value.
+fn get_key_value<R>(
+    buffer: &mut LineReader<R>,
     text: Option<&str>,
-) -> Option<(String, u64)>
+) -> Result<(usize, String, String), ConfigError>
 where
     R: Read,
 {
-    let mut line = String::new();
-    while line.trim() == "" {
-        if buffer.read_line(&mut line).unwrap() == 0 {
-            return None;
+    let line = buffer
+        .next_line()?
+        .ok_or_else(|| ConfigError::UnexpectedEof {
+            line: buffer.line_number(),
+            expected: text.unwrap_or("{key}: {value}").to_owned(),
+        })?;
+    let line_number = buffer.line_number();
+
+    let (key, value) = match line.split_once(':') {
+        Some((key, value)) => (key.trim(), value.trim()),
+        None => (line.as_str(), ""),
+    };
+    if let Some(text) = text {
+        if key != text {
+            return Err(ConfigError::UnknownKey {
+                line: line_number,
+                expected: text.to_owned(),
+                found: line.clone(),
+            });
         }
     }
-    let mut split = line.split(':');
-    let first = split.next().unwrap().trim();
-    if let Some(text) = text {
-        // Confirm that there is only one item in the split to consume (the hex value)
-        if split.clone().count() != 1 {
-            panic!("Expected \"{}: {{number}}\", got \"{}\"", text, line.trim());
+    Ok((line_number, key.to_owned(), value.to_owned()))
+}
+
+/// Read an optional key value pair from the buffer. If the next line has a different key
+/// (or the buffer is empty), leave it to be read again and return None.
+/// Otherwise, return the line number and the (unparsed) value.
+pub(crate) fn get_optional<R>(
+    buffer: &mut LineReader<R>,
+    text: &str,
+) -> Result<Option<(usize, String)>, ConfigError>
+where
+    R: Read,
+{
+    let line = match buffer.next_line()? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    match line.split_once(':') {
+        Some((key, value)) if key.trim() == text => {
+            Ok(Some((buffer.line_number(), value.trim().to_owned())))
         }
-        if first != text {
-            panic!(
-                "Expected \"{:?}: {{number}}\", got \"{}\"",
-                text,
-                line.trim()
-            );
+        _ => {
+            buffer.unread_line(line);
+            Ok(None)
         }
     }
-    let hex_str = split.next().unwrap().trim();
-    Some((first.to_owned(), u64::from_str_radix(&hex_str, 16).unwrap()))
 }
 
-/// Read a line from the buffer. Treat the line as a key value pair. If the key doesn't match the given text, panic.
-/// Return the key and the value parsed as a boolean value (read as "y" for true or "n" for false).
-pub(crate) fn get_bool<R>(buffer: &mut BufReader<R>, text: Option<&str>) -> Option<(String, bool)>
+/// Read a line from the buffer. Treat the line as a key value pair. If the key doesn't match the given text, fail.
+/// Return the key and the value parsed as a decimal number.
+pub(crate) fn get_decimal<R>(
+    buffer: &mut LineReader<R>,
+    text: Option<&str>,
+) -> Result<(String, u64), ConfigError>
 where
     R: Read,
 {
-    let mut line = String::new();
-    while line.trim() == "" {
-        if buffer.read_line(&mut line).unwrap() == 0 {
-            return None;
-        }
+    let (line, key, value) = get_key_value(buffer, text)?;
+    match value.parse::<u64>() {
+        Ok(number) => Ok((key, number)),
+        Err(_) => Err(ConfigError::BadNumber { line, key, value }),
     }
-    let mut split = line.split(':');
-    let first = split.next().unwrap().trim();
-    if let Some(text) = text {
-        // Confirm that there is only one item in the split to consume (the "y/n" value)
-        if split.clone().count() != 1 {
-            panic!("Expected \"{}: {{number}}\", got \"{}\"", text, line.trim());
-        }
-        if first != text {
-            panic!(
-                "Expected \"{:?}: {{number}}\", got \"{}\"",
-                text,
-                line.trim()
-            );
-        }
+}
+
+/// Read a line from the buffer. Treat the line as a key value pair. If the key doesn't match the given text, fail.
+/// Return the key and the value parsed as a boolean value (read as "y" for true or "n" for false).
+pub(crate) fn get_bool<R>(
+    buffer: &mut LineReader<R>,
+    text: Option<&str>,
+) -> Result<(String, bool), ConfigError>
+where
+    R: Read,
+{
+    let (line, key, value) = get_key_value(buffer, text)?;
+    match parse_bool(&value) {
+        Some(flag) => Ok((key, flag)),
+        None => Err(ConfigError::BadBool { line, key, value }),
     }
-    let value = split.next().unwrap().trim();
-    if value == "y" || value == "Y" {
-        Some((first.to_owned(), true))
-    } else if value == "n" || value == "N" {
-        Some((first.to_owned(), false))
-    } else {
-        panic!("Expected \"{{bool}}\", got \"{}\"", value);
+}
+
+/// Parse a boolean value written as "y" for true or "n" for false.
+pub(crate) fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "y" | "Y" => Some(true),
+        "n" | "N" => Some(false),
+        _ => None,
     }
 }
@@ -1,23 +1,490 @@
 use memory_hierarchy::*;
 use log::info;
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+};
+
+const USAGE: &str = "usage: memory-hierarchy [--config <path>] [--set <key>=<value>]... [--print-config <legacy|toml|json>]
+                        [--sweep <key>=<values>]... [--csv] [--threads <n>] [--lenient]
+                        [--trace-format <native|lackey|dinero>] [--drop-fetches]
+                        [--write-trace <native|lackey|dinero|binary|binary-fixed>] [[--] trace]
+       memory-hierarchy tracegen <pattern> [<key>=<value>]...
+                        [--write-trace <native|lackey|dinero|binary|binary-fixed>]
+       memory-hierarchy trace [--apply <transform>]... [--lenient]
+                        [--trace-format <native|lackey|dinero>] [--drop-fetches]
+                        [--write-trace <native|lackey|dinero|binary|binary-fixed>] [trace]
+
+A trace file named like a subcommand (trace or tracegen) is simulated by putting -- before it.
+
+--lenient skips trace lines that can't be parsed (with a warning) instead of failing.
+
+--trace-format lackey reads the output of valgrind --tool=lackey --trace-mem=yes, and
+--trace-format dinero reads Dinero din traces (the default for files ending in .din).
+--drop-fetches leaves the instruction fetches out of the trace.
+--write-trace converts the trace to the given format on stdout instead of simulating it.
+Binary traces are recognized by their magic number, so they can be read without --trace-format.
+
+--sweep runs every combination of the given values instead of a single simulation, and prints
+one row of statistics per configuration. Values are a list (dc.policy=lru,fifo) or a range
+(dc.sets=1..=64:*2, l2.line_size=16..=128:+16, dc.set_size=1..=8).
+
+tracegen writes a synthetic trace with the given access pattern to stdout, in the native format
+unless --write-trace is given. The same parameters (including seed) always give the same trace.
+
+trace applies the --apply transforms to the trace in order, and writes the result to stdout in the
+native format unless --write-trace is given.";
+
+/// The options passed on the command line.
+struct Args {
+    /// The path of the configuration file.
+    config_path: String,
+    /// The `key=value` overrides to apply on top of the configuration file, in order.
+    overrides: Vec<String>,
+    /// If given, print the configuration in this format instead of running a simulation.
+    print_config: Option<ConfigFormat>,
+    /// The `key=values` axes of a sweep. If there are any, every combination is simulated.
+    sweeps: Vec<String>,
+    /// The layout of the results of a sweep.
+    sweep_format: SweepFormat,
+    /// The number of threads to run a sweep on.
+    threads: usize,
+    /// How to read the trace.
+    trace_options: TraceOptions,
+    /// If given, convert the trace to this format instead of running a simulation.
+    write_trace: Option<TraceFormat>,
+    /// The path of the trace file. If this is not given, the trace is read from stdin.
+    trace_path: Option<String>,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut result = Self {
+            config_path: "trace.config".to_owned(),
+            overrides: vec![],
+            print_config: None,
+            sweeps: vec![],
+            sweep_format: SweepFormat::Table,
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            trace_options: TraceOptions::default(),
+            write_trace: None,
+            trace_path: None,
+        };
+
+        // Without --trace-format, the format of the trace is chosen by its extension.
+        let mut trace_format = None;
+        // After "--", the argument is the trace even if it looks like an option or a subcommand.
+        let mut options_ended = false;
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                _ if options_ended && result.trace_path.is_none() => result.trace_path = Some(arg),
+                _ if options_ended => return Err(format!("unexpected argument \"{arg}\"")),
+                "--" => options_ended = true,
+                "--config" | "-c" => {
+                    result.config_path = args.next().ok_or("--config expects a path")?;
+                }
+                "--set" | "-s" => {
+                    result.overrides.push(args.next().ok_or("--set expects key=value")?);
+                }
+                "--print-config" => {
+                    let name = args.next().ok_or("--print-config expects a format")?;
+                    result.print_config = Some(
+                        ConfigFormat::from_name(&name)
+                            .ok_or(format!("unknown config format \"{name}\""))?,
+                    );
+                }
+                "--sweep" => {
+                    result.sweeps.push(args.next().ok_or("--sweep expects key=values")?);
+                }
+                "--csv" => result.sweep_format = SweepFormat::Csv,
+                "--lenient" => result.trace_options.mode = TraceMode::Lenient,
+                "--drop-fetches" => result.trace_options.drop_fetches = true,
+                "--trace-format" => {
+                    let name = args.next().ok_or("--trace-format expects a format")?;
+                    trace_format = Some(
+                        TraceFormat::from_name(&name)
+                            .ok_or(format!("unknown trace format \"{name}\""))?,
+                    );
+                }
+                "--write-trace" => {
+                    let name = args.next().ok_or("--write-trace expects a format")?;
+                    result.write_trace = Some(
+                        TraceFormat::from_name(&name)
+                            .ok_or(format!("unknown trace format \"{name}\""))?,
+                    );
+                }
+                "--threads" => {
+                    let count = args.next().ok_or("--threads expects a number")?;
+                    result.threads = count
+                        .parse()
+                        .map_err(|_| format!("expected a number of threads, got \"{count}\""))?;
+                }
+                "--help" | "-h" => {
+                    println!(
+                        "{USAGE}\n\nkeys for --set: {}\n\
+                         keys for each cache level (dc, l2, l3, ..., and ic for the instruction cache): {}",
+                        SimulatorConfig::KEYS.join(", "),
+                        SimulatorConfig::LEVEL_KEYS.join(", ")
+                    );
+                    std::process::exit(0);
+                }
+                _ if arg.starts_with("--config=") => {
+                    result.config_path = arg["--config=".len()..].to_owned();
+                }
+                _ if arg.starts_with("--set=") => {
+                    result.overrides.push(arg["--set=".len()..].to_owned());
+                }
+                _ if arg.starts_with("--sweep=") => {
+                    result.sweeps.push(arg["--sweep=".len()..].to_owned());
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option \"{arg}\"")),
+                _ if result.trace_path.is_none() => result.trace_path = Some(arg),
+                _ => return Err(format!("unexpected argument \"{arg}\"")),
+            }
+        }
+        result.trace_options.format = trace_format.unwrap_or_else(|| {
+            result
+                .trace_path
+                .as_deref()
+                .map_or(TraceFormat::Native, TraceFormat::from_path)
+        });
+        Ok(result)
+    }
+}
+
+/// Parse the arguments after `tracegen`: a pattern, its parameters, and the format to write the trace in.
+fn parse_tracegen(mut args: impl Iterator<Item = String>) -> Result<(TraceGenerator, TraceFormat), String> {
+    let name = args.next().ok_or("tracegen expects a pattern")?;
+    if name == "--help" || name == "-h" {
+        println!(
+            "{USAGE}\n\npatterns: {}\nkeys for tracegen: {}",
+            Pattern::NAMES.join(", "),
+            TraceGenerator::KEYS.join(", ")
+        );
+        std::process::exit(0);
+    }
+    let pattern = Pattern::from_name(&name)
+        .ok_or_else(|| TraceGenError::UnknownPattern(name).to_string())?;
+    let mut generator = TraceGenerator::new(pattern);
+    let mut format = TraceFormat::Native;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--write-trace" => {
+                let name = args.next().ok_or("--write-trace expects a format")?;
+                format = TraceFormat::from_name(&name)
+                    .ok_or(format!("unknown trace format \"{name}\""))?;
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option \"{arg}\"")),
+            _ => generator.apply_parameter(&arg).map_err(|e| e.to_string())?,
+        }
+    }
+    Ok((generator, format))
+}
+
+/// The options of the `trace` subcommand.
+struct TraceArgs {
+    /// The transforms to apply to the trace, in order.
+    transforms: Vec<String>,
+    /// How to read the trace.
+    trace_options: TraceOptions,
+    /// The format to write the transformed trace in.
+    write_trace: TraceFormat,
+    /// The path of the trace file. If this is not given, the trace is read from stdin.
+    trace_path: Option<String>,
+}
+
+impl TraceArgs {
+    /// Parse the arguments after `trace`.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut result = Self {
+            transforms: vec![],
+            trace_options: TraceOptions::default(),
+            write_trace: TraceFormat::Native,
+            trace_path: None,
+        };
+        let mut trace_format = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--apply" | "-a" => {
+                    result.transforms.push(args.next().ok_or("--apply expects a transform")?);
+                }
+                "--lenient" => result.trace_options.mode = TraceMode::Lenient,
+                "--drop-fetches" => result.trace_options.drop_fetches = true,
+                "--trace-format" => {
+                    let name = args.next().ok_or("--trace-format expects a format")?;
+                    trace_format = Some(
+                        TraceFormat::from_name(&name)
+                            .ok_or(format!("unknown trace format \"{name}\""))?,
+                    );
+                }
+                "--write-trace" => {
+                    let name = args.next().ok_or("--write-trace expects a format")?;
+                    result.write_trace = TraceFormat::from_name(&name)
+                        .ok_or(format!("unknown trace format \"{name}\""))?;
+                }
+                "--help" | "-h" => {
+                    println!("{USAGE}\n\ntransforms: {}", Transform::SYNTAX.join(", "));
+                    std::process::exit(0);
+                }
+                _ if arg.starts_with("--apply=") => {
+                    result.transforms.push(arg["--apply=".len()..].to_owned());
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option \"{arg}\"")),
+                _ if result.trace_path.is_none() => result.trace_path = Some(arg),
+                _ => return Err(format!("unexpected argument \"{arg}\"")),
+            }
+        }
+        result.trace_options.format = trace_format.unwrap_or_else(|| {
+            result
+                .trace_path
+                .as_deref()
+                .map_or(TraceFormat::Native, TraceFormat::from_path)
+        });
+        Ok(result)
+    }
+}
+
+/// Read a trace, apply a chain of transforms to it, and write the result to stdout.
+fn transform_trace(args: TraceArgs) {
+    let trace_name = args.trace_path.as_deref().unwrap_or("stdin");
+    let fail = |e: &dyn std::fmt::Display| -> ! {
+        eprintln!("{trace_name}: {e}");
+        std::process::exit(1);
+    };
+    let transforms = args
+        .transforms
+        .iter()
+        .map(|text| Transform::parse(text))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(1);
+        });
+    let input = open_trace(args.trace_path.as_deref()).unwrap_or_else(|e| fail(&e));
+    let trace = Trace::from_reader(input, args.trace_options).unwrap_or_else(|e| fail(&e));
+    report_skipped(&trace.skipped, trace.skipped_lines);
+    let trace = trace.transform(&transforms).unwrap_or_else(|e| fail(&e));
+    info!("Writing {} accesses", trace.len());
+    if let Err(e) = trace
+        .write(BufWriter::new(std::io::stdout().lock()), args.write_trace)
+        .and_then(|mut stdout| stdout.flush())
+    {
+        eprintln!("could not write output: {e}");
+        std::process::exit(1);
+    }
+}
+
+/// Open the trace file at the path, or stdin if there isn't one.
+fn open_trace(path: Option<&str>) -> Result<Box<dyn Read>, String> {
+    match path {
+        Some(filename) => {
+            info!("Reading trace from file \"{}\"...", filename);
+            match File::open(filename) {
+                Ok(file) => Ok(Box::new(file)),
+                Err(e) => Err(format!("could not open \"{filename}\": {e}")),
+            }
+        }
+        None => {
+            info!("Reading trace from stdin...");
+            Ok(Box::new(std::io::stdin()))
+        }
+    }
+}
 
 fn main() {
     env_logger::init();
 
-    let config = SimulatorConfig::default();
+    if std::env::args().nth(1).as_deref() == Some("tracegen") {
+        let (generator, format) = parse_tracegen(std::env::args().skip(2)).unwrap_or_else(|e| {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(1);
+        });
+        let trace = generator.generate().unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(1);
+        });
+        info!("Generated {} accesses", trace.len());
+        if let Err(e) = trace
+            .write(BufWriter::new(std::io::stdout().lock()), format)
+            .and_then(|mut stdout| stdout.flush())
+        {
+            eprintln!("could not write output: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("trace") {
+        let args = TraceArgs::parse(std::env::args().skip(2)).unwrap_or_else(|e| {
+            eprintln!("{e}\n{USAGE}");
+            std::process::exit(1);
+        });
+        transform_trace(args);
+        return;
+    }
+
+    let args = Args::parse().unwrap_or_else(|e| {
+        eprintln!("{e}\n{USAGE}");
+        std::process::exit(1);
+    });
+
+    let trace_name = args.trace_path.as_deref().unwrap_or("stdin");
+    let fail = |e: &dyn std::fmt::Display| -> ! {
+        eprintln!("{trace_name}: {e}");
+        std::process::exit(1);
+    };
+    let write_failed = |e: std::io::Error| -> ! {
+        eprintln!("could not write output: {e}");
+        std::process::exit(1);
+    };
+    let simulation_failed = |e: SinkError<std::io::Error>| -> ! {
+        match e {
+            SinkError::Simulator(e) => fail(&e),
+            SinkError::Sink(e) => write_failed(e),
+        }
+    };
+    let input = open_trace(args.trace_path.as_deref()).unwrap_or_else(|e| fail(&e));
+
+    // Converting a trace doesn't need a configuration, so it's done before one is read.
+    if let Some(format) = args.write_trace {
+        let mut reader = TraceReader::new(input, args.trace_options);
+        let stdout = BufWriter::new(std::io::stdout().lock());
+        let mut writer = TraceWriter::new(stdout, format).unwrap_or_else(|e| write_failed(e));
+        let mut trace_error = None;
+        for operation in reader.by_ref() {
+            match operation {
+                Ok(operation) => writer.write(&operation).unwrap_or_else(|e| write_failed(e)),
+                Err(e) => trace_error = Some(e),
+            }
+        }
+        // Keep what was converted before a bad line, so it can be looked at.
+        writer.finish().unwrap_or_else(|e| write_failed(e));
+        if let Some(e) = trace_error {
+            fail(&e);
+        }
+        report_skipped(reader.skipped(), reader.skipped_lines());
+        return;
+    }
 
-    // Check for command line arguments
-    let args: Vec<String> = std::env::args().collect();
-    let trace = if args.len() > 1 {
-        let filename = &args[1];
-        info!("Reading trace from file \"{}\"...", filename);
-        Trace::from_file(filename)
+    let mut config = match SimulatorConfig::from_file(&args.config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}: {e}", args.config_path);
+            std::process::exit(1);
+        }
+    };
+    for assignment in &args.overrides {
+        if let Err(e) = config.apply_override(assignment) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+    // Each configuration in a sweep is validated on its own, since the sweep may fix the base configuration.
+    let sweep = if args.sweeps.is_empty() {
+        None
     } else {
-        info!("Reading trace from stdin...");
-        Trace::from_stdin()
+        let axes = args
+            .sweeps
+            .iter()
+            .map(|assignment| SweepAxis::parse(assignment))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|axes| Sweep::new(config.clone(), axes));
+        match axes {
+            Ok(sweep) => Some(sweep),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
     };
-    info!("Done reading trace");
+    if let (None, Err(errors)) = (&sweep, config.validate()) {
+        for e in errors {
+            eprintln!("{}: {e}", args.config_path);
+        }
+        std::process::exit(1);
+    }
+
+    if let Some(format) = args.print_config {
+        match config.to_string_with_format(format) {
+            Ok(text) => print!("{text}"),
+            Err(e) => {
+                eprintln!("{}: {e}", args.config_path);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(sweep) = sweep {
+        // A sweep simulates the same trace many times, so it's only parsed once.
+        let trace = Trace::from_reader(input, args.trace_options).unwrap_or_else(|e| fail(&e));
+        info!("Done reading trace");
+        report_skipped(&trace.skipped, trace.skipped_lines);
+
+        info!("Simulating {} configurations on {} threads", sweep.len(), args.threads);
+        let points = sweep.run(&trace, args.threads);
+        print!(
+            "{}",
+            SweepReport {
+                sweep: &sweep,
+                points: &points,
+                format: args.sweep_format,
+            }
+        );
+        return;
+    }
+
+    // Otherwise, stream the trace through the simulator, writing each row as it's produced.
+    // Accesses the configuration has no room for are bad lines.
+    let trace_options = TraceOptions {
+        address_limit: config.get_address_limit(),
+        ..args.trace_options
+    };
+    let stdout = BufWriter::new(std::io::stdout().lock());
+    let mut sink = StreamingOutput::new(stdout, &config).unwrap_or_else(|e| write_failed(e));
+
+    // Optimal replacement needs to know the future, so the whole trace is read first.
+    if config.uses_optimal() {
+        let trace = Trace::from_reader(input, trace_options).unwrap_or_else(|e| fail(&e));
+        info!("Done reading trace");
+        report_skipped(&trace.skipped, trace.skipped_lines);
+        let mut simulator = Simulator::from(config);
+        simulator.prepare_optimal(trace.iter()).unwrap_or_else(|e| fail(&e));
+        let output = simulator
+            .simulate_iter_with(trace.iter().copied(), &mut sink)
+            .unwrap_or_else(|e| simulation_failed(e));
+        sink.finish(&output)
+            .and_then(|mut stdout| writeln!(stdout))
+            .unwrap_or_else(|e| write_failed(e));
+        return;
+    }
+
+    let mut reader = TraceReader::new(input, trace_options);
+    let mut trace_error = None;
+    let operations = reader
+        .by_ref()
+        .map_while(|operation| operation.map_err(|e| trace_error = Some(e)).ok());
+    let output = Simulator::from(config)
+        .simulate_iter_with(operations, &mut sink)
+        .unwrap_or_else(|e| simulation_failed(e));
+    // A bad line in strict mode ends the trace early, so there are no statistics to write.
+    if let Some(e) = trace_error {
+        fail(&e);
+    }
+    report_skipped(reader.skipped(), reader.skipped_lines());
+    sink.finish(&output)
+        .and_then(|mut stdout| writeln!(stdout))
+        .unwrap_or_else(|e| write_failed(e));
+}
 
-    let mut sim = Simulator::from(config);
-    println!("{}", sim.simulate(trace));
+/// Warn about the lines of the trace that were skipped in lenient mode.
+/// Only the first few are listed, since a corrupt trace could have millions.
+fn report_skipped(skipped: &[TraceError], count: usize) {
+    const MAX_WARNINGS: usize = 10;
+    for e in skipped.iter().take(MAX_WARNINGS) {
+        eprintln!("warning: skipped {e}");
+    }
+    if count > MAX_WARNINGS {
+        eprintln!("warning: skipped {} more bad lines", count - MAX_WARNINGS);
+    }
 }
@@ -1,8 +1,12 @@
 use super::*;
 use core::fmt::{Display, Formatter, Result as FmtResult};
+use std::{
+    collections::BTreeMap,
+    io::{Result as IoResult, Write},
+};
 
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct SimulatorOutput {
     pub config: SimulatorConfig,
     pub accesses: Vec<AccessOutput>,
@@ -11,47 +15,132 @@ pub struct SimulatorOutput {
     pub tlb_misses: u64,
     pub tlb_hit_ratio: f64,
 
+    /// The hits and misses of the instruction TLB, if there is one.
+    pub itlb_hits: u64,
+    pub itlb_misses: u64,
+
     pub pt_hits: u64,
     pub pt_faults: u64,
     pub pt_hit_ratio: f64,
 
-    pub dc_hits: u64,
-    pub dc_misses: u64,
-    pub dc_hit_ratio: f64,
-    
-    pub l2_hits: u64,
-    pub l2_misses: u64,
-    pub l2_hit_ratio: f64,
+    /// The hits and misses of each level of the cache hierarchy, starting with the data cache.
+    pub caches: Vec<CacheStats>,
+    /// The hits and misses of the instruction cache, if there is one.
+    pub icache: CacheStats,
 
     pub total_reads: u64,
     pub total_writes: u64,
+    pub total_fetches: u64,
     pub ratio_of_reads: f64,
 
+    /// The number of operations that straddled data cache lines, and were split into an access per line.
+    pub line_splits: u64,
+    /// The number of operations that straddled pages.
+    pub page_splits: u64,
+
     /// The number of main memory references
     pub main_memory_refs: u64,
     /// The number of TLB misses
     pub page_table_refs: u64,
     /// This is equal to the number of page faults
     pub disk_refs: u64,
+
+    /// The number of times the trace switched from one address space to another.
+    pub context_switches: u64,
+    /// The statistics of each address space (process) that made accesses, by its ID.
+    pub processes: BTreeMap<u16, ProcessStats>,
+
+    /// The set dueling of each TLB and cache level that uses DRRIP or DIP, by its name in the statistics.
+    pub dueling: Vec<(String, DuelingStats)>,
 }
 
 impl SimulatorOutput {
     pub fn empty(config: SimulatorConfig) -> Self {
+        // This doesn't use `Default`, since the default configuration is read from "trace.config".
         Self {
+            accesses: vec![],
+            tlb_hits: 0,
+            tlb_misses: 0,
+            tlb_hit_ratio: 0.0,
+            itlb_hits: 0,
+            itlb_misses: 0,
+            pt_hits: 0,
+            pt_faults: 0,
+            pt_hit_ratio: 0.0,
+            caches: vec![CacheStats::default(); config.caches.len()],
+            icache: CacheStats::default(),
+            total_reads: 0,
+            total_writes: 0,
+            total_fetches: 0,
+            ratio_of_reads: 0.0,
+            line_splits: 0,
+            page_splits: 0,
+            main_memory_refs: 0,
+            page_table_refs: 0,
+            disk_refs: 0,
+            context_switches: 0,
+            processes: BTreeMap::new(),
+            dueling: vec![],
             config,
-            .. Default::default()
         }
     }
 
+    /// Count an access, and keep its row.
     pub fn add_access(&mut self, access: AccessOutput) {
-        if access.access.is_read() {
-            self.total_reads += 1;
-        } else {
-            self.total_writes += 1;
-        }
+        self.count_access(&access);
         self.accesses.push(access);
     }
 
+    /// Count an access without keeping its row.
+    pub fn count_access(&mut self, access: &AccessOutput) {
+        match access.access.access_type {
+            AccessType::Read => self.total_reads += 1,
+            AccessType::Write => self.total_writes += 1,
+            AccessType::Fetch => self.total_fetches += 1,
+        }
+    }
+
+    /// Count an access in the statistics of the address space that made it.
+    /// `main_memory_refs` is the number of references the access made to main memory,
+    /// including the dirty lines it caused to be written back.
+    pub fn count_process_access(&mut self, access: &AccessOutput, main_memory_refs: u64) {
+        let stats = self.processes.entry(access.access.asid).or_default();
+        match access.access.access_type {
+            AccessType::Read => stats.reads += 1,
+            AccessType::Write => stats.writes += 1,
+            AccessType::Fetch => stats.fetches += 1,
+        }
+        if let Some(hit) = access.tlb_hit {
+            stats.tlb.add(hit);
+        }
+        match access.page_table_hit {
+            Some(hit) if access.tlb_hit != Some(true) => stats.page_table.add(hit),
+            _ => {}
+        }
+        if let Some(level) = access.get_level(0) {
+            stats.l1.add(level.hit);
+        }
+        stats.main_memory_refs += main_memory_refs;
+    }
+
+    pub fn add_context_switch(&mut self) {
+        self.context_switches += 1;
+    }
+
+    /// Did the trace have accesses from more than one address space, or from any but the first?
+    /// Without them, the statistics are laid out as in the reference simulator.
+    pub fn has_processes(&self) -> bool {
+        self.context_switches > 0 || self.processes.keys().any(|&asid| asid != 0)
+    }
+
+    pub fn add_line_split(&mut self) {
+        self.line_splits += 1;
+    }
+
+    pub fn add_page_split(&mut self) {
+        self.page_splits += 1;
+    }
+
     pub fn add_main_memory_access(&mut self) {
         self.main_memory_refs += 1;
     }
@@ -70,6 +159,14 @@ impl SimulatorOutput {
         }
     }
 
+    pub fn add_itlb_access(&mut self, hit: bool) {
+        if hit {
+            self.itlb_hits += 1;
+        } else {
+            self.itlb_misses += 1;
+        }
+    }
+
     pub fn add_page_table_access(&mut self, hit: bool) {
         if !self.config.is_virtual_addresses_enabled() { return }
 
@@ -80,91 +177,305 @@ impl SimulatorOutput {
         }
     }
 
-    pub fn add_dc_access(&mut self, hit: bool) {
+    /// Count an access to the instruction cache.
+    pub fn add_icache_access(&mut self, hit: bool) {
         if hit {
-            self.dc_hits += 1;
+            self.icache.hits += 1;
         } else {
-            self.dc_misses += 1;
+            self.icache.misses += 1;
         }
     }
 
-    pub fn add_l2_access(&mut self, hit: bool) {
-        if !self.config.is_l2_cache_enabled() { return }
+    /// Does the output have the statistics of an instruction TLB or cache, or of any fetches?
+    /// Without them, the statistics are laid out as in the reference simulator.
+    pub fn has_instruction_side(&self) -> bool {
+        self.config.itlb.is_some() || self.config.icache.is_some() || self.total_fetches > 0
+    }
 
+    /// Count a dirty line evicted from the cache level at `depth`, and written back to the level below it.
+    pub fn add_writeback(&mut self, depth: usize) {
+        self.caches[depth].writebacks += 1;
+    }
+
+    /// Count an access to the cache level at `depth`.
+    pub fn add_cache_access(&mut self, depth: usize, hit: bool) {
+        let stats = &mut self.caches[depth];
         if hit {
-            self.l2_hits += 1;
+            stats.hits += 1;
         } else {
-            self.l2_misses += 1;
+            stats.misses += 1;
         }
     }
+}
 
-    pub fn add_l2_accesses(&mut self, count: u64) {
-        if !self.config.is_l2_cache_enabled() { return }
+/// The hits and misses of one level of the cache hierarchy.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// The number of dirty lines evicted from the level, and written back to the level below it
+    /// (or to main memory).
+    pub writebacks: u64,
+}
 
-        self.l2_hits += count;
+impl CacheStats {
+    pub fn add(&mut self, hit: bool) {
+        if hit {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
     }
 }
 
+/// The statistics of the accesses made by one address space (process) of a trace.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub fetches: u64,
+    /// The hits and misses of the TLB that translated each access (data or instruction).
+    pub tlb: CacheStats,
+    /// The hits and faults of the page table, on TLB misses.
+    pub page_table: CacheStats,
+    /// The hits and misses of the first cache level each access went through (data or instruction).
+    pub l1: CacheStats,
+    pub main_memory_refs: u64,
+}
+
+/// The fraction of accesses that hit, or 0 if there were no accesses.
+pub(crate) fn hit_ratio(hits: u64, misses: u64) -> f64 {
+    hits as f64 / ((hits + misses) as f64).max(0.0000001)
+}
+
 impl Display for SimulatorOutput {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        writeln!(f, "{}", self.config)?;
-        writeln!(f,
-            r#"{} Virt.  Page TLB    TLB TLB  PT   Phys        DC  DC          L2  L2
-Address  Page # Off  Tag    Ind Res. Res. Pg # DC Tag Ind Res. L2 Tag Ind Res.
--------- ------ ---- ------ --- ---- ---- ---- ------ --- ---- ------ --- ----"#,
-            if self.config.is_virtual_addresses_enabled() {
-                "Virtual "
-            } else {
-                "Physical"
-            }
-        )?;
-
-        let mut main_mem_accesses = self.main_memory_refs;
+        write!(f, "{}", OutputHeader(&self.config))?;
+        let widths = ColumnWidths::new(&self.config);
         for access in &self.accesses {
-            main_mem_accesses += access.get_main_memory_accesses(&self.config);
-            writeln!(f, "{}", access)?;
+            writeln!(f, "{}", AccessRow(access, widths))?;
         }
+        write!(f, "{}", OutputStatistics(self))
+    }
+}
 
-        
+/// The widths of the columns of the output that hold addresses or parts of them.
+/// Addresses of up to 32 bits get the widths of the reference simulator, and wider ones get wider columns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColumnWidths {
+    /// The address from the trace.
+    pub address: usize,
+    pub virtual_page_number: usize,
+    pub page_offset: usize,
+    pub tlb_tag: usize,
+    pub physical_page_number: usize,
+    /// The tag of every cache level.
+    pub cache_tag: usize,
+}
+
+impl Default for ColumnWidths {
+    /// The widths of the reference simulator.
+    fn default() -> Self {
+        Self {
+            address: 8,
+            virtual_page_number: 6,
+            page_offset: 4,
+            tlb_tag: 6,
+            physical_page_number: 4,
+            cache_tag: 6,
+        }
+    }
+}
+
+impl ColumnWidths {
+    /// The widths for the addresses of a configuration.
+    pub fn new(config: &SimulatorConfig) -> Self {
+        // The number of hex digits an address has beyond those of a 32-bit one.
+        let extra_digits = |bits: u64| bits.div_ceil(4).saturating_sub(ADDRESS_BITS / 4) as usize;
+        let virtual_digits = extra_digits(config.get_virtual_address_bits());
+        let physical_digits = extra_digits(config.get_physical_address_bits());
+        let widths = Self::default();
+        Self {
+            address: widths.address + extra_digits(config.get_trace_address_bits()),
+            virtual_page_number: widths.virtual_page_number + virtual_digits,
+            // The offset is the same in both addresses.
+            page_offset: widths.page_offset + virtual_digits.max(physical_digits),
+            tlb_tag: widths.tlb_tag + virtual_digits,
+            physical_page_number: widths.physical_page_number + physical_digits,
+            cache_tag: widths.cache_tag + physical_digits,
+        }
+    }
+}
+
+/// The configuration summary and column headers that come before the rows of the output.
+pub struct OutputHeader<'a>(pub &'a SimulatorConfig);
+
+impl Display for OutputHeader<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let config = self.0;
+        writeln!(f, "{}", config)?;
+        let ColumnWidths {
+            address,
+            virtual_page_number,
+            page_offset,
+            tlb_tag,
+            physical_page_number,
+            cache_tag,
+        } = ColumnWidths::new(config);
+        let dashes = |width: usize| "-".repeat(width);
+        // The columns up to the physical page number, followed by one group of columns per cache level.
+        let mut header = [
+            format!(
+                "{:<address$} {:<virtual_page_number$} {:<page_offset$} {:<tlb_tag$} TLB TLB  PT   {:<physical_page_number$}",
+                if config.is_virtual_addresses_enabled() {
+                    "Virtual"
+                } else {
+                    "Physical"
+                },
+                "Virt.",
+                "Page",
+                "TLB",
+                "Phys",
+            ),
+            format!(
+                "{:<address$} {:<virtual_page_number$} {:<page_offset$} {:<tlb_tag$} Ind Res. Res. {:<physical_page_number$}",
+                "Address", "Page #", "Off", "Tag", "Pg #",
+            ),
+            format!(
+                "{} {} {} {} --- ---- ---- {}",
+                dashes(address),
+                dashes(virtual_page_number),
+                dashes(page_offset),
+                dashes(tlb_tag),
+                dashes(physical_page_number),
+            ),
+        ];
+        for depth in 0..config.caches.len() {
+            let name = CacheConfig::short_name(depth);
+            // The name heads the index and result columns, next to the tag column's own "Tag" heading.
+            let index_end = cache_tag + 4;
+            header[0] += &format!("{name:>index_end$}{name:>4}  ");
+            header[1] += &format!(" {:>cache_tag$} Ind Res.", format!("{name} Tag"));
+            header[2] += &format!(" {} --- ----", dashes(cache_tag));
+        }
+        for line in &header {
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// The statistics that come after the rows of the output.
+pub struct OutputStatistics<'a>(pub &'a SimulatorOutput);
+
+impl Display for OutputStatistics<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let output = self.0;
         writeln!(f, "\nSimulation statistics\n")?;
-        let hit_ratio = |hits, misses| hits as f64 / ((hits + misses) as f64).max(0.0000001);
 
-        writeln!(f, "dtlb hits        : {}", self.tlb_hits)?;
-        writeln!(f, "dtlb misses      : {}", self.tlb_misses)?;
-        if self.config.is_tlb_enabled() {
-            writeln!(f, "dtlb hit ratio   : {:1.6}\n", hit_ratio(self.tlb_hits, self.tlb_misses))?;
+        writeln!(f, "dtlb hits        : {}", output.tlb_hits)?;
+        writeln!(f, "dtlb misses      : {}", output.tlb_misses)?;
+        if output.config.is_tlb_enabled() {
+            writeln!(f, "dtlb hit ratio   : {:1.6}\n", hit_ratio(output.tlb_hits, output.tlb_misses))?;
         } else {
             writeln!(f, "dtlb hit ratio   : N/A\n")?;
         }
 
-        writeln!(f, "pt hits          : {}", self.pt_hits)?;
-        writeln!(f, "pt faults        : {}", self.pt_faults)?;
+        if output.config.itlb.is_some() {
+            writeln!(f, "itlb hits        : {}", output.itlb_hits)?;
+            writeln!(f, "itlb misses      : {}", output.itlb_misses)?;
+            if output.config.instruction_tlb().is_some() {
+                writeln!(f, "itlb hit ratio   : {:1.6}\n", hit_ratio(output.itlb_hits, output.itlb_misses))?;
+            } else {
+                writeln!(f, "itlb hit ratio   : N/A\n")?;
+            }
+        }
+
+        writeln!(f, "pt hits          : {}", output.pt_hits)?;
+        writeln!(f, "pt faults        : {}", output.pt_faults)?;
         
-        if self.config.is_virtual_addresses_enabled() {
-            writeln!(f, "pt hit ratio     : {:1.6}\n", hit_ratio(self.pt_hits, self.pt_faults))?;
+        if output.config.is_virtual_addresses_enabled() {
+            writeln!(f, "pt hit ratio     : {:1.6}\n", hit_ratio(output.pt_hits, output.pt_faults))?;
         } else {
             writeln!(f, "pt hit ratio     : N/A\n")?;
         }
 
-        writeln!(f, "dc hits          : {}", self.dc_hits)?;
-        writeln!(f, "dc misses        : {}", self.dc_misses)?;
-        writeln!(f, "dc hit ratio     : {:1.6}\n", hit_ratio(self.dc_hits, self.dc_misses))?;
+        if output.config.icache.is_some() {
+            let stats = output.icache;
+            writeln!(f, "ic hits          : {}", stats.hits)?;
+            writeln!(f, "ic misses        : {}", stats.misses)?;
+            if output.config.instruction_cache().is_some() {
+                writeln!(f, "ic hit ratio     : {:1.6}\n", hit_ratio(stats.hits, stats.misses))?;
+            } else {
+                writeln!(f, "ic hit ratio     : N/A\n")?;
+            }
+        }
 
-        writeln!(f, "L2 hits          : {}", self.l2_hits)?;
-        writeln!(f, "L2 misses        : {}", self.l2_misses)?;
-        if self.config.is_l2_cache_enabled() {
-            writeln!(f, "L2 hit ratio     : {:1.6}\n", hit_ratio(self.l2_hits, self.l2_misses))?;
-        } else {
-            writeln!(f, "L2 hit ratio     : N/A\n")?;
+        for (depth, (cache, stats)) in output.config.caches.iter().zip(&output.caches).enumerate() {
+            let name = CacheConfig::stats_name(depth);
+            writeln!(f, "{:<17}: {}", format!("{name} hits"), stats.hits)?;
+            writeln!(f, "{:<17}: {}", format!("{name} misses"), stats.misses)?;
+            // Only a write-back level has dirty lines to write back, and a count of 0 is left out
+            // to match the reference output.
+            if stats.writebacks > 0 {
+                writeln!(f, "{:<17}: {}", format!("{name} writebacks"), stats.writebacks)?;
+            }
+            if cache.enabled {
+                writeln!(f, "{:<17}: {:1.6}\n", format!("{name} hit ratio"), hit_ratio(stats.hits, stats.misses))?;
+            } else {
+                writeln!(f, "{:<17}: N/A\n", format!("{name} hit ratio"))?;
+            }
         }
 
-        writeln!(f, "Total reads      : {}", self.total_reads)?;
-        writeln!(f, "Total writes     : {}", self.total_writes)?;
-        writeln!(f, "Ratio of reads   : {:1.6}\n", hit_ratio(self.total_reads, self.total_writes))?;
+        for (name, stats) in &output.dueling {
+            let sets = |sets: &[usize]| sets.iter().map(ToString::to_string).collect::<Vec<_>>().join(" ");
+            let history = stats.history.iter().map(ToString::to_string).collect::<Vec<_>>().join(" ");
+            for (policy, leaders) in stats.policies.iter().zip(&stats.leaders) {
+                writeln!(f, "{:<17}: {}", format!("{name} {policy} leaders"), sets(leaders))?;
+            }
+            writeln!(f, "{:<17}: {} of {} ({})", format!("{name} PSEL"), stats.psel, stats.psel_max, stats.follower_policy())?;
+            writeln!(f, "{:<17}: {history} (every {} accesses)\n", format!("{name} PSEL history"), stats.history_interval)?;
+        }
 
-        writeln!(f, "main memory refs : {}", main_mem_accesses)?;
-        writeln!(f, "page table refs  : {}", self.pt_hits + self.pt_faults)?;
-        write!(f, "disk refs        : {}", self.pt_faults)?;
+        writeln!(f, "Total reads      : {}", output.total_reads)?;
+        writeln!(f, "Total writes     : {}", output.total_writes)?;
+        if output.has_instruction_side() {
+            writeln!(f, "Total fetches    : {}", output.total_fetches)?;
+        }
+        writeln!(f, "Ratio of reads   : {:1.6}\n", hit_ratio(output.total_reads, output.total_writes))?;
+
+        // Traces without sizes never split, so leave these out to match the reference output.
+        if output.line_splits > 0 || output.page_splits > 0 {
+            writeln!(f, "line splits      : {}", output.line_splits)?;
+            writeln!(f, "page splits      : {}\n", output.page_splits)?;
+        }
+
+        writeln!(f, "main memory refs : {}", output.main_memory_refs)?;
+        writeln!(f, "page table refs  : {}", output.pt_hits + output.pt_faults)?;
+        write!(f, "disk refs        : {}", output.pt_faults)?;
+
+        if output.has_processes() {
+            write!(f, "\n\ncontext switches : {}", output.context_switches)?;
+            for (asid, stats) in &output.processes {
+                write!(f, "\n\nProcess {asid} statistics\n\n")?;
+                writeln!(f, "reads            : {}", stats.reads)?;
+                writeln!(f, "writes           : {}", stats.writes)?;
+                if output.has_instruction_side() {
+                    writeln!(f, "fetches          : {}", stats.fetches)?;
+                }
+                if output.config.is_tlb_enabled() {
+                    writeln!(f, "tlb hits         : {}", stats.tlb.hits)?;
+                    writeln!(f, "tlb misses       : {}", stats.tlb.misses)?;
+                }
+                if output.config.is_virtual_addresses_enabled() {
+                    writeln!(f, "pt hits          : {}", stats.page_table.hits)?;
+                    writeln!(f, "pt faults        : {}", stats.page_table.misses)?;
+                }
+                writeln!(f, "l1 hits          : {}", stats.l1.hits)?;
+                writeln!(f, "l1 misses        : {}", stats.l1.misses)?;
+                write!(f, "main memory refs : {}", stats.main_memory_refs)?;
+            }
+        }
 
         Ok(())
     }
@@ -172,7 +483,58 @@ Address  Page # Off  Tag    Ind Res. Res. Pg # DC Tag Ind Res. L2 Tag Ind Res.
 
 
 
+/// Receives the row for each access as soon as it is simulated.
+/// See [`Simulator::simulate_iter_with`].
+pub trait AccessSink {
+    type Error;
+
+    fn record(&mut self, access: &AccessOutput) -> Result<(), Self::Error>;
+}
+
+/// Writes the output of a simulation as it is produced: the header when it is created,
+/// each row as it is recorded, and the statistics when it is finished.
+/// Together, these are the same text as the `Display` of a whole [`SimulatorOutput`].
+pub struct StreamingOutput<W: Write> {
+    writer: W,
+    widths: ColumnWidths,
+}
+
+impl<W: Write> StreamingOutput<W> {
+    /// Write the configuration summary and column headers.
+    pub fn new(mut writer: W, config: &SimulatorConfig) -> IoResult<Self> {
+        write!(writer, "{}", OutputHeader(config))?;
+        Ok(Self {
+            writer,
+            widths: ColumnWidths::new(config),
+        })
+    }
+
+    /// Write the statistics after the last row, and return the writer.
+    pub fn finish(mut self, output: &SimulatorOutput) -> IoResult<W> {
+        write!(self.writer, "{}", OutputStatistics(output))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> AccessSink for StreamingOutput<W> {
+    type Error = std::io::Error;
+
+    fn record(&mut self, access: &AccessOutput) -> IoResult<()> {
+        writeln!(self.writer, "{}", AccessRow(access, self.widths))
+    }
+}
+
+/// The outcome of an access at one level of the cache hierarchy.
 #[derive(Clone, Copy, Debug)]
+pub struct LevelAccess {
+    /// The address into the cache level.
+    pub address: BlockAddress,
+    /// Was the access a hit?
+    pub hit: bool,
+}
+
+#[derive(Clone, Debug)]
 pub struct AccessOutput {
     pub access: Operation,
 
@@ -192,52 +554,12 @@ pub struct AccessOutput {
     pub page_table_hit: Option<bool>,
     /// Physical page number of the address.
     pub physical_page_number: u64,
-    /// The address into the data cache
-    pub dc_address: BlockAddress,
-    /// Was the data cache access a hit?
-    pub dc_hit: bool,
-    /// The address into the L2 cache
-    pub l2_address: Option<BlockAddress>,
-    /// Was the L2 cache acces a hit? (if the l2 cache is enabled)
-    pub l2_hit: Option<bool>,
+    /// The access at each level of the cache hierarchy, starting with the data cache.
+    /// A level is `None` if it is disabled, or if the levels above it handled the access.
+    pub caches: Vec<Option<LevelAccess>>,
 }
 
 impl AccessOutput {
-    pub fn get_main_memory_accesses(&self, config: &SimulatorConfig) -> u64 {
-        if self.access.is_read() {
-            if self.dc_hit {
-                0
-            } else if self.l2_hit == Some(true) {
-                0
-            } else {
-                1
-            }
-        } else {
-            // Access is a write
-            if self.dc_hit {
-                if config.data_cache.is_write_through() && config.l2_cache.is_write_through() {
-                    1
-                } else if config.data_cache.is_write_through() && config.l2_cache.is_write_back() {
-                    0
-                } else if config.data_cache.is_write_back() && config.l2_cache.is_write_through() {
-                    0
-                } else if config.data_cache.is_write_back() && config.l2_cache.is_write_back() {
-                    0
-                } else {
-                    1
-                }
-            } else if self.l2_hit == Some(true) {
-                if config.l2_cache.is_write_through() {
-                    1
-                } else {
-                    0
-                }
-            } else {
-                1
-            }
-        }
-    }
-
     pub fn get_virtual_address(&self) -> Option<u64> {
         self.virtual_address
     }
@@ -266,88 +588,151 @@ impl AccessOutput {
         self.physical_page_number
     }
 
+    /// Get the access at the cache level at `depth`, if it reached that level.
+    pub fn get_level(&self, depth: usize) -> Option<LevelAccess> {
+        self.caches.get(depth).copied().flatten()
+    }
+
     pub fn get_dc_tag(&self) -> u64 {
-        self.dc_address.tag
+        self.get_dc().address.tag
     }
 
     pub fn get_dc_index(&self) -> u64 {
-        self.dc_address.index
+        self.get_dc().address.index
     }
 
     pub fn get_dc_hit(&self) -> bool {
-        self.dc_hit
+        self.get_dc().hit
+    }
+
+    fn get_dc(&self) -> LevelAccess {
+        self.get_level(0).expect("every access goes through the data cache")
     }
 
     pub fn get_l2_tag(&self) -> Option<u64> {
-        self.l2_address.map(|addr| addr.tag)
+        self.get_level(1).map(|level| level.address.tag)
     }
 
     pub fn get_l2_index(&self) -> Option<u64> {
-        self.l2_address.map(|addr| addr.index)
+        self.get_level(1).map(|level| level.address.index)
     }
 
     pub fn get_l2_hit(&self) -> Option<bool> {
-        self.l2_hit
+        self.get_level(1).map(|level| level.hit)
     }
 }
 
 impl Display for AccessOutput {
+    /// Write the row of the access with the column widths of the reference simulator.
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", AccessRow(self, ColumnWidths::default()))
+    }
+}
+
+/// The row of an access in the output, with its addresses in columns of the given widths.
+pub struct AccessRow<'a>(pub &'a AccessOutput, pub ColumnWidths);
+
+impl Display for AccessRow<'_> {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        match self.get_virtual_address() {
-            Some(addr) => write!(f, "{addr:08x}"),
-            None => write!(f, "{:08x}", self.physical_address),
+        let AccessRow(access, widths) = self;
+        let ColumnWidths {
+            address,
+            virtual_page_number,
+            page_offset,
+            tlb_tag,
+            physical_page_number,
+            cache_tag,
+        } = *widths;
+        match access.get_virtual_address() {
+            Some(addr) => write!(f, "{addr:0address$x}"),
+            None => write!(f, "{:0address$x}", access.physical_address),
         }?;
         write!(f, " ")?;
-        match self.virtual_page_number {
-            Some(vpn) => write!(f, "{vpn:>6x}"),
-            None => write!(f, "{}", " ".repeat(6)),
+        match access.virtual_page_number {
+            Some(vpn) => write!(f, "{vpn:>virtual_page_number$x}"),
+            None => write!(f, "{}", " ".repeat(virtual_page_number)),
         }?;
-        write!(f, " {:>4x} ", self.page_offset)?;
+        write!(f, " {:>page_offset$x} ", access.page_offset)?;
 
-        match self.get_tlb_tag() {
-            Some(tag) => write!(f, "{tag:>6x}"),
-            None => write!(f, "{}", " ".repeat(6)),
+        match access.get_tlb_tag() {
+            Some(tag) => write!(f, "{tag:>tlb_tag$x}"),
+            None => write!(f, "{}", " ".repeat(tlb_tag)),
         }?;
         write!(f, " ")?;
-        match self.get_tlb_index() {
+        match access.get_tlb_index() {
             Some(idx) => write!(f, "{idx:>3x}"),
             None => write!(f, "{}", " ".repeat(3)),
         }?;
         write!(f, " ")?;
-        match self.tlb_hit {
+        match access.tlb_hit {
             Some(hit) => write!(f, "{}", if hit { "hit " } else { "miss" }),
             _ => write!(f, "{}", " ".repeat(4)),
         }?;
         write!(f, " ")?;
-        match self.page_table_hit {
-            Some(hit) if self.tlb_hit != Some(true) => {
+        match access.page_table_hit {
+            Some(hit) if access.tlb_hit != Some(true) => {
                 write!(f, "{}", if hit { "hit " } else { "miss" })
             }
             _ => write!(f, "{}", " ".repeat(4)),
         }?;
         write!(
             f,
-            " {:>4x} {:>6x} {:>3x} {:>4} ",
-            self.physical_page_number,
-            self.get_dc_tag(),
-            self.get_dc_index(),
-            if self.dc_hit { "hit " } else { "miss" }
+            " {:>physical_page_number$x} {:>cache_tag$x} {:>3x} {:>4} ",
+            access.physical_page_number,
+            access.get_dc_tag(),
+            access.get_dc_index(),
+            if access.get_dc_hit() { "hit " } else { "miss" }
         )?;
-        if self.l2_hit == None {
-            // return write!(f, " ");
-            return Ok(());
-        }
-        match self.get_l2_tag() {
-            Some(tag) => write!(f, "{tag:>6x} "),
-            _ => Ok(()),
-        }?;
-        match self.get_l2_index() {
-            Some(idx) => write!(f, "{idx:>3x} "),
-            _ => Ok(()),
-        }?;
-        match self.l2_hit {
-            Some(hit) => write!(f, "{}", if hit { "hit " } else { "miss" }),
-            _ => Ok(()),
+
+        // The columns of the levels below the data cache end at the last level the access reached.
+        let reached = access.caches.iter().rposition(Option::is_some).unwrap_or(0);
+        for depth in 1..=reached {
+            if depth > 1 {
+                write!(f, " ")?;
+            }
+            match access.caches[depth] {
+                Some(LevelAccess { address, hit }) => write!(
+                    f,
+                    "{:>cache_tag$x} {:>3x} {}",
+                    address.tag,
+                    address.index,
+                    if hit { "hit " } else { "miss" }
+                ),
+                None => write!(f, "{}", " ".repeat(cache_tag + 9)),
+            }?;
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn widths(virtual_bits: u64, physical_bits: u64) -> ColumnWidths {
+        let config = SimulatorConfig::builder()
+            .address_bits(virtual_bits, physical_bits)
+            .build()
+            .unwrap();
+        ColumnWidths::new(&config)
+    }
+
+    #[test]
+    fn addresses_of_up_to_32_bits_get_the_reference_widths() {
+        assert_eq!(widths(32, 32), ColumnWidths::default());
+        assert_eq!(widths(32, 29), ColumnWidths::default());
+        assert_eq!(widths(32, 29).page_offset, 4);
+    }
+
+    #[test]
+    fn wider_addresses_get_a_column_digit_per_four_bits() {
+        let widths = widths(48, 40);
+        assert_eq!(widths.address, 12);
+        assert_eq!(widths.virtual_page_number, 10);
+        assert_eq!(widths.tlb_tag, 10);
+        // The offset column fits the wider of the two addresses.
+        assert_eq!(widths.page_offset, 8);
+        assert_eq!(widths.physical_page_number, 6);
+        assert_eq!(widths.cache_tag, 8);
     }
 }
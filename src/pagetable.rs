@@ -1,6 +1,7 @@
 use super::SimulatorConfig;
 
 use log::{error, info, trace};
+use std::collections::BTreeMap;
 
 #[derive(Clone, Debug)]
 pub struct PageTable {
@@ -10,8 +11,13 @@ pub struct PageTable {
     physical_pages: u64,
     /// Page size in bytes.
     page_size: u64,
-    /// The page table entries.
+    /// The page table entries of the current address space.
     entries: Vec<Option<PageTableEntry>>,
+    /// The address space of `entries`.
+    asid: u16,
+    /// The page table entries of the other address spaces that have been switched to.
+    /// Every address space maps its own virtual pages, but they all share the physical pages.
+    other_address_spaces: BTreeMap<u16, Vec<Option<PageTableEntry>>>,
     /// Allocated physical pages.
     allocated_physical_pages: u64,
     /// Physical page bookkeeping.
@@ -19,15 +25,37 @@ pub struct PageTable {
 }
 
 impl PageTable {
+    /// Get the page table entries of every address space.
     pub fn get_entries(&self) -> Vec<PageTableEntry> {
         self.entries
-            .clone()
-            .into_iter()
-            .filter(|entry| entry.is_some())
-            .map(|entry| entry.unwrap())
+            .iter()
+            .chain(self.other_address_spaces.values().flatten())
+            .flatten()
+            .copied()
             .collect()
     }
 
+    /// Get the address space being translated for.
+    pub fn get_asid(&self) -> u16 {
+        self.asid
+    }
+
+    /// Switch to translating the virtual addresses of another address space.
+    /// An address space starts with no pages mapped.
+    pub fn switch_to(&mut self, asid: u16) {
+        if asid == self.asid {
+            return;
+        }
+        trace!("Switching page table from address space {} to {asid}", self.asid);
+        let entries = self
+            .other_address_spaces
+            .remove(&asid)
+            .unwrap_or_else(|| vec![None; self.virtual_pages as usize]);
+        let previous = std::mem::replace(&mut self.entries, entries);
+        self.other_address_spaces.insert(self.asid, previous);
+        self.asid = asid;
+    }
+
     fn new(virtual_pages: u64, physical_pages: u64, page_size: u64) -> Self {
         info!("Creating new page table with {virtual_pages} virtual pages, {physical_pages} physical pages, and a page size of {page_size}");
         let entries = vec![None; virtual_pages as usize];
@@ -36,6 +64,8 @@ impl PageTable {
             physical_pages,
             page_size,
             entries,
+            asid: 0,
+            other_address_spaces: BTreeMap::new(),
             allocated_physical_pages: 0,
             physical_page_bookkeeping: vec![0; physical_pages as usize],
         }
@@ -174,11 +204,13 @@ impl PageTable {
         self.get_last_access_time_page_number(physical_page_number) == 0
     }
 
-    /// Invalidate all the page table entries that are mapped to a given physical page number.
+    /// Invalidate all the page table entries that are mapped to a given physical page number,
+    /// in every address space.
     pub fn invalidate_page_number(&mut self, physical_page_number: u64) {
         // Go through all the entries that reference the physical page number and invalidate them.
         trace!("Invalidating entries for page #{physical_page_number:x}");
-        for entry in self.entries.iter_mut() {
+        let address_spaces = std::iter::once(&mut self.entries).chain(self.other_address_spaces.values_mut());
+        for entry in address_spaces.flatten() {
             if let Some(inner_entry) = entry {
                 if inner_entry.get_physical_page_number() == physical_page_number {
                     trace!(
@@ -263,12 +295,15 @@ impl PageTable {
             }
         }
         // Create the new page table entry in the free'd slot
-        self.entries[index] = Some(PageTableEntry::new(
-            physical_page_number << self.get_offset_bits(),
-            virtual_address,
-            self.page_size,
-            current_access_time,
-        ));
+        self.entries[index] = Some(PageTableEntry {
+            asid: self.asid,
+            ..PageTableEntry::new(
+                physical_page_number << self.get_offset_bits(),
+                virtual_address,
+                self.page_size,
+                current_access_time,
+            )
+        });
         // Mark the page table entry as accessed.
         self.mark_virtual_access(virtual_address, current_access_time);
         trace!(
@@ -284,7 +319,7 @@ impl PageTable {
         let entry = self.get_entry_mut(virtual_address);
         if let Some(entry) = entry {
             entry.last_access_time = current_access_time;
-            let entry = entry.clone();
+            let entry = *entry;
             trace!("Marking virtual access for address {virtual_address:x} at time={current_access_time}");
             self.mark_physical_access(entry.get_physical_address(), current_access_time);
         } else {
@@ -346,6 +381,8 @@ pub struct PageTableEntry {
     last_access_time: u64,
     /// Page size
     page_size: u64,
+    /// The address space whose virtual address is mapped.
+    asid: u16,
 }
 
 impl PageTableEntry {
@@ -365,6 +402,7 @@ impl PageTableEntry {
             virtual_address,
             page_size,
             last_access_time: current_access_time,
+            asid: 0,
         }
     }
 
@@ -376,6 +414,10 @@ impl PageTableEntry {
         self.virtual_address
     }
 
+    pub fn get_asid(&self) -> u16 {
+        self.asid
+    }
+
     pub fn get_virtual_page_number(&self) -> u64 {
         (self.virtual_address & !(self.page_size - 1)) >> self.page_size.trailing_zeros()
     }
@@ -388,3 +430,48 @@ impl PageTableEntry {
         self.page_size.trailing_zeros() as u64
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A page table of 8 virtual pages and 2 physical pages, each 256 bytes.
+    fn page_table() -> PageTable {
+        let config = SimulatorConfig::builder().page_table_geometry(8, 2, 256).build().unwrap();
+        PageTable::new_from_config(&config)
+    }
+
+    #[test]
+    fn address_spaces_map_their_pages_separately() {
+        let mut page_table = page_table();
+        let (first, hit) = page_table.translate(0x112, 1).unwrap();
+        assert!(!hit);
+        page_table.switch_to(1);
+        assert_eq!(page_table.get_asid(), 1);
+        let (second, hit) = page_table.translate(0x112, 2).unwrap();
+        assert!(!hit);
+        assert_ne!(first, second);
+        assert_eq!(first & 0xff, second & 0xff);
+
+        page_table.switch_to(0);
+        assert_eq!(page_table.translate(0x112, 3).unwrap(), (first, true));
+        let mut asids: Vec<_> = page_table.get_entries().iter().map(PageTableEntry::get_asid).collect();
+        asids.sort();
+        assert_eq!(asids, [0, 1]);
+    }
+
+    #[test]
+    fn address_spaces_share_the_physical_pages() {
+        let mut page_table = page_table();
+        page_table.translate(0x000, 1).unwrap();
+        page_table.switch_to(1);
+        page_table.translate(0x100, 2).unwrap();
+        // Both physical pages are in use, so the least recently used one (of address space 0) is evicted.
+        page_table.translate(0x200, 3).unwrap();
+        assert_eq!(page_table.get_entries().len(), 2);
+        assert!(page_table.get_entries().iter().all(|entry| entry.get_asid() == 1));
+
+        page_table.switch_to(0);
+        assert!(!page_table.translate(0x000, 4).unwrap().1);
+    }
+}
@@ -1,13 +1,65 @@
 use super::*;
+use core::fmt::{Display, Formatter, Result as FmtResult};
 use log::trace;
 
+/// An error that stops a simulation part way through the trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SimulatorError {
+    /// The page table has no entry for a virtual address, because it is past the virtual pages.
+    Untranslatable { address: u64, asid: u16 },
+    /// A TLB or cache level uses optimal replacement, but [`Simulator::prepare_optimal`] wasn't called.
+    MissingFuture { name: String },
+}
+
+impl Display for SimulatorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Untranslatable { address, asid } => write!(
+                f,
+                "the page table of address space {asid} can't translate virtual address {address:x}"
+            ),
+            Self::MissingFuture { name } => write!(
+                f,
+                "{name} uses optimal replacement, but wasn't given the future accesses of the trace"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SimulatorError {}
+
+/// An error from a simulation that passes its rows to an [`AccessSink`]:
+/// either the simulation stopped, or the sink failed to record a row.
+#[derive(Debug)]
+pub enum SinkError<E> {
+    Simulator(SimulatorError),
+    Sink(E),
+}
+
+impl<E: Display> Display for SinkError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Simulator(e) => write!(f, "{e}"),
+            Self::Sink(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl<E: std::error::Error> std::error::Error for SinkError<E> {}
+
 pub struct Simulator {
-    l2: Option<L2Cache>,
-    dc: DataCache,
+    /// The enabled levels of the cache hierarchy, starting with the data cache.
+    caches: Vec<CacheLevel>,
+    /// The instruction cache, if there is one. It is backed by the second level of `caches`.
+    icache: Option<CacheLevel>,
     tlb: Option<TLBCache>,
+    /// The instruction TLB, if there is one.
+    itlb: Option<TLBCache>,
     page_table: Option<PageTable>,
     config: SimulatorConfig,
     time: u64,
+    /// The address space of the last access.
+    asid: u16,
     output: SimulatorOutput,
 }
 
@@ -15,18 +67,27 @@ impl From<SimulatorConfig> for Simulator {
     fn from(config: SimulatorConfig) -> Self {
         Self {
             output: SimulatorOutput::empty(config.clone()),
-            l2: config
-                .is_l2_cache_enabled()
-                .then_some(L2Cache::new_from_config(&config)),
-            dc: DataCache::new_from_config(&config),
+            caches: config
+                .enabled_caches()
+                .map(|(depth, cache)| {
+                    CacheLevel::new_from_config(depth, cache, config.get_physical_address_bits())
+                })
+                .collect(),
+            icache: config.instruction_cache().map(|icache| {
+                CacheLevel::new_instruction_cache(icache, config.get_physical_address_bits())
+            }),
             tlb: config
                 .is_tlb_enabled()
                 .then_some(TLBCache::new_from_config(&config)),
+            itlb: config
+                .instruction_tlb()
+                .map(|itlb| TLBCache::new_from_tlb_config(itlb, &config)),
             page_table: config
                 .is_virtual_addresses_enabled()
                 .then_some(PageTable::new_from_config(&config)),
             config,
             time: 1,
+            asid: 0,
         }
     }
 }
@@ -41,21 +102,39 @@ impl Simulator {
             return Err(());
         }
 
-        if self.config.is_l2_cache_enabled() != self.get_l2().is_some() {
+        if self.config.enabled_caches().count() != self.caches.len() {
+            return Err(());
+        }
+
+        if self.caches.first().map(CacheLevel::get_depth) != Some(0) {
+            return Err(());
+        }
+
+        if self.config.instruction_cache().is_some() != self.icache.is_some() {
             return Err(());
         }
 
         Ok(())
     }
 
-    pub fn get_l2(&self) -> Option<&L2Cache> {
+    pub fn get_caches(&self) -> &[CacheLevel] {
         // assert!(self.health_check().is_ok());
-        self.l2.as_ref()
+        &self.caches
     }
 
-    pub fn get_dc(&self) -> &DataCache {
+    pub fn get_l2(&self) -> Option<&CacheLevel> {
         // assert!(self.health_check().is_ok());
-        &self.dc
+        self.caches.iter().find(|cache| cache.get_depth() == 1)
+    }
+
+    pub fn get_dc(&self) -> &CacheLevel {
+        // assert!(self.health_check().is_ok());
+        &self.caches[0]
+    }
+
+    pub fn get_icache(&self) -> Option<&CacheLevel> {
+        // assert!(self.health_check().is_ok());
+        self.icache.as_ref()
     }
 
     pub fn get_tlb(&self) -> Option<&TLBCache> {
@@ -63,6 +142,11 @@ impl Simulator {
         self.tlb.as_ref()
     }
 
+    pub fn get_itlb(&self) -> Option<&TLBCache> {
+        // assert!(self.health_check().is_ok());
+        self.itlb.as_ref()
+    }
+
     pub fn get_page_table(&self) -> Option<&PageTable> {
         // assert!(self.health_check().is_ok());
         self.page_table.as_ref()
@@ -73,14 +157,24 @@ impl Simulator {
         &self.config
     }
 
-    pub fn get_l2_mut(&mut self) -> Option<&mut L2Cache> {
+    pub fn get_caches_mut(&mut self) -> &mut [CacheLevel] {
+        // assert!(self.health_check().is_ok());
+        &mut self.caches
+    }
+
+    pub fn get_l2_mut(&mut self) -> Option<&mut CacheLevel> {
+        // assert!(self.health_check().is_ok());
+        self.caches.iter_mut().find(|cache| cache.get_depth() == 1)
+    }
+
+    pub fn get_dc_mut(&mut self) -> &mut CacheLevel {
         // assert!(self.health_check().is_ok());
-        self.l2.as_mut()
+        &mut self.caches[0]
     }
 
-    pub fn get_dc_mut(&mut self) -> &mut DataCache {
+    pub fn get_icache_mut(&mut self) -> Option<&mut CacheLevel> {
         // assert!(self.health_check().is_ok());
-        &mut self.dc
+        self.icache.as_mut()
     }
 
     pub fn get_tlb_mut(&mut self) -> Option<&mut TLBCache> {
@@ -88,6 +182,11 @@ impl Simulator {
         self.tlb.as_mut()
     }
 
+    pub fn get_itlb_mut(&mut self) -> Option<&mut TLBCache> {
+        // assert!(self.health_check().is_ok());
+        self.itlb.as_mut()
+    }
+
     pub fn get_page_table_mut(&mut self) -> Option<&mut PageTable> {
         // assert!(self.health_check().is_ok());
         self.page_table.as_mut()
@@ -102,33 +201,261 @@ impl Simulator {
         trace!("Time is now {time}", time = self.time);
     }
 
-    pub fn simulate(&mut self, trace: Trace) -> SimulatorOutput {
+    /// Get the address space of the last access.
+    pub fn get_asid(&self) -> u16 {
+        self.asid
+    }
+
+    /// Switch to another address space: the page table translates with its mappings, and each TLB
+    /// either flushes its entries or switches to the entries tagged with it. The caches are
+    /// physically addressed, so they are left alone.
+    pub fn context_switch(&mut self, asid: u16) {
+        trace!("Context switch from address space {} to {asid}", self.asid);
+        self.asid = asid;
+        self.output.add_context_switch();
+        if let Some(page_table) = &mut self.page_table {
+            page_table.switch_to(asid);
+        }
+        for tlb in self.tlb.iter_mut().chain(&mut self.itlb) {
+            tlb.switch_to(asid);
+        }
+    }
+
+    pub fn simulate(&mut self, trace: Trace) -> Result<SimulatorOutput, SimulatorError> {
+        self.prepare_optimal(trace.iter())?;
+        self.output = SimulatorOutput::empty(self.config.clone());
+        for operation in trace {
+            let rows = self.simulate_operation(operation)?;
+            self.output.accesses.extend(rows);
+        }
+        Ok(self.finish_output())
+    }
+
+    /// Give the TLBs and cache levels using optimal replacement the accesses they will make for
+    /// the operations that are about to be simulated, by translating them ahead of time.
+    /// The first level of the hierarchy sees every access to its lines, but the levels below only
+    /// see its misses and writebacks, which depend on how it replaces its own lines. They are given
+    /// the accesses made by the trace to their lines instead, so they are close to optimal, but not exact.
+    /// `simulate_iter` and `simulate_iter_with` fail with [`SimulatorError::MissingFuture`] without this.
+    pub fn prepare_optimal<'a, I>(&mut self, operations: I) -> Result<(), SimulatorError>
+    where
+        I: IntoIterator<Item = &'a Operation>,
+    {
+        if !self.config.uses_optimal() {
+            return Ok(());
+        }
+        let optimal = |policy: EvictionPolicy| (policy == EvictionPolicy::Optimal).then(FutureAccesses::default);
+        let mut tlb_future = self.tlb.as_ref().and_then(|tlb| optimal(tlb.get_eviction_policy()));
+        let mut itlb_future = self.itlb.as_ref().and_then(|itlb| optimal(itlb.get_eviction_policy()));
+        let mut icache_future = self.icache.as_ref().and_then(|icache| optimal(icache.get_eviction_policy()));
+        let mut cache_futures: Vec<_> = self.caches.iter().map(|level| optimal(level.get_eviction_policy())).collect();
+
+        // The page table maps pages the same way whatever the TLBs and caches do, so a copy of it
+        // translates the accesses to the same physical addresses.
+        let mut page_table = self.page_table.clone();
+        let mut asid = self.asid;
+        let mut time = self.time;
+        for operation in operations {
+            for access in operation.split(self.line_size(operation)) {
+                if access.asid != asid {
+                    asid = access.asid;
+                    if let Some(page_table) = &mut page_table {
+                        page_table.switch_to(asid);
+                    }
+                    for (tlb, future) in [(&self.tlb, &mut tlb_future), (&self.itlb, &mut itlb_future)] {
+                        if let (Some(tlb), Some(future)) = (tlb, future) {
+                            if !tlb.is_asid_tagged() {
+                                future.add_flush(time);
+                            }
+                        }
+                    }
+                }
+                let virtual_address = access.address();
+                let physical_address = match &mut page_table {
+                    Some(page_table) => {
+                        page_table
+                            .translate(virtual_address, time)
+                            .ok_or(SimulatorError::Untranslatable { address: virtual_address, asid })?
+                            .0
+                    }
+                    None => virtual_address,
+                };
+
+                let uses_itlb = access.is_fetch() && self.itlb.is_some();
+                let (tlb, tlb_future) = if uses_itlb { (&self.itlb, &mut itlb_future) } else { (&self.tlb, &mut tlb_future) };
+                if let (Some(tlb), Some(future), Some(_)) = (tlb, tlb_future, &page_table) {
+                    future.add_access(tlb.get_entry_address(virtual_address, asid), time);
+                }
+
+                let uses_icache = access.is_fetch() && self.icache.is_some();
+                let levels = self.caches.iter().zip(&mut cache_futures);
+                let levels: Vec<_> = if uses_icache {
+                    self.icache.iter().zip(std::iter::once(&mut icache_future)).chain(levels.skip(1)).collect()
+                } else {
+                    levels.collect()
+                };
+                for (level, future) in levels {
+                    if let Some(future) = future {
+                        future.add_access(level.get_address(physical_address), time);
+                    }
+                }
+                time += 1;
+            }
+        }
+
+        for (tlb, future) in [(&mut self.tlb, tlb_future), (&mut self.itlb, itlb_future)] {
+            if let (Some(tlb), Some(future)) = (tlb, future) {
+                tlb.set_future_accesses(future);
+            }
+        }
+        if let (Some(icache), Some(future)) = (&mut self.icache, icache_future) {
+            icache.set_future_accesses(future);
+        }
+        for (level, future) in self.caches.iter_mut().zip(cache_futures) {
+            if let Some(future) = future {
+                level.set_future_accesses(future);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that every TLB and cache level using optimal replacement has been given its future.
+    fn check_future(&self) -> Result<(), SimulatorError> {
+        let tlbs = [("dtlb", &self.tlb), ("itlb", &self.itlb)]
+            .into_iter()
+            .filter_map(|(name, tlb)| Some((name.to_owned(), tlb.as_ref()?.needs_future())));
+        let levels = self.icache.iter().chain(&self.caches).map(|level| (level.get_name().to_owned(), level.needs_future()));
+        match tlbs.chain(levels).find(|(_, needs_future)| *needs_future) {
+            Some((name, _)) => Err(SimulatorError::MissingFuture { name }),
+            None => Ok(()),
+        }
+    }
+
+    /// Simulate operations as they arrive, without keeping a row for each access.
+    /// The returned output only has the running counters.
+    /// With optimal replacement, [`Simulator::prepare_optimal`] must be given the operations first.
+    pub fn simulate_iter<I>(&mut self, operations: I) -> Result<SimulatorOutput, SimulatorError>
+    where
+        I: IntoIterator<Item = Operation>,
+    {
+        self.check_future()?;
+        self.output = SimulatorOutput::empty(self.config.clone());
+        for operation in operations {
+            self.simulate_operation(operation)?;
+        }
+        Ok(self.finish_output())
+    }
+
+    /// Simulate operations as they arrive, passing the row for each access to the sink
+    /// instead of keeping it. The returned output only has the running counters.
+    /// This stops at the first error from the simulation or the sink.
+    /// With optimal replacement, [`Simulator::prepare_optimal`] must be given the operations first.
+    pub fn simulate_iter_with<I, S>(
+        &mut self,
+        operations: I,
+        sink: &mut S,
+    ) -> Result<SimulatorOutput, SinkError<S::Error>>
+    where
+        I: IntoIterator<Item = Operation>,
+        S: AccessSink,
+    {
+        self.check_future().map_err(SinkError::Simulator)?;
         self.output = SimulatorOutput::empty(self.config.clone());
-        for access in trace {
-            self.simulate_access(access);
+        for operation in operations {
+            for row in self.simulate_operation(operation).map_err(SinkError::Simulator)? {
+                sink.record(&row).map_err(SinkError::Sink)?;
+            }
         }
-        self.output.clone()
+        Ok(self.finish_output())
     }
 
-    pub fn simulate_access(&mut self, access: Operation) -> AccessOutput {
+    /// Add the statistics kept by the caches and TLBs themselves to the output, and move it out,
+    /// leaving an empty one behind.
+    fn finish_output(&mut self) -> SimulatorOutput {
+        let caches = self.caches.iter().map(|level| (CacheConfig::stats_name(level.get_depth()), level.get_dueling_stats()));
+        let icache = self.icache.iter().map(|icache| ("ic".to_owned(), icache.get_dueling_stats()));
+        let tlbs = [("dtlb", &self.tlb), ("itlb", &self.itlb)]
+            .into_iter()
+            .filter_map(|(name, tlb)| Some((name.to_owned(), tlb.as_ref()?.get_dueling_stats())));
+        self.output.dueling = tlbs
+            .chain(icache)
+            .chain(caches)
+            .filter_map(|(name, stats)| Some((name, stats?)))
+            .collect();
+        std::mem::replace(&mut self.output, SimulatorOutput::empty(self.config.clone()))
+    }
+
+    /// Simulate an operation of any size. An operation that straddles the lines of the first
+    /// level it goes through is split into one access per line (and so, per page), each with its own row.
+    pub fn simulate_operation(&mut self, operation: Operation) -> Result<Vec<AccessOutput>, SimulatorError> {
+        let line_size = self.line_size(&operation);
+        if operation.straddles(line_size) {
+            self.output.add_line_split();
+        }
+        if operation.straddles(self.config.get_page_size()) {
+            self.output.add_page_split();
+        }
+        operation
+            .split(line_size)
+            .map(|access| self.simulate_access(access))
+            .collect()
+    }
+
+    /// Write the lines written back from the level above into a level. They aren't counted as hits
+    /// or misses, and may be passed further down, or evict dirty lines of their own.
+    /// This returns the lines the level passes on to the level below it.
+    fn write_back(cache: &mut CacheLevel, lines: Vec<u64>, time: u64, output: &mut SimulatorOutput) -> Vec<u64> {
+        let mut writebacks = vec![];
+        for line in lines {
+            let outcome = cache.access(LevelRequest::Writeback, cache.get_address(line), time);
+            writebacks.extend(outcome.next.map(|_| line));
+            if let Some(evicted) = outcome.writeback {
+                output.add_writeback(cache.get_depth());
+                writebacks.push(evicted);
+            }
+        }
+        writebacks
+    }
+
+    /// The size of a line in the first level an operation goes through.
+    fn line_size(&self, operation: &Operation) -> u64 {
+        match &self.icache {
+            Some(icache) if operation.is_fetch() => icache.get_block_size(),
+            _ => self.get_dc().get_block_size(),
+        }
+    }
+
+    /// Simulate an access that stays within one line of the first level it goes through.
+    /// Instruction fetches go through the instruction TLB and cache, where there are any.
+    pub fn simulate_access(&mut self, access: Operation) -> Result<AccessOutput, SimulatorError> {
         assert!(self.health_check().is_ok());
+        if access.asid != self.asid {
+            self.context_switch(access.asid);
+        }
         let virtual_address = access.address();
+        let untranslatable = SimulatorError::Untranslatable {
+            address: virtual_address,
+            asid: access.asid,
+        };
         let physical_address;
-        
+
         let time = self.get_time();
         trace!("Access {access} at {time}");
+        let uses_itlb = access.is_fetch() && self.itlb.is_some();
+        let uses_icache = access.is_fetch() && self.icache.is_some();
+        let tlb = if uses_itlb { &mut self.itlb } else { &mut self.tlb };
         let mut is_tlb_hit;
         let tlb_address;
         let is_page_table_hit;
-        match (&mut self.tlb, &mut self.page_table) {
+        match (tlb, &mut self.page_table) {
             (Some(tlb), Some(page_table)) => {
-                let addr = BlockAddress::new_tlb_address(virtual_address, &self.config);
+                let addr = tlb.get_address(virtual_address);
                 tlb_address = Some(addr);
                 // info!("About to translate TLB address...");
                 is_tlb_hit = tlb.translate(addr, time);
                 // info!("About to translate page table address...");
                 (physical_address, is_page_table_hit) =
-                    page_table.translate(virtual_address, time).unwrap();
+                    page_table.translate(virtual_address, time).ok_or(untranslatable)?;
                 is_tlb_hit = is_tlb_hit && is_page_table_hit;
             }
             (None, Some(page_table)) => {
@@ -136,7 +463,7 @@ impl Simulator {
                 tlb_address = None;
                 // info!("About to translate page table address...");
                 (physical_address, is_page_table_hit) =
-                    page_table.translate(virtual_address, time).unwrap();
+                    page_table.translate(virtual_address, time).ok_or(untranslatable)?;
             }
             _ => {
                 physical_address = virtual_address;
@@ -145,7 +472,9 @@ impl Simulator {
                 is_page_table_hit = false;
             }
         }
-        if self.config.is_tlb_enabled() {
+        if uses_itlb {
+            self.output.add_itlb_access(is_tlb_hit);
+        } else if self.config.is_tlb_enabled() {
             self.output.add_tlb_access(is_tlb_hit);
         }
         if !is_tlb_hit && self.config.is_virtual_addresses_enabled() {
@@ -154,143 +483,86 @@ impl Simulator {
         let is_page_fault =
             self.config.is_virtual_addresses_enabled() && !is_tlb_hit && !is_page_table_hit;
 
+        // The lines of the evicted page written back by the levels it is dropped from, on their way down.
+        let mut page_writebacks = vec![];
         if is_page_fault {
-            // let count = self.dc.invalidate_page(physical_address & !(self.config.get_page_size() - 1), &self.config);
-            if let (Some(tlb), Some(pt)) = (&mut self.tlb, &mut self.page_table) {
-                let blocks = tlb.invalidate_page(physical_address, pt, &self.config);
-                let count = blocks.len();
-                if count > 0 {
-                    eprintln!("Evicted {count} pages from the TLB");
+            if let Some(pt) = &mut self.page_table {
+                for (tlb, name) in [(&mut self.tlb, "TLB"), (&mut self.itlb, "ITLB")] {
+                    let Some(tlb) = tlb else { continue };
+                    let count = tlb.invalidate_page(physical_address, pt, &self.config).len();
+                    if count > 0 {
+                        eprintln!("Evicted {count} pages from the {name}");
+                    }
                 }
-
             }
-            let blocks = self.dc.invalidate_page(physical_address, &self.config);
-
-            let count = blocks.len();
-            if count > 0 {
-                eprintln!("Evicted {count} pages from the DC");
+            // The page is dropped from every level, so its dirty lines are written back level by
+            // level until they reach main memory. The instruction cache is backed by the L2, like the data cache.
+            let page_size = self.config.get_page_size();
+            let (data_cache, lower_levels) = self
+                .caches
+                .split_first_mut()
+                .expect("the hierarchy always starts with the data cache");
+            for cache in self.icache.iter_mut().chain(std::iter::once(data_cache)) {
+                for line in cache.invalidate_page(physical_address, page_size) {
+                    self.output.add_writeback(cache.get_depth());
+                    page_writebacks.push(line);
+                }
             }
-            // self.output.add_main_memory_accesses(count as u64);
-
-            if self.config.data_cache.is_write_back() && !self.config.is_l2_cache_enabled() {
-                // self.output.add_main_memory_accesses(count as u64);
-            } else if let Some(l2) = &mut self.l2 {
-                // let count = l2.invalidate_page(physical_address, &self.config);
-                let blocks = l2.invalidate_page(physical_address, &self.config);
-                let count = blocks.len();
-                // if self.config.l2_cache.is_write_back() {
-                //     self.output.add_main_memory_accesses(count as u64);
-                // }
-                if count > 0 {
-                    eprintln!("Evicted {count} pages from the L2");
+            for cache in lower_levels {
+                page_writebacks = Self::write_back(cache, page_writebacks, time, &mut self.output);
+                for line in cache.invalidate_page(physical_address, page_size) {
+                    self.output.add_writeback(cache.get_depth());
+                    page_writebacks.push(line);
                 }
             }
         }
-
-        let dc_address = BlockAddress::new_data_cache_address(physical_address, &self.config);
-        let dc_hit = self.dc.access(access.is_read(), dc_address, time);
-        self.output.add_dc_access(dc_hit);
-
-        let l2_address;
-        let l2_hit;
-        match &mut self.l2 {
-            Some(l2) => {
-                let addr = BlockAddress::new_l2_cache_address(physical_address, &self.config);
-                l2_address = Some(addr);
-                // if self.config.l2_cache.is_write_back() {
-                //     let result = l2.access(access.is_read(), addr, time);
-                //     self.output.add_l2_access(result);
-                //     l2_hit = Some(result);
-                // } else if !dc_hit || ((self.config.data_cache.is_write_through() || self.config.l2_cache.is_write_through()) && access.is_write()) {
-                // } else {
-                //     l2_hit = None;
-                // }
-                // if dc_hit && (self.config.data_cache.is_write_back() || self.config.l2_cache.is_write_back()) {
-                //     l2.access(access.is_read(), addr, time);
-                //     l2_hit = None
-                // } else if !dc_hit {
-                //     let result = l2.access(access.is_read(), addr, time);
-                //     self.output.add_l2_access(result);
-                //     l2_hit = Some(result);
-                // } else {
-                //     l2_hit = None
-                // }
-
-                // if !dc_hit || (dc_hit && (self.config.data_cache.is_write_back() || self.config.l2_cache.is_write_back())) {
-                //     let result = l2.access(access.is_read(), addr, time);
-                //     self.output.add_l2_access(result);
-                //     l2_hit = Some(result);
-                // } else {
-                //     l2_hit = None
-                // }
-
-
-                if self.config.data_cache.is_write_through() && self.config.l2_cache.is_write_through() {
-                    // Good, do not change!
-                    if !dc_hit || access.is_write() {
-                        let result = l2.access(access.is_read(), addr, time);
-                        self.output.add_l2_access(result);
-                        l2_hit = Some(result);
-                    } else {
-                        l2_hit = None;
-                    }
-                } else if self.config.data_cache.is_write_through() && self.config.l2_cache.is_write_back() {
-                    // This works a little, but there is a bug with DC hits
-                    // With only writes, or only reads, this works!
-                    if !dc_hit || (dc_hit && access.is_write()) {
-                        let result = l2.access(access.is_read(), addr, time);
-                        self.output.add_l2_access(result);
-                        l2_hit = Some(result);
-                    } else {
-                        l2_hit = None;
-                    }
-                } else if self.config.data_cache.is_write_back() && self.config.l2_cache.is_write_through() {
-                    // let result = l2.access(access.is_read(), addr, time);
-                    // self.output.add_l2_access(result);
-                    // if dc_hit {
-                    //     l2_hit = None;
-                    // } else {
-                    //     l2_hit = Some(result);
-                    // }
-                    if access.is_write() {
-                        let result = l2.access(access.is_read(), addr, time);
-                        self.output.add_l2_access(result);
-                        if !dc_hit {
-                            l2_hit = Some(result);
-                        } else {
-                            l2_hit = None;
-                        }
-                    } else {
-                        if !dc_hit {
-                            let result = l2.access(access.is_read(), addr, time);
-                            self.output.add_l2_access(result);
-                            l2_hit = Some(result);
-                        } else {
-                            l2_hit = None;
-                        }
-                    }
-                } else if self.config.data_cache.is_write_back() && self.config.l2_cache.is_write_back() {
-                    if !dc_hit || access.is_write() {
-                        let result = l2.access(access.is_read(), addr, time);
-                        assert!(self.dc.access(access.is_read(), dc_address, time));
-                        self.output.add_l2_access(result);
-                        if dc_hit {
-                            l2_hit = None;
-                        } else {
-                            l2_hit = Some(result);
-                        }
-                    } else {
-                        l2_hit = None
-                    }
-                } else {
-                    unreachable!()
-                }
+        // Whatever the page fault wrote back to main memory.
+        let page_writebacks = page_writebacks.len() as u64;
+
+        // Pass the access down the hierarchy until a level handles it, along with the dirty lines
+        // written back by the levels it went through. Instruction fetches start at the instruction
+        // cache instead of the data cache.
+        let mut caches = vec![None; self.config.caches.len()];
+        let mut request = Some(LevelRequest::from_operation(&access));
+        // The physical addresses of the lines written back into the next level down.
+        let mut writebacks = vec![];
+        let (data_cache, lower_levels) = self
+            .caches
+            .split_first_mut()
+            .expect("the hierarchy always starts with the data cache");
+        let first_level = match &mut self.icache {
+            Some(icache) if uses_icache => icache,
+            _ => data_cache,
+        };
+        for cache in std::iter::once(first_level).chain(lower_levels) {
+            if request.is_none() && writebacks.is_empty() {
+                break;
             }
-            None => {
-                l2_address = None;
-                l2_hit = None;
+            let depth = cache.get_depth();
+            // The lines written back from the level above go in first.
+            writebacks = Self::write_back(cache, writebacks, time, &mut self.output);
+
+            let Some(current) = request else { continue };
+            let address = cache.get_address(physical_address);
+            let outcome = cache.access(current, address, time);
+            if uses_icache && depth == 0 {
+                self.output.add_icache_access(outcome.hit);
+            } else {
+                self.output.add_cache_access(depth, outcome.hit);
             }
+            if let Some(evicted) = outcome.writeback {
+                self.output.add_writeback(depth);
+                writebacks.push(evicted);
+            }
+            caches[depth] = Some(LevelAccess {
+                address,
+                hit: outcome.hit,
+            });
+            request = outcome.next;
         }
+        // Whatever the last level passes down goes to main memory, and so does every line it writes back.
+        let main_memory_refs = u64::from(request.is_some()) + writebacks.len() as u64 + page_writebacks;
+        self.output.add_main_memory_accesses(main_memory_refs);
 
         let to_page_number = |addr| {
             (addr & !(self.config.get_page_size() - 1))
@@ -320,14 +592,252 @@ impl Simulator {
                 .config
                 .is_virtual_addresses_enabled()
                 .then_some(is_page_table_hit),
-            dc_address,
-            dc_hit,
-            l2_address,
-            l2_hit,
+            caches,
+        };
+
+        self.output.count_access(&result);
+        self.output.count_process_access(&result, main_memory_refs);
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A hierarchy of physically addressed write-back, write-allocate levels with 16-byte lines,
+    /// each given as its number of sets and set size.
+    fn write_back_hierarchy(levels: &[(u64, u64)]) -> SimulatorConfig {
+        let level = |&(number_of_sets, set_size): &(u64, u64)| CacheConfig {
+            write_allocate: Some(true),
+            ..CacheConfig::new(number_of_sets, set_size, 16, false)
         };
+        SimulatorConfig::builder()
+            .virtual_addresses(false)
+            .tlb_enabled(false)
+            .cache_levels(levels.iter().map(level).collect())
+            .build()
+            .unwrap()
+    }
+
+    fn run(config: SimulatorConfig, trace: &str) -> SimulatorOutput {
+        Simulator::from(config).simulate(trace.parse().unwrap()).unwrap()
+    }
+
+    /// Run W:0 R:0 W:0 R:10 R:0 through a one-line data cache and a two-line L2, and return whether each access
+    /// hit in the data cache and in the L2 (if it got there.)
+    fn data_cache_and_l2_hits(write_through: bool, write_allocate: bool, l2_write_allocate: bool) -> Vec<(bool, Option<bool>)> {
+        let config = SimulatorConfig::builder()
+            .virtual_addresses(false)
+            .tlb_enabled(false)
+            .data_cache_geometry(1, 1, 16)
+            .data_cache_write_policy(write_through, write_allocate)
+            .l2_cache_geometry(1, 2, 16)
+            .l2_cache_write_policy(false, l2_write_allocate)
+            .build()
+            .unwrap();
+        let mut simulator = Simulator::from(config);
+        let operations = [Operation::write(0x0), Operation::read(0x0), Operation::write(0x0), Operation::read(0x10), Operation::read(0x0)];
+        operations
+            .into_iter()
+            .map(|operation| {
+                let access = simulator.simulate_access(operation).unwrap();
+                let hit = |level: usize| access.caches[level].as_ref().map(|level| level.hit);
+                (hit(0).unwrap(), hit(1))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_write_through_data_cache_without_allocation_passes_every_write_to_the_l2() {
+        let hits = data_cache_and_l2_hits(true, false, true);
+        assert_eq!(hits, [(false, Some(false)), (false, Some(true)), (true, Some(true)), (false, Some(false)), (false, Some(true))]);
+    }
+
+    #[test]
+    fn a_write_through_data_cache_with_allocation_fetches_on_a_write_miss() {
+        let hits = data_cache_and_l2_hits(true, true, true);
+        assert_eq!(hits, [(false, Some(false)), (true, None), (true, Some(true)), (false, Some(false)), (false, Some(true))]);
+    }
+
+    #[test]
+    fn a_write_back_data_cache_with_allocation_keeps_writes_to_itself() {
+        let hits = data_cache_and_l2_hits(false, true, true);
+        assert_eq!(hits, [(false, Some(false)), (true, None), (true, None), (false, Some(false)), (false, Some(true))]);
+    }
+
+    #[test]
+    fn a_write_back_data_cache_without_allocation_passes_write_misses_to_the_l2() {
+        let hits = data_cache_and_l2_hits(false, false, true);
+        assert_eq!(hits, [(false, Some(false)), (false, Some(true)), (true, None), (false, Some(false)), (false, Some(true))]);
+    }
+
+    #[test]
+    fn an_l2_without_write_allocation_does_not_keep_written_lines() {
+        let hits = data_cache_and_l2_hits(true, false, false);
+        assert_eq!(hits[..2], [(false, Some(false)), (false, Some(false))]);
+    }
+
+    #[test]
+    fn a_line_evicted_from_the_upper_levels_is_found_in_a_third() {
+        // Line 0 is evicted from the data cache and the L2 by line 10, but the L3 holds both.
+        let output = run(write_back_hierarchy(&[(1, 1), (1, 1), (1, 2)]), "R:0\nR:10\nR:0\n");
+        let stats: Vec<_> = output.caches.iter().map(|level| (level.hits, level.misses)).collect();
+        assert_eq!(stats, [(0, 3), (0, 3), (1, 2)]);
+        assert_eq!(output.main_memory_refs, 2);
+
+        let last = output.accesses.last().unwrap();
+        let hits: Vec<_> = last.caches.iter().map(|level| level.as_ref().map(|level| level.hit)).collect();
+        assert_eq!(hits, [Some(false), Some(false), Some(true)]);
+    }
+
+    #[test]
+    fn streaming_a_trace_gives_the_same_statistics_as_simulating_it() {
+        let text: String = (0..500u64)
+            .map(|i| format!("{}:{:x}\n", if i % 4 == 0 { 'W' } else { 'R' }, (i * 104729) % 8192 * 8))
+            .collect();
+        let trace: Trace = text.parse().unwrap();
+        let config = SimulatorConfig::builder().build().unwrap();
+        let whole = Simulator::from(config.clone()).simulate(trace.clone()).unwrap();
+        let streamed = Simulator::from(config).simulate_iter(trace.iter().copied()).unwrap();
+        assert_eq!(OutputStatistics(&streamed).to_string(), OutputStatistics(&whole).to_string());
+        assert_eq!(whole.accesses.len(), 500);
+        assert!(streamed.accesses.is_empty());
+    }
+
+    #[test]
+    fn an_instruction_fetch_goes_through_the_instruction_tlb_and_cache() {
+        let config = SimulatorConfig::builder()
+            .set("itlb.sets", "2")
+            .and_then(|builder| builder.set("ic.sets", "2"))
+            .unwrap()
+            .build()
+            .unwrap();
+        // The read maps the page, so the first fetch misses only in the instruction TLB and cache, and the L2.
+        // The fetch then brings line 0 into the L2, where the data cache finds it.
+        let output = run(config, "R:40\nI:0\nI:0\nR:0\n");
+        assert_eq!((output.itlb_hits, output.itlb_misses), (1, 1));
+        assert_eq!((output.tlb_hits, output.tlb_misses), (1, 1));
+        assert_eq!((output.pt_hits, output.pt_faults), (1, 1));
+        assert_eq!((output.icache.hits, output.icache.misses), (1, 1));
+        assert_eq!((output.caches[0].hits, output.caches[0].misses), (0, 2));
+        assert_eq!((output.caches[1].hits, output.caches[1].misses), (1, 2));
+        assert_eq!((output.total_fetches, output.total_reads), (2, 2));
+        assert_eq!(output.main_memory_refs, 2);
+    }
+
+    #[test]
+    fn reads_never_write_back() {
+        let output = run(write_back_hierarchy(&[(1, 1)]), "R:0\nR:10\nR:0\n");
+        assert_eq!(output.caches[0].writebacks, 0);
+        assert_eq!(output.main_memory_refs, 3);
+    }
+
+    #[test]
+    fn a_dirty_line_evicted_from_the_last_level_is_written_to_main_memory() {
+        // W:0 fetches the line and dirties it, R:10 evicts it, writing it back as it fetches its own line,
+        // and R:0 fetches it again.
+        let output = run(write_back_hierarchy(&[(1, 1)]), "W:0\nR:10\nR:0\n");
+        assert_eq!((output.caches[0].hits, output.caches[0].misses), (0, 3));
+        assert_eq!(output.caches[0].writebacks, 1);
+        assert_eq!(output.main_memory_refs, 4);
+    }
+
+    #[test]
+    fn a_dirty_line_evicted_from_the_data_cache_is_written_into_the_l2() {
+        // The L2 holds both lines, so it absorbs the writeback of line 0 and later serves it.
+        let output = run(write_back_hierarchy(&[(1, 1), (1, 2)]), "W:0\nR:10\nR:0\n");
+        assert_eq!(output.caches[0].writebacks, 1);
+        // The writeback is neither a hit nor a miss of the L2.
+        assert_eq!((output.caches[1].hits, output.caches[1].misses), (1, 2));
+        assert_eq!(output.caches[1].writebacks, 0);
+        assert_eq!(output.main_memory_refs, 2);
+    }
+
+    #[test]
+    fn a_dirty_line_evicted_from_the_l2_counts_a_main_memory_write() {
+        // The data cache writes line 0 back into the L2, which then evicts it to fetch line 10.
+        let output = run(write_back_hierarchy(&[(1, 1), (1, 1)]), "W:0\nR:10\n");
+        assert_eq!(output.caches[0].writebacks, 1);
+        assert_eq!(output.caches[1].writebacks, 1);
+        assert_eq!(output.main_memory_refs, 3);
+    }
+
+    #[test]
+    fn writebacks_are_only_printed_when_there_are_any() {
+        let config = write_back_hierarchy(&[(1, 1)]);
+        let statistics = OutputStatistics(&run(config.clone(), "W:0\nR:0\n")).to_string();
+        assert!(!statistics.contains("writebacks"), "{statistics}");
+        let statistics = OutputStatistics(&run(config, "W:0\nR:10\n")).to_string();
+        assert!(statistics.contains("dc writebacks    : 1\n"), "{statistics}");
+    }
+
+    #[test]
+    fn a_page_fault_writes_back_the_dirty_lines_of_the_evicted_page() {
+        // Two physical pages of 64 bytes, so the third page evicts the first, which has a dirty line.
+        let config = SimulatorConfig::builder()
+            .tlb_enabled(false)
+            .page_table_geometry(4, 2, 64)
+            .address_bits(8, 7)
+            .cache_levels(vec![CacheConfig {
+                write_allocate: Some(true),
+                ..CacheConfig::new(4, 2, 16, false)
+            }])
+            .build()
+            .unwrap();
+        let output = run(config, "W:0\nR:40\nR:80\n");
+        assert_eq!(output.pt_faults, 3);
+        assert_eq!(output.caches[0].writebacks, 1);
+        assert_eq!(output.main_memory_refs, 4);
+    }
+
+    /// A hierarchy of just a data cache, with a single set of two 16-byte lines, on physical addresses.
+    fn simulator(policy: EvictionPolicy) -> Simulator {
+        let config = SimulatorConfig::builder()
+            .virtual_addresses(false)
+            .tlb_enabled(false)
+            .l2_cache_enabled(false)
+            .data_cache_geometry(1, 2, 16)
+            .data_cache_eviction_policy(policy)
+            .build()
+            .unwrap();
+        Simulator::from(config)
+    }
+
+    /// Three rounds of a loop over three lines.
+    fn looping_trace() -> Trace {
+        "R:00\nR:10\nR:20\n".repeat(3).parse().unwrap()
+    }
+
+    #[test]
+    fn optimal_beats_lru_on_a_loop_larger_than_the_cache() {
+        let output = simulator(EvictionPolicy::LRU).simulate(looping_trace()).unwrap();
+        assert_eq!((output.caches[0].hits, output.caches[0].misses), (0, 9));
+
+        // The lines are used in the order 0, 1, 2, 0, 1, 2, ..., and a miss evicts the line used furthest ahead,
+        // so the accesses at times 4, 6 and 8 hit.
+        let output = simulator(EvictionPolicy::Optimal).simulate(looping_trace()).unwrap();
+        assert_eq!((output.caches[0].hits, output.caches[0].misses), (3, 6));
+        let hits: Vec<_> = output.accesses.iter().map(|access| access.caches[0].as_ref().is_some_and(|dc| dc.hit)).collect();
+        assert_eq!(hits, [false, false, false, true, false, true, false, true, false]);
+    }
+
+    #[test]
+    fn optimal_streams_once_prepared() {
+        let trace = looping_trace();
+        let mut simulator = simulator(EvictionPolicy::Optimal);
+        simulator.prepare_optimal(trace.iter()).unwrap();
+        let output = simulator.simulate_iter(trace).unwrap();
+        assert_eq!((output.caches[0].hits, output.caches[0].misses), (3, 6));
+    }
 
-        self.output.add_access(result);
+    #[test]
+    fn optimal_needs_its_future_to_stream() {
+        let result = simulator(EvictionPolicy::Optimal).simulate_iter(looping_trace());
+        assert!(matches!(result, Err(SimulatorError::MissingFuture { .. })));
 
-        result
+        // Other policies don't need it.
+        assert!(simulator(EvictionPolicy::LRU).simulate_iter(looping_trace()).is_ok());
     }
 }
@@ -0,0 +1,465 @@
+use super::*;
+use core::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+/// One configuration field to vary in a sweep, along with the values it takes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SweepAxis {
+    /// The key of the field, as accepted by [`SimulatorConfig::set`].
+    pub key: String,
+    /// The values of the field, as text to be parsed by [`SimulatorConfig::set`].
+    pub values: Vec<String>,
+}
+
+impl SweepAxis {
+    /// Parse an axis written as `key=values`. The values are either a comma-separated list
+    /// (`dc.policy=lru,fifo`), or a range of numbers: `1..=8` counts up by one,
+    /// `16..=128:+16` counts up by 16, and `1..=64:*2` doubles each time.
+    /// A range written with `..` instead of `..=` leaves out its end.
+    pub fn parse(assignment: &str) -> Result<Self, ConfigError> {
+        let bad_sweep = |message: String| ConfigError::BadOverride {
+            assignment: assignment.to_owned(),
+            message,
+        };
+        let (key, values) = assignment
+            .split_once('=')
+            .ok_or_else(|| bad_sweep("expected \"key=values\"".to_owned()))?;
+        let (key, values) = (key.trim(), values.trim());
+
+        let values = match values.split_once("..") {
+            Some((start, rest)) => {
+                let (inclusive, rest) = match rest.strip_prefix('=') {
+                    Some(rest) => (true, rest),
+                    None => (false, rest),
+                };
+                let (end, step) = rest.split_once(':').unwrap_or((rest, "+1"));
+                let number = |text: &str| {
+                    text.trim()
+                        .parse::<u64>()
+                        .map_err(|_| bad_sweep(format!("expected a number, got \"{text}\"")))
+                };
+                let (start, end) = (number(start)?, number(end)?);
+                let bad_step = || bad_sweep(format!("bad step \"{step}\", expected \"+N\" or \"*N\""));
+                let next: Box<dyn Fn(u64) -> Option<u64>> = if let Some(n) = step.strip_prefix('+') {
+                    match number(n)? {
+                        0 => return Err(bad_step()),
+                        n => Box::new(move |value: u64| value.checked_add(n)),
+                    }
+                } else if let Some(n) = step.strip_prefix('*') {
+                    match number(n)? {
+                        // Multiplying by 0 or 1, or starting at 0, would never reach the end.
+                        n if n < 2 || start == 0 => return Err(bad_step()),
+                        n => Box::new(move |value: u64| value.checked_mul(n)),
+                    }
+                } else {
+                    return Err(bad_step());
+                };
+
+                let mut values = vec![];
+                let mut value = Some(start);
+                while let Some(current) = value {
+                    if current > end || (!inclusive && current == end) {
+                        break;
+                    }
+                    values.push(current.to_string());
+                    value = next(current);
+                }
+                values
+            }
+            None => values
+                .split(',')
+                .map(|value| value.trim().to_owned())
+                .collect(),
+        };
+
+        if values.is_empty() || values.iter().any(String::is_empty) {
+            return Err(bad_sweep("expected at least one value".to_owned()));
+        }
+        Ok(Self {
+            key: key.to_owned(),
+            values,
+        })
+    }
+}
+
+/// A set of configurations to simulate: every combination of the values of the axes,
+/// applied on top of a base configuration.
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    base: SimulatorConfig,
+    axes: Vec<SweepAxis>,
+}
+
+/// Why a configuration in a sweep couldn't be simulated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SweepError {
+    /// The configuration isn't one the simulator can model, or the trace doesn't fit in it.
+    Invalid(Vec<ValidationError>),
+    /// The simulation of the trace stopped part way through.
+    Simulation(SimulatorError),
+}
+
+impl Display for SweepError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Invalid(errors) => {
+                let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "{}", errors.join("; "))
+            }
+            Self::Simulation(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for SweepError {}
+
+/// The result of simulating one configuration in a sweep.
+#[derive(Clone)]
+pub struct SweepPoint {
+    /// The value of each axis of the sweep for this configuration, in the order of the axes.
+    pub values: Vec<String>,
+    /// The statistics of the simulation, or the reasons the configuration couldn't be simulated.
+    /// The per-access rows are left out, to keep large sweeps small.
+    pub output: Result<SimulatorOutput, SweepError>,
+}
+
+impl Sweep {
+    /// Create a sweep of the given axes over a base configuration.
+    /// Every value is checked against its key up front, so a typo fails before any simulation runs.
+    pub fn new(base: SimulatorConfig, axes: Vec<SweepAxis>) -> Result<Self, ConfigError> {
+        for axis in &axes {
+            for value in &axis.values {
+                base.clone().set(&axis.key, value)?;
+            }
+        }
+        Ok(Self { base, axes })
+    }
+
+    /// The axes of the sweep.
+    pub fn axes(&self) -> &[SweepAxis] {
+        &self.axes
+    }
+
+    /// The number of configurations in the sweep.
+    pub fn len(&self) -> usize {
+        self.axes.iter().map(|axis| axis.values.len()).product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the values of the axes for the configuration at `index`. The last axis varies fastest.
+    fn values_of(&self, mut index: usize) -> Vec<String> {
+        let mut values = vec![String::new(); self.axes.len()];
+        for (value, axis) in values.iter_mut().zip(&self.axes).rev() {
+            *value = axis.values[index % axis.values.len()].clone();
+            index /= axis.values.len();
+        }
+        values
+    }
+
+    /// Build the configuration at `index`, and simulate the trace on it.
+    fn simulate_point(&self, index: usize, trace: &Trace) -> SweepPoint {
+        let values = self.values_of(index);
+        let mut config = self.base.clone();
+        for (axis, value) in self.axes.iter().zip(&values) {
+            config
+                .set(&axis.key, value)
+                .expect("sweep values are checked when the sweep is created");
+        }
+
+        // The trace was read without an address limit, since each configuration has its own.
+        let end = trace.iter().map(Operation::last_address).max();
+        let output = config
+            .validate()
+            .and_then(|()| match (end, config.get_address_limit()) {
+                (Some(address), Some(limit)) if address >= limit => {
+                    Err(vec![ValidationError::TraceOutOfRange { address, limit }])
+                }
+                _ => Ok(()),
+            })
+            .map_err(SweepError::Invalid)
+            .and_then(|()| {
+                let mut simulator = Simulator::from(config);
+                simulator
+                    .prepare_optimal(trace.iter())
+                    .and_then(|()| simulator.simulate_iter(trace.iter().copied()))
+                    .map_err(SweepError::Simulation)
+            });
+        SweepPoint { values, output }
+    }
+
+    /// Simulate the trace on every configuration in the sweep, using up to `threads` threads.
+    /// The results are in the order of the configurations, whichever thread ran them.
+    pub fn run(&self, trace: &Trace, threads: usize) -> Vec<SweepPoint> {
+        let next_index = AtomicUsize::new(0);
+        let results = Mutex::new(vec![None; self.len()]);
+
+        std::thread::scope(|scope| {
+            for _ in 0..threads.clamp(1, self.len().max(1)) {
+                scope.spawn(|| loop {
+                    let index = next_index.fetch_add(1, Ordering::Relaxed);
+                    if index >= self.len() {
+                        break;
+                    }
+                    let point = self.simulate_point(index, trace);
+                    results.lock().unwrap()[index] = Some(point);
+                });
+            }
+        });
+
+        results
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|point| point.expect("every configuration is simulated"))
+            .collect()
+    }
+}
+
+/// The layouts the results of a sweep can be written in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SweepFormat {
+    /// Columns padded with spaces to line up.
+    Table,
+    /// Comma-separated values, with a header row.
+    Csv,
+}
+
+/// The results of a sweep, ready to be written as a table or CSV.
+/// There is one row per configuration, with a column for each axis followed by the statistics.
+pub struct SweepReport<'a> {
+    pub sweep: &'a Sweep,
+    pub points: &'a [SweepPoint],
+    pub format: SweepFormat,
+}
+
+impl SweepReport<'_> {
+    /// The header and the cells of every row.
+    fn rows(&self) -> Vec<Vec<String>> {
+        // Sweeps can change the depth of the hierarchy, so make room for the deepest one.
+        let depth = self
+            .points
+            .iter()
+            .filter_map(|point| point.output.as_ref().ok())
+            .map(|output| output.caches.len())
+            .max()
+            .unwrap_or(0);
+        // The instruction side only gets columns if some configuration has it.
+        let instruction_side = self
+            .points
+            .iter()
+            .filter_map(|point| point.output.as_ref().ok())
+            .any(SimulatorOutput::has_instruction_side);
+
+        let mut header: Vec<String> = self.sweep.axes.iter().map(|axis| axis.key.clone()).collect();
+        let mut groups = vec![("dtlb".to_owned(), "misses")];
+        if instruction_side {
+            groups.push(("itlb".to_owned(), "misses"));
+        }
+        groups.push(("pt".to_owned(), "faults"));
+        if instruction_side {
+            groups.push(("ic".to_owned(), "misses"));
+        }
+        groups.extend((0..depth).map(|depth| (CacheConfig::stats_name(depth), "misses")));
+        for (name, misses) in groups {
+            header.push(format!("{name} hits"));
+            header.push(format!("{name} {misses}"));
+            header.push(format!("{name} hit ratio"));
+        }
+        header.push("total reads".to_owned());
+        header.push("total writes".to_owned());
+        if instruction_side {
+            header.push("total fetches".to_owned());
+        }
+        header.extend((0..depth).map(|depth| format!("{} writebacks", CacheConfig::stats_name(depth))));
+        header.extend(
+            [
+                "line splits",
+                "page splits",
+                "main memory refs",
+                "page table refs",
+                "disk refs",
+                "error",
+            ]
+            .map(str::to_owned),
+        );
+
+        let mut rows = vec![header];
+        for point in self.points {
+            let mut row = point.values.clone();
+            match &point.output {
+                Ok(output) => {
+                    let mut counters = vec![(
+                        output.tlb_hits,
+                        output.tlb_misses,
+                        output.config.is_tlb_enabled(),
+                    )];
+                    if instruction_side {
+                        counters.push((
+                            output.itlb_hits,
+                            output.itlb_misses,
+                            output.config.instruction_tlb().is_some(),
+                        ));
+                    }
+                    counters.push((
+                        output.pt_hits,
+                        output.pt_faults,
+                        output.config.is_virtual_addresses_enabled(),
+                    ));
+                    if instruction_side {
+                        counters.push((
+                            output.icache.hits,
+                            output.icache.misses,
+                            output.config.instruction_cache().is_some(),
+                        ));
+                    }
+                    let components = counters.len();
+                    counters.extend(
+                        output
+                            .caches
+                            .iter()
+                            .zip(&output.config.caches)
+                            .map(|(stats, cache)| (stats.hits, stats.misses, cache.enabled)),
+                    );
+                    counters.resize(components + depth, (0, 0, false));
+                    for (hits, misses, enabled) in counters {
+                        row.push(hits.to_string());
+                        row.push(misses.to_string());
+                        row.push(if enabled {
+                            format!("{:1.6}", hit_ratio(hits, misses))
+                        } else {
+                            "N/A".to_owned()
+                        });
+                    }
+                    row.push(output.total_reads.to_string());
+                    row.push(output.total_writes.to_string());
+                    if instruction_side {
+                        row.push(output.total_fetches.to_string());
+                    }
+                    let mut writebacks: Vec<String> =
+                        output.caches.iter().map(|stats| stats.writebacks.to_string()).collect();
+                    writebacks.resize(depth, "0".to_owned());
+                    row.extend(writebacks);
+                    row.extend(
+                        [
+                            output.line_splits,
+                            output.page_splits,
+                            output.main_memory_refs,
+                            output.pt_hits + output.pt_faults,
+                            output.pt_faults,
+                        ]
+                        .map(|count| count.to_string()),
+                    );
+                    row.push(String::new());
+                }
+                Err(error) => {
+                    row.resize(rows[0].len() - 1, String::new());
+                    row.push(error.to_string());
+                }
+            }
+            rows.push(row);
+        }
+        rows
+    }
+}
+
+impl Display for SweepReport<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let rows = self.rows();
+        match self.format {
+            SweepFormat::Csv => {
+                for row in rows {
+                    let cells: Vec<String> = row
+                        .into_iter()
+                        .map(|cell| {
+                            if cell.contains([',', '"']) {
+                                format!("\"{}\"", cell.replace('"', "\"\""))
+                            } else {
+                                cell
+                            }
+                        })
+                        .collect();
+                    writeln!(f, "{}", cells.join(","))?;
+                }
+            }
+            SweepFormat::Table => {
+                let mut widths = vec![0; rows[0].len()];
+                for row in &rows {
+                    for (width, cell) in widths.iter_mut().zip(row) {
+                        *width = (*width).max(cell.len());
+                    }
+                }
+                for row in &rows {
+                    let cells: Vec<String> = row
+                        .iter()
+                        .zip(&widths)
+                        .map(|(cell, width)| format!("{cell:>width$}"))
+                        .collect();
+                    writeln!(f, "{}", cells.join("  ").trim_end())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A few hundred reads and writes scattered over 16KiB.
+    fn scattered_trace() -> Trace {
+        let text: String = (0..300u64)
+            .map(|i| format!("{}:{:x}\n", if i % 3 == 0 { 'W' } else { 'R' }, (i * 7919) % 4096 * 4))
+            .collect();
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn every_point_matches_a_run_of_its_own_configuration() {
+        let base = SimulatorConfig::builder().build().unwrap();
+        let axes = ["dc.sets=1..=4:*2", "dc.policy=lru,fifo", "l2.write_through=y,n"];
+        let axes = axes.map(|axis| SweepAxis::parse(axis).unwrap()).to_vec();
+        let sweep = Sweep::new(base.clone(), axes).unwrap();
+        let trace = scattered_trace();
+        let points = sweep.run(&trace, 3);
+        assert_eq!(points.len(), 12);
+
+        for point in &points {
+            let mut config = base.clone();
+            for (axis, value) in sweep.axes().iter().zip(&point.values) {
+                config.set(&axis.key, value).unwrap();
+            }
+            let expected = Simulator::from(config).simulate(trace.clone()).unwrap();
+            let output = point.output.as_ref().unwrap();
+            assert_eq!(
+                OutputStatistics(output).to_string(),
+                OutputStatistics(&expected).to_string(),
+                "{:?}",
+                point.values
+            );
+        }
+    }
+
+    #[test]
+    fn points_are_ordered_with_the_last_axis_varying_fastest() {
+        let base = SimulatorConfig::builder().build().unwrap();
+        let axes = ["dc.sets=1,3", "dc.set_size=1,2"];
+        let axes = axes.map(|axis| SweepAxis::parse(axis).unwrap()).to_vec();
+        let points = Sweep::new(base, axes).unwrap().run(&scattered_trace(), 2);
+        let values: Vec<_> = points.iter().map(|point| point.values.join(" ")).collect();
+        assert_eq!(values, ["1 1", "1 2", "3 1", "3 2"]);
+        // Three sets can't be indexed by address bits, which fails only those points.
+        let valid: Vec<_> = points.iter().map(|point| point.output.is_ok()).collect();
+        assert_eq!(valid, [true, true, false, false]);
+        let Err(SweepError::Invalid(errors)) = &points[2].output else {
+            panic!("dc.sets=3 is simulated");
+        };
+        assert!(matches!(errors[..], [ValidationError::NotPowerOfTwo { value: 3, .. }]), "{errors:?}");
+    }
+}
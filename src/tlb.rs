@@ -1,8 +1,21 @@
 use super::*;
 use log::{debug, info, trace};
 
+/// The lowest bit of an entry's tag holding its address space ID, in a TLB tagged with them.
+/// This is above the tag of any virtual page number the page table can hold.
+const ASID_TAG_SHIFT: u32 = 48;
+
 pub struct TLBCache {
     cache: Cache,
+    /// The number of bytes in a page, which is what each entry translates.
+    page_size: u64,
+    /// The number of bits in a virtual page number.
+    page_number_bits: u64,
+    index_bits: u64,
+    /// Are the entries tagged with their address space? If not, the TLB is flushed on a context switch.
+    asid_tagged: bool,
+    /// The address space being translated for.
+    asid: u16,
 }
 
 impl TLBCache {
@@ -15,30 +28,110 @@ impl TLBCache {
         info!("Creating new TLBCache with {sets} sets, associativity={associativity}, block-size={block_size}, policy={evict_policy:?}");
         Self {
             cache: Cache::new(sets, block_size, associativity, evict_policy),
+            page_size: block_size,
+            page_number_bits: ADDRESS_BITS.saturating_sub(block_size.trailing_zeros().into()),
+            index_bits: sets.trailing_zeros() as u64,
+            asid_tagged: false,
+            asid: 0,
         }
     }
 
+    /// Create the data TLB described by the configuration.
     pub fn new_from_config(config: &SimulatorConfig) -> Self {
-        let number_of_sets = config.tlb.get_number_of_sets();
-        let entries_in_set = config.tlb.get_entries_in_set();
+        Self::new_from_tlb_config(&config.tlb, config)
+    }
+
+    /// Create a TLB (such as the instruction TLB) from its own settings,
+    /// translating pages of the size in the configuration.
+    pub fn new_from_tlb_config(tlb: &TLBConfig, config: &SimulatorConfig) -> Self {
+        let number_of_sets = tlb.get_number_of_sets();
+        let entries_in_set = tlb.get_entries_in_set();
         let entry_size = config.get_page_size();
         let block_size = entry_size;
-        let evict_policy = config.tlb.get_eviction_policy();
-        Self::new(
-            number_of_sets as usize,
-            block_size,
-            entries_in_set,
-            evict_policy,
-        )
+        let evict_policy = tlb.get_eviction_policy();
+        Self {
+            asid_tagged: tlb.asid_tagged,
+            page_number_bits: config
+                .get_virtual_address_bits()
+                .saturating_sub(config.get_page_table_offset_bits()),
+            ..Self::new(
+                number_of_sets as usize,
+                block_size,
+                entries_in_set,
+                evict_policy,
+            )
+        }
+    }
+
+    /// Get the address of the entry translating a virtual address.
+    /// This is the virtual page number, split into a tag and an index.
+    pub fn get_address(&self, virtual_address: u64) -> BlockAddress {
+        let page_number = virtual_address >> self.page_size.trailing_zeros();
+        BlockAddress::new(page_number, self.page_number_bits, self.index_bits, 0)
+    }
+
+    /// Get the address of an entry in the cache, which is tagged with the address space it
+    /// translates for if the TLB is ASID tagged.
+    fn get_tagged_address(&self, address: BlockAddress, asid: u16) -> BlockAddress {
+        if !self.asid_tagged {
+            return address;
+        }
+        BlockAddress {
+            tag: address.tag | u64::from(asid) << ASID_TAG_SHIFT,
+            ..address
+        }
+    }
+
+    /// Get the address of the entry translating a virtual address in an address space,
+    /// as the TLB stores it.
+    pub fn get_entry_address(&self, virtual_address: u64, asid: u16) -> BlockAddress {
+        self.get_tagged_address(self.get_address(virtual_address), asid)
+    }
+
+    /// Is the TLB tagged with address spaces, so that it isn't flushed on a context switch?
+    pub fn is_asid_tagged(&self) -> bool {
+        self.asid_tagged
+    }
+
+    /// The replacement policy of the TLB.
+    pub fn get_eviction_policy(&self) -> EvictionPolicy {
+        self.cache.get_eviction_policy()
+    }
+
+    /// Give the TLB the entries it will look up, if it uses optimal replacement.
+    pub fn set_future_accesses(&mut self, future: FutureAccesses) {
+        self.cache.set_future_accesses(future);
+    }
+
+    /// Does the TLB use optimal replacement without having been given its future lookups?
+    pub fn needs_future(&self) -> bool {
+        self.cache.needs_future()
     }
 
     /// Try to translate the address using the TLB. This function
     /// returns whether or not the translation was a hit.
     pub fn translate(&mut self, address: BlockAddress, current_access_time: u64) -> bool {
+        let address = self.get_tagged_address(address, self.asid);
         self.cache
             .is_read_and_allocate_hit(address, current_access_time)
     }
 
+    /// Get the leader sets and policy selection counter of the TLB, if it uses DRRIP.
+    pub fn get_dueling_stats(&self) -> Option<DuelingStats> {
+        self.cache.get_dueling_stats()
+    }
+
+    /// Switch to translating for another address space. A TLB that isn't ASID tagged is flushed,
+    /// and the number of entries flushed is returned.
+    pub fn switch_to(&mut self, asid: u16) -> usize {
+        self.asid = asid;
+        if self.asid_tagged {
+            0
+        } else {
+            self.cache.flush().len()
+        }
+    }
+
 
     /// Invalidate a physical page from the cache. This gets all the blocks loaded from
     /// the page, and then invalidates them in the cache.
@@ -50,9 +143,13 @@ impl TLBCache {
         let mut invalidated_blocks = Vec::new();
 
         for entry in page_table.get_entries() {
+            // Without tags, the TLB only holds entries of the current address space.
+            if !self.asid_tagged && entry.get_asid() != self.asid {
+                continue;
+            }
             if entry.get_physical_address() == physical_address {
-                let addr = entry.get_virtual_address();
-                if let Some(block) = self.cache.invalidate(BlockAddress::new_tlb_address(addr, config)) {
+                let addr = self.get_tagged_address(self.get_address(entry.get_virtual_address()), entry.get_asid());
+                if let Some(block) = self.cache.invalidate(addr) {
                     trace!("Invalidated TLB block {block:?}");
                     invalidated_blocks.push(block);
                 }
@@ -60,8 +157,47 @@ impl TLBCache {
         }
         // page_table.invalidate_page_number(physical_address / config.get_page_size());
         let number_of_blocks = invalidated_blocks.len();
-        debug!("TLB Invalidating {number_of_blocks} blocks = {} bytes at {physical_address:x}", number_of_blocks as u64 * config.data_cache.get_block_size());
+        debug!("TLB Invalidating {number_of_blocks} blocks = {} bytes at {physical_address:x}", number_of_blocks as u64 * config.data_cache().get_block_size());
         
         invalidated_blocks
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tlb(asid_tagged: bool) -> TLBCache {
+        let config = SimulatorConfig::builder().build().unwrap();
+        let tlb = TLBConfig {
+            asid_tagged,
+            ..TLBConfig::new(1, 4)
+        };
+        TLBCache::new_from_tlb_config(&tlb, &config)
+    }
+
+    #[test]
+    fn an_untagged_tlb_is_flushed_on_a_context_switch() {
+        let mut tlb = tlb(false);
+        let page = tlb.get_address(0);
+        assert!(!tlb.translate(page, 1));
+        assert!(tlb.translate(page, 2));
+        assert_eq!(tlb.switch_to(1), 1);
+        assert!(!tlb.translate(page, 3));
+        assert_eq!(tlb.switch_to(0), 1);
+        assert!(!tlb.translate(page, 4));
+    }
+
+    #[test]
+    fn a_tagged_tlb_keeps_the_entries_of_each_address_space() {
+        let mut tlb = tlb(true);
+        let page = tlb.get_address(0);
+        assert!(!tlb.translate(page, 1));
+        assert_eq!(tlb.switch_to(1), 0);
+        // The same virtual page of another address space is a different entry.
+        assert!(!tlb.translate(page, 2));
+        assert_ne!(tlb.get_entry_address(0, 0).tag, tlb.get_entry_address(0, 1).tag);
+        assert_eq!(tlb.switch_to(0), 0);
+        assert!(tlb.translate(page, 3));
+    }
+}
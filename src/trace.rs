@@ -1,57 +1,414 @@
-use super::{get_hexadecimal, SimulatorConfig};
+use super::{
+    binary::{self, BinaryDecoder, BinaryEncoder},
+    dinero, lackey, CacheConfig, ConfigError, LineReader, SimulatorConfig,
+};
 use core::fmt::{Display, Formatter, Result as FmtResult};
+use log::warn;
+
+use std::{
+    io::{BufReader, Chain, Cursor, Read, Result as IoResult, Write},
+    str::FromStr,
+};
+
+/// An error encountered while reading a trace.
+/// Every text variant carries the line number (starting at 1) and the text of the offending line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceError {
+    /// The line isn't laid out as the trace format expects.
+    Malformed {
+        line: usize,
+        text: String,
+        /// A description of the expected layout.
+        expected: String,
+    },
+    /// The access type isn't one the trace format defines.
+    BadAccessType {
+        line: usize,
+        text: String,
+        /// A description of the access types of the format.
+        expected: String,
+    },
+    /// The address isn't a valid hexadecimal number.
+    BadAddress { line: usize, text: String },
+    /// The size isn't a positive decimal number, or the access runs past the largest address.
+    BadSize { line: usize, text: String },
+    /// The address space ID isn't a decimal number that fits in 16 bits.
+    BadAsid { line: usize, text: String },
+    /// The access touches an address past the limit in the [`TraceOptions`].
+    /// In a binary trace, the line is the number of the record.
+    OutOfRange {
+        line: usize,
+        operation: Operation,
+        limit: u64,
+    },
+    /// A record of a binary trace (or its header, as record 0) is corrupt.
+    Binary { record: usize, message: String },
+    /// The trace could not be opened or read.
+    Io(String),
+}
 
-use std::io::{BufReader, Read};
+impl Display for TraceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Malformed {
+                line,
+                text,
+                expected,
+            } => write!(f, "line {line}: expected {expected}, got \"{text}\""),
+            Self::BadAccessType {
+                line,
+                text,
+                expected,
+            } => write!(f, "line {line}: unknown access type in \"{text}\", expected {expected}"),
+            Self::BadAddress { line, text } => {
+                write!(f, "line {line}: bad hexadecimal address in \"{text}\"")
+            }
+            Self::BadSize { line, text } => {
+                write!(f, "line {line}: bad size in \"{text}\", expected a number of bytes")
+            }
+            Self::BadAsid { line, text } => write!(
+                f,
+                "line {line}: bad address space ID in \"{text}\", expected a number up to {}",
+                u16::MAX
+            ),
+            Self::OutOfRange {
+                line,
+                operation,
+                limit,
+            } => write!(
+                f,
+                "line {line}: \"{operation}\" is outside of the address space, which ends at {limit:x}"
+            ),
+            Self::Binary { record: 0, message } => write!(f, "bad binary trace header: {message}"),
+            Self::Binary { record, message } => write!(f, "record {record}: {message}"),
+            Self::Io(message) => write!(f, "{message}"),
+        }
+    }
+}
 
-/// A memory access operation to be performed by the simulator.
-#[derive(Clone, Copy, Debug)]
-pub enum Operation {
-    Read(u64),
-    Write(u64),
+impl std::error::Error for TraceError {}
+
+/// How to handle lines of a trace that can't be parsed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TraceMode {
+    /// Fail on the first bad line.
+    #[default]
+    Strict,
+    /// Skip bad lines, keeping their errors in [`Trace::skipped`].
+    Lenient,
 }
 
-impl Operation {
-    fn from_buffer<R>(buffer: &mut BufReader<R>) -> Option<Self>
-    where
-        R: Read,
-    {
-        let (access_type, address) = get_hexadecimal(buffer, None)?;
+/// The kind of memory access an operation makes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    Read,
+    Write,
+    /// An instruction fetch, which reads through the instruction side of the hierarchy.
+    Fetch,
+}
 
-        match access_type.as_str() {
-            "R" => Some(Self::Read(address)),
-            "W" => Some(Self::Write(address)),
+/// The layouts a trace can be written in.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One `type:address` or `type:address:size` operation per line, optionally followed by
+    /// `@asid` for the address space making it. A `C:asid` line switches to another address space.
+    #[default]
+    Native,
+    /// The output of Valgrind's `--tool=lackey --trace-mem=yes`.
+    Lackey,
+    /// The `label address` "din" format of the Dinero cache simulators.
+    Dinero,
+    /// The compact binary format of this simulator. Binary traces are recognized by their
+    /// magic number, whatever format they are read as.
+    Binary(BinaryEncoding),
+}
+
+/// How the addresses of a binary trace are stored.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BinaryEncoding {
+    /// Every address takes eight bytes.
+    Fixed,
+    /// Every address is stored as a varint of its difference from the one before,
+    /// which is much smaller for traces with locality.
+    #[default]
+    Delta,
+}
+
+impl TraceFormat {
+    /// Choose the format from a file's extension: `.din` files are Dinero traces,
+    /// and anything else is a native trace.
+    pub fn from_path(path: &str) -> Self {
+        match std::path::Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+        {
+            Some(ext) if ext.eq_ignore_ascii_case("din") => Self::Dinero,
+            _ => Self::Native,
+        }
+    }
+
+    /// Parse the name of a format, as given on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "native" => Some(Self::Native),
+            "lackey" => Some(Self::Lackey),
+            "dinero" | "din" => Some(Self::Dinero),
+            "binary" => Some(Self::Binary(BinaryEncoding::Delta)),
+            "binary-fixed" => Some(Self::Binary(BinaryEncoding::Fixed)),
             _ => None,
         }
     }
 
-    pub fn is_read(&self) -> bool {
+    /// Parse one line of a trace in this format, found at the given line number.
+    /// Operations without an address space of their own are made by `asid`.
+    /// Return None if the line has no operations, such as a comment.
+    fn parse_line(self, line: usize, text: &str, asid: u16) -> Result<Option<Record>, TraceError> {
         match self {
-            Self::Read(_) => true,
-            Self::Write(_) => false,
+            Self::Native => {
+                let text = strip_comment(text);
+                if text.is_empty() {
+                    return Ok(None);
+                }
+                Operation::parse(line, text, asid).map(|operation| Some((operation, None)))
+            }
+            Self::Lackey => lackey::parse_line(line, text),
+            Self::Dinero => dinero::parse_line(line, text),
+            // A binary trace would have been recognized by its magic number before any lines were read.
+            Self::Binary(_) => Err(TraceError::Binary {
+                record: 0,
+                message: "missing the magic number of a binary trace".to_owned(),
+            }),
         }
     }
+}
 
-    pub fn is_write(&self) -> bool {
-        match self {
-            Self::Read(_) => false,
-            Self::Write(_) => true,
+/// Remove the `#` comment, if any, from a line of a native trace.
+fn strip_comment(text: &str) -> &str {
+    match text.split_once('#') {
+        Some((text, _comment)) => text.trim(),
+        None => text,
+    }
+}
+
+/// Parse a context switch line of a native trace (`C:asid`), found at the given line number.
+/// Return None if the line isn't a context switch.
+fn parse_context_switch(line: usize, text: &str) -> Option<Result<u16, TraceError>> {
+    let asid = strip_comment(text).strip_prefix("C:")?;
+    Some(asid.trim().parse().map_err(|_| TraceError::BadAsid {
+        line,
+        text: text.to_owned(),
+    }))
+}
+
+/// The operations on one line of a trace. Most lines hold a single operation,
+/// but some (like a Lackey "modify") hold a read followed by a write.
+pub(crate) type Record = (Operation, Option<Operation>);
+
+/// How a trace is read.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceOptions {
+    /// How to handle lines that can't be parsed.
+    pub mode: TraceMode,
+    /// The layout of the trace.
+    pub format: TraceFormat,
+    /// Leave out instruction fetches, keeping only the data accesses.
+    pub drop_fetches: bool,
+    /// The first address past the ones an access may touch, such as the end of the virtual address
+    /// space of a configuration (see [`SimulatorConfig::get_address_limit`]). Accesses past it are
+    /// bad lines. Without one, any address is accepted.
+    pub address_limit: Option<u64>,
+}
+
+impl From<TraceMode> for TraceOptions {
+    /// Read a native trace in the given mode, keeping every operation.
+    fn from(mode: TraceMode) -> Self {
+        Self {
+            mode,
+            ..Self::default()
         }
     }
+}
 
-    pub fn address(&self) -> u64 {
-        match self {
-            Self::Read(address) => *address,
-            Self::Write(address) => *address,
+/// A memory access operation to be performed by the simulator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Operation {
+    pub access_type: AccessType,
+    /// The address of the first byte accessed.
+    pub address: u64,
+    /// The number of bytes accessed, starting at the address. This is at least 1.
+    pub size: u64,
+    /// The address space (process) making the access. Traces of a single process leave this at 0.
+    pub asid: u16,
+}
+
+impl Operation {
+    /// A one byte read.
+    pub fn read(address: u64) -> Self {
+        Self {
+            access_type: AccessType::Read,
+            address,
+            size: 1,
+            asid: 0,
+        }
+    }
+
+    /// A one byte instruction fetch.
+    pub fn fetch(address: u64) -> Self {
+        Self {
+            access_type: AccessType::Fetch,
+            address,
+            size: 1,
+            asid: 0,
         }
     }
+
+    /// A one byte write.
+    pub fn write(address: u64) -> Self {
+        Self {
+            access_type: AccessType::Write,
+            address,
+            size: 1,
+            asid: 0,
+        }
+    }
+
+    /// The same operation, but accessing `size` bytes.
+    pub fn with_size(self, size: u64) -> Self {
+        Self { size, ..self }
+    }
+
+    /// The same operation, but made by the address space `asid`.
+    pub fn with_asid(self, asid: u16) -> Self {
+        Self { asid, ..self }
+    }
+
+    /// Parse the text of a native trace line, such as `R:1ffc`, `R:1ffc:8` or `R:1ffc:8@2`, found at
+    /// the given line number. Without an `@asid` suffix, the operation is made by the address space `asid`.
+    fn parse(line: usize, text: &str, asid: u16) -> Result<Self, TraceError> {
+        let text_owned = || text.to_owned();
+        let (fields, asid) = match text.rsplit_once('@') {
+            Some((fields, asid)) => {
+                let asid = asid.trim().parse().map_err(|_| TraceError::BadAsid {
+                    line,
+                    text: text_owned(),
+                })?;
+                (fields, asid)
+            }
+            None => (text, asid),
+        };
+        let mut fields = fields.split(':');
+        let (Some(access_type), Some(address), size, None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(TraceError::Malformed {
+                line,
+                text: text_owned(),
+                expected: "\"type:address\" or \"type:address:size\"".to_owned(),
+            });
+        };
+        let address =
+            u64::from_str_radix(address.trim(), 16).map_err(|_| TraceError::BadAddress {
+                line,
+                text: text_owned(),
+            })?;
+        let size = match size {
+            None => 1,
+            Some(size) => match size.trim().parse::<u64>() {
+                // The last byte accessed has to be addressable too.
+                Ok(size) if size > 0 && address.checked_add(size - 1).is_some() => size,
+                _ => {
+                    return Err(TraceError::BadSize {
+                        line,
+                        text: text_owned(),
+                    })
+                }
+            },
+        };
+
+        let access_type = match access_type.trim() {
+            "R" => AccessType::Read,
+            "W" => AccessType::Write,
+            "I" => AccessType::Fetch,
+            _ => {
+                return Err(TraceError::BadAccessType {
+                    line,
+                    text: text_owned(),
+                    expected: "\"R\", \"W\" or \"I\"".to_owned(),
+                })
+            }
+        };
+        Ok(Self {
+            access_type,
+            address,
+            size,
+            asid,
+        })
+    }
+
+    pub fn is_read(&self) -> bool {
+        self.access_type == AccessType::Read
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.access_type == AccessType::Write
+    }
+
+    pub fn is_fetch(&self) -> bool {
+        self.access_type == AccessType::Fetch
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The address of the last byte accessed.
+    pub fn last_address(&self) -> u64 {
+        self.address + (self.size - 1)
+    }
+
+    /// Does the operation access bytes on both sides of a multiple of `boundary`
+    /// (such as the end of a cache line or a page)? The boundary must be a power of two.
+    pub fn straddles(&self, boundary: u64) -> bool {
+        self.address / boundary != self.last_address() / boundary
+    }
+
+    /// Split the operation into pieces that each stay between two multiples of `boundary`.
+    /// An operation that doesn't straddle a boundary is its own single piece.
+    pub fn split(&self, boundary: u64) -> impl Iterator<Item = Self> {
+        let operation = *self;
+        let mut next = Some(operation.address);
+        core::iter::from_fn(move || {
+            let address = next?;
+            let end = (address | (boundary - 1)).min(operation.last_address());
+            next = (end < operation.last_address()).then(|| end + 1);
+            Some(Self {
+                address,
+                size: end - address + 1,
+                ..operation
+            })
+        })
+    }
 }
 
 impl Display for Operation {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        match self {
-            Self::Read(address) => write!(f, "R:{:03x}", address),
-            Self::Write(address) => write!(f, "W:{:03x}", address),
+        let access_type = match self.access_type {
+            AccessType::Read => "R",
+            AccessType::Write => "W",
+            AccessType::Fetch => "I",
+        };
+        write!(f, "{access_type}:{:03x}", self.address)?;
+        if self.size != 1 {
+            write!(f, ":{}", self.size)?;
+        }
+        if self.asid != 0 {
+            write!(f, "@{}", self.asid)?;
         }
+        Ok(())
     }
 }
 
@@ -80,12 +437,14 @@ pub struct BlockAddress {
 }
 
 impl BlockAddress {
-    pub fn new(address: u64, index_bits: u64, offset_bits: u64) -> Self {
+    /// Split an address of `address_bits` bits into a tag, index and offset.
+    /// The tag gets the bits above the index and offset.
+    pub fn new(address: u64, address_bits: u64, index_bits: u64, offset_bits: u64) -> Self {
         let tag = address >> (index_bits + offset_bits);
         let index = (address >> offset_bits) & ((1 << index_bits) - 1);
         let offset = address & ((1 << offset_bits) - 1);
 
-        let tag_bits = 32 - index_bits - offset_bits;
+        let tag_bits = address_bits.saturating_sub(index_bits + offset_bits);
 
         Self {
             tag_bits,
@@ -107,43 +466,264 @@ impl BlockAddress {
     pub fn new_data_cache_address(address: u64, config: &SimulatorConfig) -> Self {
         let index_bits = config.get_data_cache_index_bits();
         let offset_bits = config.get_data_cache_offset_bits();
-        Self::new(address, index_bits, offset_bits)
+        Self::new(address, config.get_physical_address_bits(), index_bits, offset_bits)
     }
 
-    pub fn new_l2_cache_address(address: u64, config: &SimulatorConfig) -> Self {
-        let index_bits = config.get_l2_cache_index_bits();
-        let offset_bits = config.get_l2_cache_offset_bits();
-        Self::new(address, index_bits, offset_bits)
+    /// Split a physical address of `address_bits` bits for a cache.
+    pub fn new_cache_address(address: u64, address_bits: u64, cache: &CacheConfig) -> Self {
+        Self::new(address, address_bits, cache.get_index_bits(), cache.get_offset_bits())
     }
 
     pub fn new_page_table_address(address: u64, config: &SimulatorConfig) -> Self {
         let index_bits = config.get_page_table_index_bits();
         let offset_bits = config.get_page_table_offset_bits();
-        Self::new(address, index_bits, offset_bits)
+        Self::new(address, config.get_virtual_address_bits(), index_bits, offset_bits)
     }
 
     pub fn new_tlb_address(address: u64, config: &SimulatorConfig) -> Self {
         let index_bits = config.get_tlb_index_bits();
-        let page_number =
-            (address & !(config.get_page_size() - 1)) >> config.get_page_table_offset_bits();
-        Self::new(page_number, index_bits, 0)
+        let offset_bits = config.get_page_table_offset_bits();
+        let page_number = (address & !(config.get_page_size() - 1)) >> offset_bits;
+        let page_number_bits = config.get_virtual_address_bits().saturating_sub(offset_bits);
+        Self::new(page_number, page_number_bits, index_bits, 0)
     }
 }
 
 impl Display for BlockAddress {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        let addr = (self.tag << self.index_bits + self.offset_bits)
+        let addr = (self.tag << (self.index_bits + self.offset_bits))
             | (self.index << self.offset_bits)
             | self.offset;
         write!(f, "{:03x}", addr)
     }
 }
 
+/// Reads the operations of a trace one line at a time, without keeping them.
+///
+/// In strict mode, the first bad line is yielded as an error, and then the iterator ends.
+/// In lenient mode, bad lines are skipped with a warning and counted; only the first few
+/// errors are kept, since a long trace could have any number of them.
+pub struct TraceReader<R> {
+    source: TraceSource<R>,
+    options: TraceOptions,
+    /// An operation read along with the last one returned, waiting its turn.
+    pending: Option<Operation>,
+    /// The number of lines skipped in lenient mode.
+    skipped_lines: usize,
+    /// The errors for the first skipped lines.
+    skipped: Vec<TraceError>,
+    /// Has the reader stopped at an error?
+    failed: bool,
+    /// The address space switched to by the last context switch line of a native trace.
+    asid: u16,
+}
+
+impl<R> TraceReader<R>
+where
+    R: Read,
+{
+    /// The number of skipped lines whose errors are kept.
+    pub const KEPT_ERRORS: usize = 10;
+
+    /// Read a trace with the given options, or just a [`TraceMode`] for a native trace.
+    /// A binary trace is recognized by its magic number, whatever the format in the options.
+    pub fn new(mut reader: R, options: impl Into<TraceOptions>) -> Self {
+        // A read can return fewer bytes than asked for (from a pipe, say), so keep reading until the
+        // whole magic number is in or the trace ends. The bytes read are put back in front of the rest.
+        // If the start of the trace can't be read, reading it as text reports the error.
+        let mut start = Vec::with_capacity(binary::MAGIC.len());
+        let _ = reader.by_ref().take(binary::MAGIC.len() as u64).read_to_end(&mut start);
+        let is_binary = start == binary::MAGIC;
+        let reader = BufReader::new(Cursor::new(start).chain(reader));
+        let source = if is_binary {
+            TraceSource::Binary(BinaryDecoder::new(reader))
+        } else {
+            TraceSource::Text(LineReader::from_buffer(reader))
+        };
+        Self {
+            source,
+            options: options.into(),
+            pending: None,
+            skipped_lines: 0,
+            skipped: Vec::new(),
+            failed: false,
+            asid: 0,
+        }
+    }
+
+    /// The number of lines skipped so far in lenient mode.
+    pub fn skipped_lines(&self) -> usize {
+        self.skipped_lines
+    }
+
+    /// The errors for the first lines skipped in lenient mode (up to [`TraceReader::KEPT_ERRORS`]).
+    pub fn skipped(&self) -> &[TraceError] {
+        &self.skipped
+    }
+
+    /// Read the operations on the next line of the trace that has any (or the next record of a binary trace),
+    /// checking that they are within the address limit. Return None at the end of the trace.
+    fn next_record(&mut self) -> Option<Result<Record, TraceError>> {
+        let record = match self.read_record()? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e)),
+        };
+        let Some(limit) = self.options.address_limit else {
+            return Some(Ok(record));
+        };
+        let (first, then) = record;
+        match std::iter::once(first).chain(then).find(|operation| operation.last_address() >= limit) {
+            Some(operation) => {
+                let line = match &self.source {
+                    TraceSource::Text(buffer) => buffer.line_number(),
+                    TraceSource::Binary(decoder) => decoder.records(),
+                };
+                Some(Err(TraceError::OutOfRange {
+                    line,
+                    operation,
+                    limit,
+                }))
+            }
+            None => Some(Ok(record)),
+        }
+    }
+
+    /// Read the operations on the next line of the trace that has any (or the next record of a binary trace).
+    /// Return None at the end of the trace.
+    fn read_record(&mut self) -> Option<Result<Record, TraceError>> {
+        let buffer = match &mut self.source {
+            TraceSource::Text(buffer) => buffer,
+            TraceSource::Binary(decoder) => {
+                return decoder
+                    .next_operation()
+                    .map(|operation| operation.map(|operation| (operation, None)))
+            }
+        };
+        loop {
+            let line = match buffer.next_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(ConfigError::Io(message)) => return Some(Err(TraceError::Io(message))),
+                Err(e) => return Some(Err(TraceError::Io(e.to_string()))),
+            };
+            if self.options.format == TraceFormat::Native {
+                match parse_context_switch(buffer.line_number(), &line) {
+                    Some(Ok(asid)) => {
+                        self.asid = asid;
+                        continue;
+                    }
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {}
+                }
+            }
+            match self.options.format.parse_line(buffer.line_number(), &line, self.asid) {
+                Ok(None) => continue,
+                Ok(Some(record)) => return Some(Ok(record)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl<R> Iterator for TraceReader<R>
+where
+    R: Read,
+{
+    type Item = Result<Operation, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let operation = match self.pending.take() {
+                Some(operation) => operation,
+                None if self.failed => return None,
+                None => match (self.next_record()?, self.options.mode) {
+                    (Ok((operation, then)), _) => {
+                        self.pending = then;
+                        operation
+                    }
+                    // A binary trace can't be picked up again after a bad record, even in lenient mode.
+                    (Err(e @ (TraceError::Io(_) | TraceError::Binary { .. })), _)
+                    | (Err(e), TraceMode::Strict) => {
+                        self.failed = true;
+                        return Some(Err(e));
+                    }
+                    (Err(e), TraceMode::Lenient) => {
+                        warn!("Skipping bad trace line: {e}");
+                        self.skipped_lines += 1;
+                        if self.skipped.len() < Self::KEPT_ERRORS {
+                            self.skipped.push(e);
+                        }
+                        continue;
+                    }
+                },
+            };
+            if !(self.options.drop_fetches && operation.is_fetch()) {
+                return Some(Ok(operation));
+            }
+        }
+    }
+}
+
+/// Where a [`TraceReader`] gets its operations from: the start of the trace, read to look for
+/// the magic number of a binary trace, followed by the rest of it.
+enum TraceSource<R> {
+    /// A text trace, read one line at a time.
+    Text(LineReader<Chain<Cursor<Vec<u8>>, R>>),
+    /// A binary trace, read one record at a time.
+    Binary(BinaryDecoder<Chain<Cursor<Vec<u8>>, R>>),
+}
+
+/// Writes operations as a trace in any format, one at a time.
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    format: TraceFormat,
+    /// The encoder of a binary trace, which remembers the last address written.
+    encoder: Option<BinaryEncoder>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Start a trace in the given format. The header of a binary trace is written right away.
+    pub fn new(mut writer: W, format: TraceFormat) -> IoResult<Self> {
+        let encoder = match format {
+            TraceFormat::Binary(encoding) => Some(BinaryEncoder::new(&mut writer, encoding)?),
+            _ => None,
+        };
+        Ok(Self {
+            writer,
+            format,
+            encoder,
+        })
+    }
+
+    /// Write an operation to the trace.
+    /// Dinero traces have no sizes, so only the first byte of a larger operation is kept.
+    /// Neither Dinero nor Lackey traces have address spaces, so those are lost.
+    pub fn write(&mut self, operation: &Operation) -> IoResult<()> {
+        match (self.format, &mut self.encoder) {
+            (TraceFormat::Native, _) => writeln!(self.writer, "{operation}"),
+            (TraceFormat::Lackey, _) => lackey::write_operation(&mut self.writer, operation),
+            (TraceFormat::Dinero, _) => dinero::write_operation(&mut self.writer, operation),
+            (TraceFormat::Binary(_), Some(encoder)) => encoder.write(&mut self.writer, operation),
+            (TraceFormat::Binary(_), None) => unreachable!("binary traces always have an encoder"),
+        }
+    }
+
+    /// Flush the trace, and get the writer back.
+    pub fn finish(mut self) -> IoResult<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
 /// A trace of memory access operations to be performed by the simulator.
 #[derive(Clone, Debug)]
 pub struct Trace {
     /// The memory access operations to be performed by the simulator.
     pub operations: Vec<Operation>,
+    /// The number of lines that were skipped when the trace was read in lenient mode.
+    pub skipped_lines: usize,
+    /// The errors for the first skipped lines (up to [`TraceReader::KEPT_ERRORS`]).
+    pub skipped: Vec<TraceError>,
 }
 
 impl Trace {
@@ -151,6 +731,8 @@ impl Trace {
     pub fn new() -> Self {
         Self {
             operations: Vec::new(),
+            skipped_lines: 0,
+            skipped: Vec::new(),
         }
     }
 
@@ -166,24 +748,32 @@ impl Trace {
         self.operations.get(index).copied()
     }
 
+    /// Reads a trace from any reader, with the given options (or just a [`TraceMode`] for a native trace).
+    /// In strict mode, the first bad line is an error. In lenient mode, bad lines are skipped
+    /// with a warning, and counted in [`Trace::skipped_lines`].
+    pub fn from_reader<R>(reader: R, options: impl Into<TraceOptions>) -> Result<Self, TraceError>
+    where
+        R: Read,
+    {
+        let mut reader = TraceReader::new(reader, options);
+        let operations = reader.by_ref().collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            operations,
+            skipped_lines: reader.skipped_lines(),
+            skipped: reader.skipped,
+        })
+    }
+
     /// Reads a trace from stdin.
-    pub fn from_stdin() -> Self {
-        let mut buffer = BufReader::new(std::io::stdin());
-        let mut trace = Self::new();
-        while let Some(operation) = Operation::from_buffer(&mut buffer) {
-            trace.operations.push(operation);
-        }
-        trace
+    pub fn from_stdin(options: impl Into<TraceOptions>) -> Result<Self, TraceError> {
+        Self::from_reader(std::io::stdin(), options)
     }
 
-    pub fn from_file(filename: &str) -> Self {
-        let file = std::fs::File::open(filename).unwrap();
-        let mut buffer = BufReader::new(file);
-        let mut trace = Self::new();
-        while let Some(operation) = Operation::from_buffer(&mut buffer) {
-            trace.operations.push(operation);
-        }
-        trace
+    /// Reads a trace from a file.
+    pub fn from_file(filename: &str, options: impl Into<TraceOptions>) -> Result<Self, TraceError> {
+        let file = std::fs::File::open(filename)
+            .map_err(|e| TraceError::Io(format!("could not open \"{filename}\": {e}")))?;
+        Self::from_reader(file, options)
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &Operation> {
@@ -194,13 +784,21 @@ impl Trace {
         self.operations.iter_mut()
     }
 
-    pub fn into_iter(self) -> impl Iterator<Item = Operation> {
-        self.operations.into_iter()
-    }
-
     pub fn push(&mut self, operation: Operation) {
         self.operations.push(operation);
     }
+
+    /// Write the trace in the given format.
+    pub fn write<W>(&self, writer: W, format: TraceFormat) -> IoResult<W>
+    where
+        W: Write,
+    {
+        let mut writer = TraceWriter::new(writer, format)?;
+        for operation in &self.operations {
+            writer.write(operation)?;
+        }
+        writer.finish()
+    }
 }
 
 impl Default for Trace {
@@ -209,6 +807,15 @@ impl Default for Trace {
     }
 }
 
+impl FromStr for Trace {
+    type Err = TraceError;
+
+    /// Read a trace in strict mode.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_reader(s.as_bytes(), TraceMode::Strict)
+    }
+}
+
 impl IntoIterator for Trace {
     type Item = Operation;
     type IntoIter = std::vec::IntoIter<Self::Item>;
@@ -229,3 +836,138 @@ impl Display for Trace {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strict_mode_fails_at_the_first_bad_line() {
+        let error = "R:10\n\nX:20\nR:30\n".parse::<Trace>().unwrap_err();
+        assert!(matches!(error, TraceError::BadAccessType { line: 3, .. }), "{error:?}");
+    }
+
+    #[test]
+    fn bad_lines_are_reported_by_kind() {
+        let error = |text: &str| text.parse::<Trace>().unwrap_err();
+        assert!(matches!(error("R"), TraceError::Malformed { line: 1, .. }));
+        assert!(matches!(error("R:1:2:3"), TraceError::Malformed { line: 1, .. }));
+        assert!(matches!(error("R:zz"), TraceError::BadAddress { line: 1, .. }));
+    }
+
+    #[test]
+    fn lenient_mode_skips_bad_lines() {
+        let trace = Trace::from_reader("R:10\nbad\nW:20\nR:zz\n".as_bytes(), TraceMode::Lenient).unwrap();
+        assert_eq!(trace.operations, vec![Operation::read(0x10), Operation::write(0x20)]);
+        assert_eq!(trace.skipped_lines, 2);
+        assert!(matches!(trace.skipped[0], TraceError::Malformed { line: 2, .. }));
+        assert!(matches!(trace.skipped[1], TraceError::BadAddress { line: 4, .. }));
+    }
+
+    #[test]
+    fn lenient_mode_keeps_only_the_first_errors() {
+        let text = "bad\n".repeat(TraceReader::<&[u8]>::KEPT_ERRORS + 5);
+        let trace = Trace::from_reader(text.as_bytes(), TraceMode::Lenient).unwrap();
+        assert!(trace.is_empty());
+        assert_eq!(trace.skipped_lines, TraceReader::<&[u8]>::KEPT_ERRORS + 5);
+        assert_eq!(trace.skipped.len(), TraceReader::<&[u8]>::KEPT_ERRORS);
+    }
+
+    #[test]
+    fn sizes_are_parsed_and_checked() {
+        let trace: Trace = "R:1ffc:8
+W:20".parse().unwrap();
+        assert_eq!(trace.operations, vec![Operation::read(0x1ffc).with_size(8), Operation::write(0x20)]);
+        let error = |text: &str| text.parse::<Trace>().unwrap_err();
+        assert!(matches!(error("R:10:0"), TraceError::BadSize { line: 1, .. }));
+        assert!(matches!(error("R:ffffffffffffffff:2"), TraceError::BadSize { line: 1, .. }));
+    }
+
+    #[test]
+    fn an_access_within_a_line_is_not_split() {
+        let access = Operation::read(0x10).with_size(16);
+        assert!(!access.straddles(16));
+        assert_eq!(access.split(16).collect::<Vec<_>>(), vec![access]);
+    }
+
+    #[test]
+    fn an_access_straddling_lines_is_split_at_each_boundary() {
+        let access = Operation::write(0x1c).with_size(40).with_asid(3);
+        assert!(access.straddles(16));
+        assert_eq!(
+            access.split(16).collect::<Vec<_>>(),
+            vec![
+                Operation::write(0x1c).with_size(4).with_asid(3),
+                Operation::write(0x20).with_size(16).with_asid(3),
+                Operation::write(0x30).with_size(16).with_asid(3),
+                Operation::write(0x40).with_size(4).with_asid(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn an_access_straddling_a_page_is_split_at_the_page() {
+        let access = Operation::read(0xffe).with_size(4);
+        assert!(access.straddles(4096));
+        assert!(!access.straddles(8192));
+        assert_eq!(
+            access.split(4096).collect::<Vec<_>>(),
+            vec![Operation::read(0xffe).with_size(2), Operation::read(0x1000).with_size(2)]
+        );
+    }
+
+    #[test]
+    fn an_access_ending_at_the_last_address_is_split() {
+        let access = Operation::read(u64::MAX - 5).with_size(6);
+        let pieces: Vec<_> = access.split(4).collect();
+        assert_eq!(pieces.last().unwrap().last_address(), u64::MAX);
+        assert_eq!(pieces.iter().map(Operation::size).sum::<u64>(), 6);
+    }
+
+    #[test]
+    fn context_switches_and_suffixes_set_the_address_space() {
+        let trace: Trace = "R:10\nC:2\nW:20 # process 2\nR:30@5\nC:0\nR:40:4\n".parse().unwrap();
+        assert_eq!(
+            trace.operations,
+            vec![
+                Operation::read(0x10),
+                Operation::write(0x20).with_asid(2),
+                Operation::read(0x30).with_asid(5),
+                Operation::read(0x40).with_size(4),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_address_space_ids_are_rejected() {
+        let error = |text: &str| text.parse::<Trace>().unwrap_err();
+        assert!(matches!(error("R:10\nC:x"), TraceError::BadAsid { line: 2, .. }));
+        assert!(matches!(error("C:65536"), TraceError::BadAsid { line: 1, .. }));
+        assert!(matches!(error("R:10@-1"), TraceError::BadAsid { line: 1, .. }));
+    }
+
+    #[test]
+    fn address_spaces_are_written_to_native_traces() {
+        let trace = Trace {
+            operations: vec![Operation::read(0x10).with_asid(3), Operation::write(0x20).with_size(2)],
+            ..Trace::new()
+        };
+        assert_eq!(trace.to_string().parse::<Trace>().unwrap().operations, trace.operations);
+    }
+
+    #[test]
+    fn a_48_bit_address_is_split_into_tag_index_and_offset() {
+        let address = BlockAddress::new(0xba98_7654_3210, 48, 6, 5);
+        assert_eq!((address.tag, address.index, address.offset), (0x17_530e_ca86, 0x10, 0x10));
+        assert_eq!(address.tag_bits, 37);
+        assert_eq!(address.get_address(), 0xba98_7654_3210);
+    }
+
+    #[test]
+    fn a_64_bit_address_keeps_its_top_bits_in_the_tag() {
+        let address = BlockAddress::new(u64::MAX, 64, 8, 6);
+        assert_eq!((address.tag, address.index, address.offset), (u64::MAX >> 14, 0xff, 0x3f));
+        assert_eq!(address.tag_bits, 50);
+        assert_eq!(address.get_address(), u64::MAX);
+    }
+}
@@ -0,0 +1,518 @@
+use super::{Operation, Trace};
+use core::fmt::{Display, Formatter, Result as FmtResult};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+/// An error in the settings of a [`TraceGenerator`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceGenError {
+    /// The name isn't one of [`Pattern::NAMES`].
+    UnknownPattern(String),
+    /// A parameter (`key=value`) names an unknown key or has a bad value.
+    BadParameter { assignment: String, message: String },
+    /// The parameters don't describe a workload that can be generated.
+    Invalid(String),
+}
+
+impl Display for TraceGenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::UnknownPattern(name) => write!(
+                f,
+                "unknown pattern \"{name}\", expected one of: {}",
+                Pattern::NAMES.join(", ")
+            ),
+            Self::BadParameter {
+                assignment,
+                message,
+            } => write!(f, "bad parameter \"{assignment}\": {message}"),
+            Self::Invalid(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for TraceGenError {}
+
+/// The access patterns a [`TraceGenerator`] can produce.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    /// Every element of the footprint in order, wrapping around at the end.
+    Sequential,
+    /// Every `stride` bytes through the footprint, wrapping around at the end.
+    Stride,
+    /// Elements of the footprint picked uniformly at random.
+    Uniform,
+    /// Elements of the footprint picked with Zipfian popularity, so a few hot elements get most accesses.
+    /// The hot elements are scattered over the footprint.
+    Zipf,
+    /// Following the `next` pointers of a linked list whose nodes are a random cycle through the footprint.
+    PointerChase,
+    /// The loads and stores of `C = A * B` for `n` by `n` matrices, tiled if `block` is set.
+    MatMul,
+    /// The loads and stores of `B = transpose(A)` for `n` by `n` matrices, tiled if `block` is set.
+    Transpose,
+}
+
+impl Pattern {
+    /// The names of the patterns, as accepted by [`Pattern::from_name`].
+    pub const NAMES: &'static [&'static str] = &[
+        "sequential",
+        "stride",
+        "uniform",
+        "zipf",
+        "pointer-chase",
+        "matmul",
+        "transpose",
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sequential" => Some(Self::Sequential),
+            "stride" => Some(Self::Stride),
+            "uniform" => Some(Self::Uniform),
+            "zipf" => Some(Self::Zipf),
+            "pointer-chase" => Some(Self::PointerChase),
+            "matmul" => Some(Self::MatMul),
+            "transpose" => Some(Self::Transpose),
+            _ => None,
+        }
+    }
+}
+
+/// The largest number of elements the zipf and pointer-chase patterns can spread their accesses over,
+/// since they keep a table with an entry per element.
+pub const MAX_TRACKED_ELEMENTS: u64 = 1 << 24;
+
+/// Generates a synthetic trace with a known access pattern.
+///
+/// The same settings and seed always generate the same trace.
+#[derive(Clone, Debug)]
+pub struct TraceGenerator {
+    pub pattern: Pattern,
+    /// The number of accesses to generate. The matrix kernels ignore this,
+    /// since their length follows from the size of the matrices.
+    pub count: u64,
+    /// The lowest address accessed.
+    pub base: u64,
+    /// The number of bytes the accesses are spread over, starting at `base`.
+    pub footprint: u64,
+    /// The number of bytes in each element, and in each access.
+    pub element_size: u64,
+    /// The distance in bytes between accesses of the stride pattern.
+    pub stride: u64,
+    /// The exponent of the Zipfian distribution. Larger exponents make the hot set smaller.
+    pub exponent: f64,
+    /// The number of rows (and columns) of each matrix of the matrix kernels.
+    pub matrix_size: u64,
+    /// The number of rows (and columns) of each tile of the matrix kernels, or 0 for no tiling.
+    pub block_size: u64,
+    /// The fraction of loads that are made into stores. Every access of the simple patterns
+    /// is a load, so this is their fraction of writes. The stores of the matrix kernels are always writes.
+    pub write_fraction: f64,
+    /// The seed of the random numbers used by the pattern and the write mix.
+    pub seed: u64,
+}
+
+impl TraceGenerator {
+    /// The keys accepted by [`TraceGenerator::set`].
+    pub const KEYS: &'static [&'static str] = &[
+        "count", "base", "footprint", "size", "stride", "exponent", "n", "block", "writes", "seed",
+    ];
+
+    /// Create a generator for a pattern with the default settings: 100000 accesses of 8 bytes over
+    /// a 64 KiB footprint at address 0, a 64 byte stride, a Zipf exponent of 1, 64 by 64 untiled
+    /// matrices, no writes, and a seed of 0.
+    pub fn new(pattern: Pattern) -> Self {
+        Self {
+            pattern,
+            count: 100_000,
+            base: 0,
+            footprint: 64 * 1024,
+            element_size: 8,
+            stride: 64,
+            exponent: 1.0,
+            matrix_size: 64,
+            block_size: 0,
+            write_fraction: 0.0,
+            seed: 0,
+        }
+    }
+
+    /// Change a single setting, named by one of [`TraceGenerator::KEYS`], to the value parsed from text.
+    /// The base address is hexadecimal, and the other numbers are decimal.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), TraceGenError> {
+        let bad_parameter = |message: String| TraceGenError::BadParameter {
+            assignment: format!("{key}={value}"),
+            message,
+        };
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| bad_parameter(format!("expected a number, got \"{value}\"")))
+        };
+        let fraction = || {
+            value
+                .parse::<f64>()
+                .map_err(|_| bad_parameter(format!("expected a number, got \"{value}\"")))
+        };
+
+        match key {
+            "count" => self.count = number()?,
+            "base" => {
+                let digits = value.strip_prefix("0x").unwrap_or(value);
+                self.base = u64::from_str_radix(digits, 16).map_err(|_| {
+                    bad_parameter(format!("expected a hexadecimal address, got \"{value}\""))
+                })?;
+            }
+            "footprint" => self.footprint = number()?,
+            "size" => self.element_size = number()?,
+            "stride" => self.stride = number()?,
+            "exponent" => self.exponent = fraction()?,
+            "n" => self.matrix_size = number()?,
+            "block" => self.block_size = number()?,
+            "writes" => self.write_fraction = fraction()?,
+            "seed" => self.seed = number()?,
+            _ => {
+                return Err(bad_parameter(format!(
+                    "unknown key \"{key}\", expected one of: {}",
+                    Self::KEYS.join(", ")
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a setting written as `key=value`, such as `count=1000`.
+    pub fn apply_parameter(&mut self, assignment: &str) -> Result<(), TraceGenError> {
+        match assignment.split_once('=') {
+            Some((key, value)) => self.set(key.trim(), value.trim()),
+            None => Err(TraceGenError::BadParameter {
+                assignment: assignment.to_owned(),
+                message: "expected \"key=value\"".to_owned(),
+            }),
+        }
+    }
+
+    /// The number of elements in the footprint.
+    fn elements(&self) -> u64 {
+        self.footprint / self.element_size
+    }
+
+    /// Check that the settings describe a workload that fits in the address space.
+    fn validate(&self) -> Result<(), TraceGenError> {
+        let invalid = |message: String| Err(TraceGenError::Invalid(message));
+        if self.element_size == 0 {
+            return invalid("the element size must be at least 1 byte".to_owned());
+        }
+        if !(0.0..=1.0).contains(&self.write_fraction) {
+            return invalid(format!(
+                "the fraction of writes ({}) must be between 0 and 1",
+                self.write_fraction
+            ));
+        }
+        let footprint = match self.pattern {
+            Pattern::MatMul | Pattern::Transpose => {
+                if self.matrix_size == 0 {
+                    return invalid("the matrices must have at least one row".to_owned());
+                }
+                let matrices = if self.pattern == Pattern::MatMul { 3 } else { 2 };
+                self.matrix_size
+                    .checked_mul(self.matrix_size)
+                    .and_then(|elements| elements.checked_mul(self.element_size))
+                    .and_then(|bytes| bytes.checked_mul(matrices))
+            }
+            _ => {
+                if self.elements() == 0 {
+                    return invalid(format!(
+                        "the footprint ({}) must hold at least one element of {} bytes",
+                        self.footprint, self.element_size
+                    ));
+                }
+                if matches!(self.pattern, Pattern::Zipf | Pattern::PointerChase)
+                    && self.elements() > MAX_TRACKED_ELEMENTS
+                {
+                    return invalid(format!(
+                        "the footprint ({}) holds {} elements of {} bytes, but this pattern can only \
+                         spread its accesses over {MAX_TRACKED_ELEMENTS}",
+                        self.footprint,
+                        self.elements(),
+                        self.element_size
+                    ));
+                }
+                if self.pattern == Pattern::Stride && self.stride == 0 {
+                    return invalid("the stride must be at least 1 byte".to_owned());
+                }
+                if self.pattern == Pattern::Zipf && !(0.0..).contains(&self.exponent) {
+                    return invalid(format!(
+                        "the Zipf exponent ({}) must not be negative",
+                        self.exponent
+                    ));
+                }
+                Some(self.footprint)
+            }
+        };
+        match footprint.and_then(|footprint| self.base.checked_add(footprint)) {
+            Some(_) => Ok(()),
+            None => invalid("the accesses run past the largest address".to_owned()),
+        }
+    }
+
+    /// Generate the trace.
+    pub fn generate(&self) -> Result<Trace, TraceGenError> {
+        self.validate()?;
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        // The addresses of the loads, in order, with the stores marked.
+        let mut accesses: Vec<(u64, bool)> = vec![];
+        let element = |index: u64| self.base + index * self.element_size;
+
+        match self.pattern {
+            Pattern::Sequential => {
+                accesses.extend((0..self.count).map(|i| (element(i % self.elements()), false)));
+            }
+            Pattern::Stride => {
+                // Only whole elements are accessed, so the last stride before the end wraps around,
+                // and a stride that lands inside an element accesses the whole element.
+                let span = self.elements() * self.element_size;
+                let offset = |i: u64| {
+                    let offset = (u128::from(i) * u128::from(self.stride) % u128::from(span)) as u64;
+                    offset - offset % self.element_size
+                };
+                accesses.extend((0..self.count).map(|i| (self.base + offset(i), false)));
+            }
+            Pattern::Uniform => {
+                for _ in 0..self.count {
+                    accesses.push((element(rng.gen_range(0..self.elements())), false));
+                }
+            }
+            Pattern::Zipf => {
+                // The probability of the element of rank k is proportional to 1 / k^exponent.
+                let mut cumulative = Vec::with_capacity(self.elements() as usize);
+                let mut total = 0.0;
+                for rank in 1..=self.elements() {
+                    total += 1.0 / (rank as f64).powf(self.exponent);
+                    cumulative.push(total);
+                }
+                let mut elements: Vec<u64> = (0..self.elements()).collect();
+                elements.shuffle(&mut rng);
+                for _ in 0..self.count {
+                    let target = rng.gen::<f64>() * total;
+                    let rank = cumulative.partition_point(|&weight| weight < target);
+                    accesses.push((element(elements[rank.min(elements.len() - 1)]), false));
+                }
+            }
+            Pattern::PointerChase => {
+                // Sattolo's algorithm shuffles the nodes into a single cycle, so the chase visits them all.
+                let nodes = self.elements() as usize;
+                let mut next: Vec<usize> = (0..nodes).collect();
+                for i in (1..nodes).rev() {
+                    next.swap(i, rng.gen_range(0..i));
+                }
+                let mut node = 0;
+                for _ in 0..self.count {
+                    accesses.push((element(node as u64), false));
+                    node = next[node];
+                }
+            }
+            Pattern::MatMul => self.matmul(&mut accesses),
+            Pattern::Transpose => self.transpose(&mut accesses),
+        }
+
+        let mut trace = Trace::new();
+        for (address, is_store) in accesses {
+            let operation = if is_store || rng.gen_bool(self.write_fraction) {
+                Operation::write(address)
+            } else {
+                Operation::read(address)
+            };
+            trace.push(operation.with_size(self.element_size));
+        }
+        Ok(trace)
+    }
+
+    /// The tiles of a loop over `0..n`, as ranges of indices. Without tiling, there is just one.
+    fn tiles(&self) -> Vec<std::ops::Range<u64>> {
+        let n = self.matrix_size;
+        let block = if self.block_size == 0 { n } else { self.block_size };
+        (0..n)
+            .step_by(block as usize)
+            .map(|start| start..(start + block).min(n))
+            .collect()
+    }
+
+    /// The address of the element in row `i` and column `j` of the matrix at position `matrix`
+    /// (the matrices are stored one after another, in row-major order).
+    fn matrix_element(&self, matrix: u64, i: u64, j: u64) -> u64 {
+        let n = self.matrix_size;
+        self.base + ((matrix * n + i) * n + j) * self.element_size
+    }
+
+    /// `C[i][j] += A[i][k] * B[k][j]`, with A, B and C stored in that order.
+    /// Without tiling, each element of C is stored once after its dot product.
+    /// With tiling, it is loaded and stored once per tile of the k loop.
+    fn matmul(&self, accesses: &mut Vec<(u64, bool)>) {
+        let (a, b, c) = (0, 1, 2);
+        let tiles = self.tiles();
+        let tiled = tiles.len() > 1;
+        for rows in &tiles {
+            for columns in &tiles {
+                for inner in &tiles {
+                    for i in rows.clone() {
+                        for j in columns.clone() {
+                            if tiled {
+                                accesses.push((self.matrix_element(c, i, j), false));
+                            }
+                            for k in inner.clone() {
+                                accesses.push((self.matrix_element(a, i, k), false));
+                                accesses.push((self.matrix_element(b, k, j), false));
+                            }
+                            accesses.push((self.matrix_element(c, i, j), true));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// `B[j][i] = A[i][j]`, with A and B stored in that order.
+    fn transpose(&self, accesses: &mut Vec<(u64, bool)>) {
+        let (a, b) = (0, 1);
+        let tiles = self.tiles();
+        for rows in &tiles {
+            for columns in &tiles {
+                for i in rows.clone() {
+                    for j in columns.clone() {
+                        accesses.push((self.matrix_element(a, i, j), false));
+                        accesses.push((self.matrix_element(b, j, i), true));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A generator of a pattern with its settings overridden by `key=value` parameters.
+    fn generator(name: &str, parameters: &[&str]) -> TraceGenerator {
+        let mut generator = TraceGenerator::new(Pattern::from_name(name).unwrap());
+        for parameter in parameters {
+            generator.apply_parameter(parameter).unwrap();
+        }
+        generator
+    }
+
+    fn addresses(trace: &Trace) -> Vec<u64> {
+        trace.operations.iter().map(|operation| operation.address).collect()
+    }
+
+    /// The addresses of the reads, and of the writes.
+    fn accesses_by_kind(trace: &Trace) -> (Vec<u64>, Vec<u64>) {
+        let (reads, writes): (Vec<&Operation>, Vec<&Operation>) =
+            trace.operations.iter().partition(|operation| operation.is_read());
+        let addresses = |operations: Vec<&Operation>| operations.iter().map(|operation| operation.address).collect();
+        (addresses(reads), addresses(writes))
+    }
+
+    #[test]
+    fn the_same_seed_generates_the_same_trace() {
+        for name in Pattern::NAMES {
+            let parameters = ["count=500", "footprint=4096", "n=6", "block=4", "writes=0.3", "seed=7"];
+            let first = generator(name, &parameters).generate().unwrap();
+            let second = generator(name, &parameters).generate().unwrap();
+            assert_eq!(first.operations, second.operations, "{name}");
+        }
+        // Another seed changes the random choices.
+        for name in ["uniform", "zipf", "pointer-chase"] {
+            let first = generator(name, &["count=500", "seed=1"]).generate().unwrap();
+            let second = generator(name, &["count=500", "seed=2"]).generate().unwrap();
+            assert_ne!(addresses(&first), addresses(&second), "{name}");
+        }
+    }
+
+    #[test]
+    fn simple_patterns_stay_within_the_footprint() {
+        for name in ["sequential", "stride", "uniform", "zipf", "pointer-chase"] {
+            let trace = generator(name, &["count=2000", "base=1000", "footprint=1024", "size=4", "stride=24"])
+                .generate()
+                .unwrap();
+            assert_eq!(trace.operations.len(), 2000, "{name}");
+            for operation in &trace.operations {
+                assert!((0x1000..0x1400).contains(&operation.address), "{name}: {operation}");
+                assert_eq!(operation.address % 4, 0, "{name}: {operation}");
+                assert_eq!((operation.size, operation.is_read()), (4, true), "{name}: {operation}");
+            }
+        }
+    }
+
+    #[test]
+    fn sequential_and_stride_wrap_around_the_footprint() {
+        let trace = generator("sequential", &["count=6", "footprint=32"]).generate().unwrap();
+        assert_eq!(addresses(&trace), [0, 8, 16, 24, 0, 8]);
+        // A stride of 20 lands inside elements, which are accessed whole, and 80 wraps around to 16.
+        let trace = generator("stride", &["count=5", "footprint=64", "stride=20"]).generate().unwrap();
+        assert_eq!(addresses(&trace), [0, 16, 40, 56, 16]);
+    }
+
+    #[test]
+    fn pointer_chase_visits_every_element_once_per_lap() {
+        let trace = generator("pointer-chase", &["count=128", "footprint=512"]).generate().unwrap();
+        let addresses = addresses(&trace);
+        let mut lap = addresses[..64].to_vec();
+        lap.sort();
+        assert_eq!(lap, (0..64).map(|i| i * 8).collect::<Vec<_>>());
+        assert_eq!(addresses[64..], addresses[..64]);
+    }
+
+    #[test]
+    fn zipf_favors_a_few_hot_elements() {
+        let trace = generator("zipf", &["count=10000", "footprint=8192", "exponent=1.5"]).generate().unwrap();
+        let mut counts = std::collections::HashMap::new();
+        for address in addresses(&trace) {
+            *counts.entry(address).or_insert(0) += 1;
+        }
+        // With 1024 elements, the hottest gets over a third of the accesses.
+        assert!(counts.values().any(|&count| count > 3333), "{counts:?}");
+    }
+
+    #[test]
+    fn matrix_kernels_touch_each_matrix() {
+        // C = A * B for 2 by 2 matrices: 2 loads for each k, and a store, for each element of C.
+        let trace = generator("matmul", &["n=2", "size=4", "base=100"]).generate().unwrap();
+        assert_eq!(trace.operations.len(), 4 * (2 * 2 + 1));
+        assert_eq!(accesses_by_kind(&trace).1, [0x120, 0x124, 0x128, 0x12c]);
+        assert!(trace.operations.iter().all(|operation| (0x100..0x130).contains(&operation.address)));
+
+        // B = transpose(A) for 3 by 3 matrices: A is read in row order and B written in column order.
+        let trace = generator("transpose", &["n=3", "size=1"]).generate().unwrap();
+        let (reads, writes) = accesses_by_kind(&trace);
+        assert_eq!(reads, (0..9).collect::<Vec<_>>());
+        assert_eq!(writes, [9, 12, 15, 10, 13, 16, 11, 14, 17]);
+    }
+
+    #[test]
+    fn huge_footprints_are_rejected_instead_of_allocated() {
+        for name in ["zipf", "pointer-chase"] {
+            let error = generator(name, &["footprint=1125899906842624", "size=1", "count=10"]).generate().unwrap_err();
+            assert!(matches!(error, TraceGenError::Invalid(_)), "{name}: {error}");
+        }
+        // The other patterns don't keep anything per element.
+        let trace = generator("uniform", &["footprint=1125899906842624", "size=1", "count=10"]).generate().unwrap();
+        assert_eq!(trace.operations.len(), 10);
+    }
+
+    #[test]
+    fn bad_settings_are_rejected() {
+        let error = |name: &str, parameters: &[&str]| generator(name, parameters).generate().unwrap_err();
+        assert!(matches!(error("sequential", &["size=0"]), TraceGenError::Invalid(_)));
+        assert!(matches!(error("sequential", &["footprint=4"]), TraceGenError::Invalid(_)));
+        assert!(matches!(error("stride", &["stride=0"]), TraceGenError::Invalid(_)));
+        assert!(matches!(error("zipf", &["exponent=-1"]), TraceGenError::Invalid(_)));
+        assert!(matches!(error("uniform", &["writes=1.5"]), TraceGenError::Invalid(_)));
+        assert!(matches!(error("matmul", &["n=0"]), TraceGenError::Invalid(_)));
+        assert!(matches!(error("sequential", &["base=ffffffffffffff00", "footprint=512"]), TraceGenError::Invalid(_)));
+        let mut generator = TraceGenerator::new(Pattern::Uniform);
+        assert!(matches!(generator.apply_parameter("colour=red"), Err(TraceGenError::BadParameter { .. })));
+        assert!(matches!(generator.apply_parameter("count"), Err(TraceGenError::BadParameter { .. })));
+    }
+}
@@ -0,0 +1,446 @@
+use super::{AccessType, Operation, Trace, TraceFormat, TraceOptions};
+use core::fmt::{Display, Formatter, Result as FmtResult};
+use std::ops::Range;
+
+/// An error in a [`Transform`], or from applying one to a trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransformError {
+    /// The text of a transform couldn't be parsed.
+    BadTransform { text: String, message: String },
+    /// The transform can't be applied to any trace.
+    Invalid(String),
+    /// The transform moved an access outside of the 64-bit address space.
+    OutOfRange { index: usize, operation: Operation },
+}
+
+impl Display for TransformError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::BadTransform { text, message } => write!(f, "bad transform \"{text}\": {message}"),
+            Self::Invalid(message) => write!(f, "{message}"),
+            Self::OutOfRange { index, operation } => write!(
+                f,
+                "access {index} ({operation}) would be moved outside of the address space"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+/// A change to a trace, such as making every access a read or keeping only part of it.
+///
+/// Transforms that change access types leave instruction fetches alone.
+#[derive(Clone, Debug)]
+pub enum Transform {
+    /// Make every write a read.
+    ForceReads,
+    /// Make every read a write.
+    ForceWrites,
+    /// Make every read a write, and every write a read.
+    SwapReadsAndWrites,
+    /// Add a (possibly negative) offset to every address.
+    Offset(i64),
+    /// Keep only the bits of every address that are set in the mask.
+    Mask(u64),
+    /// Shift every address left by a number of bits.
+    ShiftLeft(u32),
+    /// Shift every address right by a number of bits.
+    ShiftRight(u32),
+    /// Keep the accesses with these indices. The end may be past the end of the trace.
+    Window(Range<usize>),
+    /// Keep every nth access, starting with the first.
+    Sample(usize),
+    /// Drop the accesses that start in this range of addresses.
+    DropAddresses(Range<u64>),
+    /// Alternate between the accesses of the trace and those of another trace, starting with the trace.
+    /// Once either runs out, the rest of the other follows.
+    Interleave(Trace),
+    /// Drop every access of the same type, by the same address space, to the same lines as
+    /// the access before it, for lines of the given size in bytes.
+    Dedupe(u64),
+    /// Make every access in the address space with this ID, such as before interleaving
+    /// it with the trace of another process.
+    Asid(u16),
+}
+
+impl Transform {
+    /// The forms of the transforms accepted by [`Transform::parse`].
+    pub const SYNTAX: &'static [&'static str] = &[
+        "reads",
+        "writes",
+        "swap",
+        "add=<hex offset>",
+        "mask=<hex mask>",
+        "shl=<bits>",
+        "shr=<bits>",
+        "window=<start>..<end>",
+        "sample=<n>",
+        "drop=<hex start>..<hex end>",
+        "interleave=<trace path>",
+        "dedupe=<line size>",
+        "asid=<id>",
+    ];
+
+    /// Parse a transform written as one of [`Transform::SYNTAX`], such as `window=1000..2000`.
+    /// Addresses and masks are hexadecimal, and other numbers are decimal. The trace of
+    /// `interleave` is read from the given path in strict mode, in the format its extension suggests.
+    pub fn parse(text: &str) -> Result<Self, TransformError> {
+        let bad_transform = |message: String| TransformError::BadTransform {
+            text: text.to_owned(),
+            message,
+        };
+        let (name, value) = match text.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (text.trim(), None),
+        };
+        let argument = || value.ok_or_else(|| bad_transform(format!("{name} expects a value")));
+        let number = |value: &str| {
+            value
+                .parse::<u64>()
+                .map_err(|_| bad_transform(format!("expected a number, got \"{value}\"")))
+        };
+        let hex = |value: &str| {
+            u64::from_str_radix(value.strip_prefix("0x").unwrap_or(value), 16)
+                .map_err(|_| bad_transform(format!("expected a hexadecimal number, got \"{value}\"")))
+        };
+        let range = || {
+            let value = argument()?;
+            let not_range = || bad_transform(format!("expected \"start..end\", got \"{value}\""));
+            value.split_once("..").ok_or_else(not_range)
+        };
+        let bits = |value: &str| match value.parse::<u32>() {
+            Ok(bits) if bits < u64::BITS => Ok(bits),
+            _ => Err(bad_transform(format!(
+                "expected a number of bits below {}, got \"{value}\"",
+                u64::BITS
+            ))),
+        };
+
+        let transform = match (name, value) {
+            ("reads", None) => Self::ForceReads,
+            ("writes", None) => Self::ForceWrites,
+            ("swap", None) => Self::SwapReadsAndWrites,
+            ("add", _) => {
+                let value = argument()?;
+                // A negative offset can have a magnitude one larger than a positive one.
+                let offset = match value.strip_prefix('-') {
+                    Some(magnitude) => 0i64.checked_sub_unsigned(hex(magnitude)?),
+                    None => i64::try_from(hex(value)?).ok(),
+                };
+                Self::Offset(offset.ok_or_else(|| bad_transform("the offset is too large".to_owned()))?)
+            }
+            ("mask", _) => Self::Mask(hex(argument()?)?),
+            ("shl", _) => Self::ShiftLeft(bits(argument()?)?),
+            ("shr", _) => Self::ShiftRight(bits(argument()?)?),
+            ("window", _) => {
+                let (start, end) = range()?;
+                let (start, end) = (number(start)?, number(end)?);
+                Self::Window(start as usize..end as usize)
+            }
+            ("sample", _) => Self::Sample(number(argument()?)? as usize),
+            ("drop", _) => {
+                let (start, end) = range()?;
+                Self::DropAddresses(hex(start)?..hex(end)?)
+            }
+            ("interleave", _) => {
+                let path = argument()?;
+                let options = TraceOptions {
+                    format: TraceFormat::from_path(path),
+                    ..TraceOptions::default()
+                };
+                let trace = Trace::from_file(path, options)
+                    .map_err(|e| bad_transform(format!("could not read the trace: {e}")))?;
+                Self::Interleave(trace)
+            }
+            ("dedupe", _) => Self::Dedupe(number(argument()?)?),
+            ("asid", _) => {
+                let value = argument()?;
+                Self::Asid(value.parse().map_err(|_| {
+                    bad_transform(format!("expected an ID up to {}, got \"{value}\"", u16::MAX))
+                })?)
+            }
+            ("reads" | "writes" | "swap", Some(_)) => {
+                return Err(bad_transform(format!("{name} doesn't take a value")))
+            }
+            _ => {
+                return Err(bad_transform(format!(
+                    "expected one of: {}",
+                    Self::SYNTAX.join(", ")
+                )))
+            }
+        };
+        transform.check()?;
+        Ok(transform)
+    }
+
+    /// Check the settings of the transform, which parsing also does.
+    fn check(&self) -> Result<(), TransformError> {
+        let invalid = |message: &str| Err(TransformError::Invalid(message.to_owned()));
+        match self {
+            Self::Window(range) if range.start > range.end => {
+                invalid("the window must not end before it starts")
+            }
+            Self::DropAddresses(range) if range.start > range.end => {
+                invalid("the range of addresses must not end before it starts")
+            }
+            Self::Sample(0) => invalid("the sampling interval must be at least 1"),
+            Self::Dedupe(0) => invalid("the line size must be at least 1 byte"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply the transform to a trace.
+    pub fn apply(&self, mut trace: Trace) -> Result<Trace, TransformError> {
+        self.check()?;
+        let operations = std::mem::take(&mut trace.operations);
+        trace.operations = match self {
+            Self::ForceReads => Self::map_types(operations, |_| AccessType::Read),
+            Self::ForceWrites => Self::map_types(operations, |_| AccessType::Write),
+            Self::SwapReadsAndWrites => Self::map_types(operations, |access_type| match access_type {
+                AccessType::Read => AccessType::Write,
+                _ => AccessType::Read,
+            }),
+            Self::Offset(offset) => Self::map_addresses(operations, |address| {
+                address.checked_add_signed(*offset)
+            })?,
+            Self::Mask(mask) => Self::map_addresses(operations, |address| Some(address & mask))?,
+            Self::ShiftLeft(bits) => Self::map_addresses(operations, |address| {
+                address.checked_mul(1 << bits)
+            })?,
+            Self::ShiftRight(bits) => {
+                Self::map_addresses(operations, |address| Some(address >> bits))?
+            }
+            Self::Window(range) => {
+                let end = range.end.min(operations.len());
+                let start = range.start.min(end);
+                operations[start..end].to_vec()
+            }
+            Self::Sample(n) => operations.into_iter().step_by(*n).collect(),
+            Self::DropAddresses(range) => operations
+                .into_iter()
+                .filter(|operation| !range.contains(&operation.address))
+                .collect(),
+            Self::Interleave(other) => {
+                let mut interleaved = Vec::with_capacity(operations.len() + other.len());
+                let (mut first, mut second) = (operations.into_iter(), other.iter().copied());
+                loop {
+                    match (first.next(), second.next()) {
+                        (None, None) => break,
+                        (a, b) => interleaved.extend(a.into_iter().chain(b)),
+                    }
+                }
+                trace.skipped_lines += other.skipped_lines;
+                trace.skipped.extend(other.skipped.iter().cloned());
+                interleaved
+            }
+            Self::Dedupe(line_size) => {
+                let lines = |operation: &Operation| {
+                    (
+                        operation.access_type,
+                        operation.asid,
+                        operation.address / line_size,
+                        operation.last_address() / line_size,
+                    )
+                };
+                let mut previous = None;
+                operations
+                    .into_iter()
+                    .filter(|operation| previous.replace(lines(operation)) != Some(lines(operation)))
+                    .collect()
+            }
+            Self::Asid(asid) => operations
+                .into_iter()
+                .map(|operation| operation.with_asid(*asid))
+                .collect(),
+        };
+        Ok(trace)
+    }
+
+    /// Change the type of every read and write, leaving fetches alone.
+    fn map_types(
+        mut operations: Vec<Operation>,
+        map: impl Fn(AccessType) -> AccessType,
+    ) -> Vec<Operation> {
+        for operation in operations.iter_mut().filter(|operation| !operation.is_fetch()) {
+            operation.access_type = map(operation.access_type);
+        }
+        operations
+    }
+
+    /// Change the address of every access. The map returns None if the address would be out of range.
+    fn map_addresses(
+        mut operations: Vec<Operation>,
+        map: impl Fn(u64) -> Option<u64>,
+    ) -> Result<Vec<Operation>, TransformError> {
+        for (index, operation) in operations.iter_mut().enumerate() {
+            match map(operation.address) {
+                Some(address) if address.checked_add(operation.size - 1).is_some() => {
+                    operation.address = address;
+                }
+                _ => {
+                    return Err(TransformError::OutOfRange {
+                        index,
+                        operation: *operation,
+                    })
+                }
+            }
+        }
+        Ok(operations)
+    }
+}
+
+impl Trace {
+    /// Apply a chain of transforms to the trace, in order.
+    pub fn transform(self, transforms: &[Transform]) -> Result<Self, TransformError> {
+        transforms
+            .iter()
+            .try_fold(self, |trace, transform| transform.apply(trace))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(operations: &[Operation]) -> Trace {
+        Trace {
+            operations: operations.to_vec(),
+            ..Trace::new()
+        }
+    }
+
+    /// Parse a transform and apply it to a trace of the operations.
+    fn apply(transform: &str, operations: &[Operation]) -> Result<Vec<Operation>, TransformError> {
+        Ok(Transform::parse(transform)?.apply(trace(operations))?.operations)
+    }
+
+    /// A read, a write and a fetch.
+    fn mixed() -> [Operation; 3] {
+        [Operation::read(0x10), Operation::write(0x20), Operation::fetch(0x30)]
+    }
+
+    #[test]
+    fn access_types_are_changed_except_for_fetches() {
+        let types = |transform: &str| -> Vec<_> {
+            apply(transform, &mixed()).unwrap().iter().map(|operation| operation.access_type).collect()
+        };
+        use AccessType::{Fetch, Read, Write};
+        assert_eq!(types("reads"), [Read, Read, Fetch]);
+        assert_eq!(types("writes"), [Write, Write, Fetch]);
+        assert_eq!(types("swap"), [Write, Read, Fetch]);
+    }
+
+    #[test]
+    fn addresses_are_moved_masked_and_shifted() {
+        let addresses = |transform: &str| -> Vec<_> {
+            apply(transform, &mixed()).unwrap().iter().map(|operation| operation.address).collect()
+        };
+        assert_eq!(addresses("add=1000"), [0x1010, 0x1020, 0x1030]);
+        assert_eq!(addresses("add=-10"), [0x0, 0x10, 0x20]);
+        assert_eq!(addresses("mask=0x30"), [0x10, 0x20, 0x30]);
+        assert_eq!(addresses("mask=f"), [0x0, 0x0, 0x0]);
+        assert_eq!(addresses("shl=4"), [0x100, 0x200, 0x300]);
+        assert_eq!(addresses("shr=4"), [0x1, 0x2, 0x3]);
+    }
+
+    #[test]
+    fn the_largest_offsets_are_accepted() {
+        assert!(matches!(Transform::parse("add=-8000000000000000"), Ok(Transform::Offset(i64::MIN))));
+        assert!(matches!(Transform::parse("add=7fffffffffffffff"), Ok(Transform::Offset(i64::MAX))));
+        let too_large = |text: &str| matches!(Transform::parse(text), Err(TransformError::BadTransform { .. }));
+        assert!(too_large("add=8000000000000000"));
+        assert!(too_large("add=-8000000000000001"));
+
+        let top = [Operation::read(u64::MAX)];
+        assert_eq!(apply("add=-8000000000000000", &top).unwrap(), [Operation::read(i64::MAX as u64)]);
+    }
+
+    #[test]
+    fn moving_an_access_out_of_the_address_space_fails() {
+        let out_of_range = |transform: &str, operations: &[Operation]| match apply(transform, operations) {
+            Err(TransformError::OutOfRange { index, .. }) => index,
+            result => panic!("{transform}: {result:?}"),
+        };
+        assert_eq!(out_of_range("add=-11", &mixed()), 0);
+        let high = [Operation::read(0x10), Operation::read(0x8000_0000_0000_0001)];
+        assert_eq!(out_of_range("add=7fffffffffffffff", &high), 1);
+        assert_eq!(out_of_range("shl=60", &mixed()), 0);
+        // The last byte of an access must stay in range too.
+        assert_eq!(out_of_range("add=1", &[Operation::read(u64::MAX - 7).with_size(8)]), 0);
+    }
+
+    #[test]
+    fn windows_and_samples_keep_some_accesses() {
+        let operations: Vec<_> = (0..10).map(|i| Operation::read(i * 0x10)).collect();
+        let addresses = |transform: &str| -> Vec<_> {
+            apply(transform, &operations).unwrap().iter().map(|operation| operation.address).collect()
+        };
+        assert_eq!(addresses("window=2..5"), [0x20, 0x30, 0x40]);
+        assert_eq!(addresses("window=8..100"), [0x80, 0x90]);
+        assert!(addresses("window=20..30").is_empty());
+        assert_eq!(addresses("sample=4"), [0x0, 0x40, 0x80]);
+        assert_eq!(addresses("drop=20..80"), [0x0, 0x10, 0x80, 0x90]);
+    }
+
+    #[test]
+    fn interleaving_alternates_until_one_trace_runs_out() {
+        let other = trace(&[Operation::write(0x1), Operation::write(0x2), Operation::write(0x3), Operation::write(0x4)]);
+        let interleaved = Transform::Interleave(other.clone()).apply(trace(&mixed())).unwrap();
+        let addresses: Vec<_> = interleaved.iter().map(|operation| operation.address).collect();
+        assert_eq!(addresses, [0x10, 0x1, 0x20, 0x2, 0x30, 0x3, 0x4]);
+
+        let interleaved = Transform::Interleave(trace(&mixed()[..1])).apply(other).unwrap();
+        let addresses: Vec<_> = interleaved.iter().map(|operation| operation.address).collect();
+        assert_eq!(addresses, [0x1, 0x10, 0x2, 0x3, 0x4]);
+    }
+
+    #[test]
+    fn dedupe_drops_repeats_of_the_same_lines() {
+        let operations = [
+            Operation::read(0x10).with_size(4),
+            Operation::read(0x1c).with_size(4),
+            // Straddles lines 0x10 and 0x20, so it differs from the access before it.
+            Operation::read(0x1e).with_size(4),
+            Operation::read(0x10),
+            Operation::write(0x10),
+            Operation::write(0x10).with_asid(1),
+            Operation::write(0x14).with_asid(1),
+        ];
+        let deduped = apply("dedupe=16", &operations).unwrap();
+        assert_eq!(deduped, [operations[0], operations[2], operations[3], operations[4], operations[5]]);
+    }
+
+    #[test]
+    fn every_access_is_moved_to_the_address_space() {
+        let moved = apply("asid=7", &mixed()).unwrap();
+        assert!(moved.iter().all(|operation| operation.asid == 7));
+    }
+
+    #[test]
+    fn transforms_are_applied_in_order() {
+        let transforms = [Transform::parse("shl=4").unwrap(), Transform::parse("window=1..3").unwrap()];
+        let transformed = trace(&mixed()).transform(&transforms).unwrap();
+        let addresses: Vec<_> = transformed.iter().map(|operation| operation.address).collect();
+        assert_eq!(addresses, [0x200, 0x300]);
+    }
+
+    #[test]
+    fn bad_transforms_are_rejected() {
+        let bad = |text: &str| matches!(Transform::parse(text), Err(TransformError::BadTransform { .. }));
+        let invalid = |text: &str| matches!(Transform::parse(text), Err(TransformError::Invalid(_)));
+        assert!(bad("rotate=4"));
+        assert!(bad("reads=y"));
+        assert!(bad("add"));
+        assert!(bad("add=xyz"));
+        assert!(bad("shl=64"));
+        assert!(bad("window=5"));
+        assert!(bad("window=a..b"));
+        assert!(bad("asid=65536"));
+        assert!(bad("interleave=/nonexistent/trace.dat"));
+        assert!(invalid("window=5..2"));
+        assert!(invalid("drop=20..10"));
+        assert!(invalid("sample=0"));
+        assert!(invalid("dedupe=0"));
+    }
+}